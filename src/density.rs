@@ -2,37 +2,30 @@
 //! Provides functionality for processing surfel data into substance density textures.
 //!
 
-use self::SubstanceFilter::*;
 use geom::Vertex;
-use image::{ImageBuffer, Pixel, Rgba};
+use image::{ImageBuffer, Luma, Rgba};
+use lookup_table::{SurfelLookupTable, TexelSurfels};
+use ramp::ColorRamp;
+use reconstruction::Reconstruction;
+use reverse_index::ReverseIndex;
 use scene::Entity;
 use sim::SurfelData;
 use surf;
-use surfel_table::build_surfel_lookup_table;
-use std::f32::NEG_INFINITY;
+use table_options::TableOptions;
 
-type Surface = surf::Surface<surf::Surfel<Vertex, SurfelData>>;
-
-pub enum SubstanceFilter {
-    /// When combining n surfels into a texel, take the unweighted average of substance.
-    Flat,
-    /// When combining n surfels into a texel do a weighted average, give the nearest
-    /// texel the highest influence, gradually decreasing until the last surfel with influence 0
-    Smooth,
-}
+pub type Surface = surf::Surface<surf::Surfel<Vertex, SurfelData>>;
 
 pub struct Density {
     substance_idx: usize,
-    tex_width: usize,
-    tex_height: usize,
-    island_bleed: usize,
     min_density: f32,
     max_density: f32,
     /// Color to use for locations in the texture unused by the mesh
     undefined_color: Rgba<u8>,
-    min_color: Rgba<u8>,
-    max_color: Rgba<u8>,
-    filtering: SubstanceFilter,
+    /// Colors for densities, with 0 for the minimum and 1 for the maximum density
+    ramp: ColorRamp,
+    filtering: Box<Reconstruction>,
+    /// Texture dimensions and settings for building surfel lookup tables
+    table_options: TableOptions,
 }
 
 impl Density {
@@ -46,9 +39,9 @@ impl Density {
         undefined_color: Rgba<u8>,
         min_color: Rgba<u8>,
         max_color: Rgba<u8>,
-        filtering: SubstanceFilter,
+        filtering: impl Reconstruction + 'static,
     ) -> Self {
-        Density {
+        Self::with_ramp(
             substance_idx,
             tex_width,
             tex_height,
@@ -56,108 +49,337 @@ impl Density {
             min_density,
             max_density,
             undefined_color,
-            min_color,
-            max_color,
+            ColorRamp::two_color(min_color, max_color),
             filtering,
+        )
+    }
+
+    /// Creates a density collector that colors densities with the given color ramp
+    /// instead of blending between two colors.
+    ///
+    /// The minimum density maps to 0 on the ramp, the maximum density to 1.
+    pub fn with_ramp(
+        substance_idx: usize,
+        tex_width: usize,
+        tex_height: usize,
+        island_bleed: usize,
+        min_density: f32,
+        max_density: f32,
+        undefined_color: Rgba<u8>,
+        ramp: ColorRamp,
+        filtering: impl Reconstruction + 'static,
+    ) -> Self {
+        Density {
+            substance_idx,
+            min_density,
+            max_density,
+            undefined_color,
+            ramp,
+            filtering: Box::new(filtering),
+            table_options: TableOptions::new(tex_width, tex_height, island_bleed),
         }
     }
 
-    pub fn build_table(&self, entity: &Entity, surf: &Surface) -> Vec<Vec<(f32, usize)>> {
-        build_surfel_lookup_table(
-            entity,
-            surf,
-            4,
-            self.tex_width,
-            self.tex_height,
-            self.island_bleed,
-        )
+    /// Uses the given options for building surfel lookup tables, e.g. to gather more
+    /// surfels or to use supersampling.
+    ///
+    /// This replaces the texture dimensions and island bleed passed to the constructor.
+    pub fn with_table_options(self, table_options: TableOptions) -> Self {
+        Self {
+            table_options,
+            ..self
+        }
+    }
+
+    /// Texture dimensions and settings for building surfel lookup tables, which can also
+    /// be used to build or load a table for `collect_with_table`.
+    pub fn table_options(&self) -> &TableOptions {
+        &self.table_options
     }
 
     pub fn collect(&self, entity: &Entity, surf: &Surface) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
         //let position_tex = position_tex(entity, self.tex_width, self.tex_height, self.island_bleed);
 
-        self.collect_with_table(
-            surf,
-            &build_surfel_lookup_table(
-                entity,
-                surf,
-                4,
-                self.tex_width,
-                self.tex_height,
-                self.island_bleed,
-            ),
-        )
+        self.collect_with_table(surf, &self.table_options.build_table(entity, surf))
     }
 
     pub fn collect_with_table(
         &self,
         surf: &Surface,
-        table: &Vec<Vec<(f32, usize)>>,
+        table: &SurfelLookupTable,
     ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-        ImageBuffer::from_fn(self.tex_width as u32, self.tex_height as u32, |x, y| {
+        self.collect_from(table, &self.substance_of(surf))
+    }
+
+    /// Like `collect_with_table`, but obtains the substance of each surfel from `value_of`
+    /// instead of a surface, e.g. from substances saved in an earlier iteration.
+    pub fn collect_from(
+        &self,
+        table: &SurfelLookupTable,
+        value_of: &(Fn(usize) -> f32 + Sync),
+    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        self.table_options.image_from_fn(|x, y| {
+            self.color_from(table.texel_at(x as usize, y as usize), value_of)
+        })
+    }
+
+    /// Gets the color of a single texel with the given close surfels, as used
+    /// by `collect_with_table`.
+    pub fn color_at(&self, surf: &Surface, close_surfels: TexelSurfels) -> Rgba<u8> {
+        self.color_from(close_surfels, &self.substance_of(surf))
+    }
+
+    /// Like `color_at`, but obtains the substance of each surfel from `value_of`.
+    pub fn color_from(
+        &self,
+        close_surfels: TexelSurfels,
+        value_of: &Fn(usize) -> f32,
+    ) -> Rgba<u8> {
+        match self.density_from(close_surfels, value_of) {
+            None => self.undefined_color,
+            Some(density) => self.ramp.color_at(self.alpha_of(density)),
+        }
+    }
+
+    /// Builds the mapping from surfels to the texels of the given table they influence,
+    /// weighted with the reconstruction used by this density.
+    pub fn reverse_index(&self, surf: &Surface, table: &SurfelLookupTable) -> ReverseIndex {
+        ReverseIndex::with_reconstruction(table, surf.samples.len(), &*self.filtering)
+    }
+
+    /// Index of the substance visualized by this density.
+    pub fn substance_idx(&self) -> usize {
+        self.substance_idx
+    }
+
+    /// Renders a horizontal strip of the colors used for densities, with the minimum
+    /// density at the left and the maximum density at the right.
+    pub fn legend(&self, width: u32, height: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        self.ramp.legend(width, height)
+    }
+
+    /// Collects the unclamped substance density of each texel into a floating point buffer.
+    ///
+    /// Texels that are not covered by the mesh are set to the given `undefined` value,
+    /// e.g. `NAN` or a negative sentinel. Use `coverage_with_table` to obtain a separate
+    /// mask of covered texels instead.
+    pub fn collect_f32(
+        &self,
+        entity: &Entity,
+        surf: &Surface,
+        undefined: f32,
+    ) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+        self.collect_f32_with_table(surf, &self.table_options.build_table(entity, surf), undefined)
+    }
+
+    pub fn collect_f32_with_table(
+        &self,
+        surf: &Surface,
+        table: &SurfelLookupTable,
+        undefined: f32,
+    ) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+        self.collect_f32_from(table, &self.substance_of(surf), undefined)
+    }
+
+    /// Like `collect_f32_with_table`, but obtains the substance of each surfel from `value_of`.
+    pub fn collect_f32_from(
+        &self,
+        table: &SurfelLookupTable,
+        value_of: &(Fn(usize) -> f32 + Sync),
+        undefined: f32,
+    ) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+        self.table_options.image_from_fn(|x, y| {
             let x = x as usize;
             let y = y as usize;
-            let surfels = &table[y * self.tex_width + x];
-            let density = match surfels.len() {
-                0 => None,
-                // Single surfel, no filtering
-                1 => Some(surf.samples[surfels[0].1].data().substances[self.substance_idx]),
-                _ => Some(match self.filtering {
-                    Flat => self.density_at_idxs(surf, surfels),
-                    Smooth => self.dist_sqr_ratio_weighted(surf, surfels),
-                })
-            };
+            let density = self.density_from(table.texel_at(x, y), value_of);
 
-            match density {
-                None => self.undefined_color,
-                Some(density) => {
-                    let alpha = density.max(self.min_density).min(self.max_density)
-                        / (self.max_density - self.min_density);
+            Luma {
+                data: [density.unwrap_or(undefined)],
+            }
+        })
+    }
+
+    /// Collects substance densities into a 16 bit buffer, where the minimum density
+    /// maps to zero and the maximum density maps to `u16::max_value()`.
+    ///
+    /// Texels that are not covered by the mesh are set to the given `undefined` value.
+    pub fn collect_u16(
+        &self,
+        entity: &Entity,
+        surf: &Surface,
+        undefined: u16,
+    ) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        self.collect_u16_with_table(surf, &self.table_options.build_table(entity, surf), undefined)
+    }
+
+    pub fn collect_u16_with_table(
+        &self,
+        surf: &Surface,
+        table: &SurfelLookupTable,
+        undefined: u16,
+    ) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        self.collect_u16_from(table, &self.substance_of(surf), undefined)
+    }
 
-                    self.min_color.map2(&self.max_color, |c0, c1| {
-                        ((1.0 - alpha) * (c0 as f32) + alpha * (c1 as f32)) as u8
-                    })
+    /// Like `collect_u16_with_table`, but obtains the substance of each surfel from `value_of`.
+    pub fn collect_u16_from(
+        &self,
+        table: &SurfelLookupTable,
+        value_of: &(Fn(usize) -> f32 + Sync),
+        undefined: u16,
+    ) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        self.table_options.image_from_fn(|x, y| {
+            let x = x as usize;
+            let y = y as usize;
+            let density = self.density_from(table.texel_at(x, y), value_of);
+
+            let value = match density {
+                None => undefined,
+                Some(density) => {
+                    (self.alpha_of(density) * (u16::max_value() as f32)).round() as u16
                 }
+            };
+
+            Luma { data: [value] }
+        })
+    }
+
+    /// Creates a mask that is 255 for texels covered by the mesh and 0 elsewhere.
+    pub fn coverage_with_table(
+        &self,
+        table: &SurfelLookupTable,
+    ) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        self.table_options.image_from_fn(|x, y| {
+            let covered = !table.texel_at(x as usize, y as usize).is_empty();
+            Luma {
+                data: [if covered { 255 } else { 0 }],
             }
         })
     }
 
-    fn weighted_avg(&self, surf: &Surface, close_surfels: &Vec<(f32, usize)>, weights: impl Clone + Iterator<Item = f32>) -> f32 {
-        let one_over_weights_sum = weights.clone().sum::<f32>().recip();
-        let scaled_weights = weights.map(|w| one_over_weights_sum * w);
-        close_surfels.iter()
-            .map(|&(_, surfel_idx)| surf.samples[surfel_idx].data().substances[self.substance_idx])
-            .zip(scaled_weights)
-            .map(|(substance, weight)| substance * weight)
-            .sum::<f32>()
-    }
-    
-    
-    fn dist_sqr_ratio_weighted(&self, surf: &Surface, close_surfels: &Vec<(f32, usize)>) -> f32 {
-        // The maximally distant surfel still has some influence, 
-        const MIN_WEIGHT : f32 = 1.0;
-        // If a surfel completely coincides with the texel position, it has MIN_WEIGHT+RANGE influence
-        const RANGE : f32 = 5.0;
-        let dists = close_surfels.iter().map(|&(dist, _)| dist);
-        let max_dist = dists.clone().fold(NEG_INFINITY, f32::max);
-        let max_dist_inv = max_dist.recip();
-        let weights = dists.map(|d| (max_dist - d) * max_dist_inv * RANGE + MIN_WEIGHT);
-        self.weighted_avg(surf, close_surfels, weights)
-    }
-
-    /*fn inv_distance_weighted(&self, surf: &Surface, close_surfels: &Vec<(f32, usize)>) -> f32 {
-        // Super expensive, running sqrt twice
-        let weights = close_surfels.iter().map(|&(dist, _)| dist.sqrt().recip());
-        self.weighted_avg(surf, close_surfels, weights)
-    }*/
-
-    fn density_at_idxs(&self, surf: &Surface, close_surfels: &Vec<(f32, usize)>) -> f32 {
-        let one_over_n = (close_surfels.len() as f32).recip();
-
-        one_over_n
-            * close_surfels
-                .iter()
-                .map(|&(_dist, idx)| surf.samples[idx].data().substances[self.substance_idx])
-                .sum::<f32>()
+    /// Filters the substance of this density from the given close surfels, or `None`
+    /// if the texel is not covered.
+    fn density_from(
+        &self,
+        close_surfels: TexelSurfels,
+        value_of: &Fn(usize) -> f32,
+    ) -> Option<f32> {
+        self.filtering.reconstruct(close_surfels, value_of)
+    }
+
+    /// Maps the given density to the range from 0 for the minimum density to 1 for the
+    /// maximum density, clamping densities outside of the range.
+    fn alpha_of(&self, density: f32) -> f32 {
+        (density.max(self.min_density).min(self.max_density) - self.min_density)
+            / (self.max_density - self.min_density)
+    }
+
+    /// Obtains the substance of this density from a surfel index.
+    fn substance_of<'a>(&self, surf: &'a Surface) -> impl Fn(usize) -> f32 + Sync + 'a {
+        let substance_idx = self.substance_idx;
+        move |surfel_idx| surf.samples[surfel_idx].data().substances[substance_idx]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lookup_table::TableSettings;
+    use reconstruction::SubstanceFilter;
+    use std::f32::NAN;
+    use surfel_table::Gather;
+
+    /// Substance of three surfels, one above the maximum density.
+    const SUBSTANCES: [f32; 3] = [0.5, 2.0, 5.0];
+
+    fn table() -> SurfelLookupTable {
+        let texels = vec![
+            vec![(1.0, 0)],
+            vec![(1.0, 1), (1.0, 0)],
+            vec![],
+            vec![(1.0, 2)],
+        ];
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        SurfelLookupTable::from_texels(settings, 0, texels)
+    }
+
+    fn density() -> Density {
+        let black = Rgba { data: [0, 0, 0, 255] };
+        let white = Rgba {
+            data: [255, 255, 255, 255],
+        };
+        Density::new(
+            0,
+            2,
+            2,
+            0,
+            0.0,
+            2.0,
+            Rgba { data: [0, 0, 0, 0] },
+            black,
+            white,
+            SubstanceFilter::Flat,
+        )
+    }
+
+    #[test]
+    fn f32_values_are_unclamped() {
+        let image = density().collect_f32_from(&table(), &|idx| SUBSTANCES[idx], NAN);
+
+        assert_ulps_eq!(image.get_pixel(0, 0).data[0], 0.5);
+        assert_ulps_eq!(image.get_pixel(1, 0).data[0], 1.25);
+        assert!(image.get_pixel(0, 1).data[0].is_nan());
+        assert_ulps_eq!(image.get_pixel(1, 1).data[0], 5.0);
+    }
+
+    #[test]
+    fn u16_values_span_density_range() {
+        let image = density().collect_u16_from(&table(), &|idx| SUBSTANCES[idx], 7);
+
+        assert_eq!(image.get_pixel(0, 0).data[0], (0.25 * 65535.0_f32).round() as u16);
+        assert_eq!(image.get_pixel(1, 0).data[0], (0.625 * 65535.0_f32).round() as u16);
+        assert_eq!(image.get_pixel(0, 1).data[0], 7);
+        assert_eq!(image.get_pixel(1, 1).data[0], u16::max_value());
+    }
+
+    #[test]
+    fn min_density_maps_to_min_color() {
+        let black = Rgba { data: [0, 0, 0, 255] };
+        let white = Rgba {
+            data: [255, 255, 255, 255],
+        };
+        let density = Density::new(
+            0,
+            3,
+            1,
+            0,
+            1.0,
+            3.0,
+            Rgba { data: [0, 0, 0, 0] },
+            black,
+            white,
+            SubstanceFilter::Flat,
+        );
+        let texels = vec![vec![(1.0, 0)], vec![(1.0, 1)], vec![(1.0, 2)]];
+        let settings = TableSettings::new(3, 1, 0, Gather::Nearest(1));
+        let table = SurfelLookupTable::from_texels(settings, 0, texels);
+        let substances = [1.0, 2.0, 3.0];
+
+        let image = density.collect_from(&table, &|idx| substances[idx]);
+
+        // Previously, the minimum density mapped to the middle of the range
+        assert_eq!(*image.get_pixel(0, 0), black);
+        assert_eq!(
+            *image.get_pixel(1, 0),
+            ColorRamp::two_color(black, white).color_at(0.5)
+        );
+        assert_eq!(*image.get_pixel(2, 0), white);
+    }
+
+    #[test]
+    fn coverage_mask_of_table() {
+        let mask = density().coverage_with_table(&table());
+
+        assert_eq!(mask.into_raw(), vec![255, 255, 0, 255]);
     }
 }
@@ -0,0 +1,310 @@
+//!
+//! Provides diagnostics for UV distortion and texel density of entities.
+//!
+
+use geom::Triangle;
+use geom_tex::{jacobian, GBuffer};
+use image::{ImageBuffer, Luma};
+use scene::{Entity, Mesh};
+use std::f32::{INFINITY, NEG_INFINITY};
+use std::fmt;
+use uv_triangle::triangle_into_uv_image_space;
+
+/// Calculates the singular values of a jacobian as found in `GeomTexel::jacobian`,
+/// that is, the world-space lengths of the axes of the texel after mapping it
+/// onto the surface, larger value first.
+pub fn singular_values(jacobian: [[f32; 2]; 2]) -> (f32, f32) {
+    let [[a, b], [c, d]] = jacobian;
+    let sum_sqr = a * a + b * b + c * c + d * d;
+    let det = a * d - b * c;
+    let discriminant = (sum_sqr * sum_sqr - 4.0 * det * det).max(0.0).sqrt();
+
+    (
+        (0.5 * (sum_sqr + discriminant)).sqrt(),
+        (0.5 * (sum_sqr - discriminant)).max(0.0).sqrt(),
+    )
+}
+
+/// Ratio of larger to smaller singular value, 1 for texels mapped to squares on the surface.
+/// Infinite for degenerate mappings.
+pub fn stretch(jacobian: [[f32; 2]; 2]) -> f32 {
+    let (max, min) = singular_values(jacobian);
+    if min > 0.0 {
+        max / min
+    } else {
+        INFINITY
+    }
+}
+
+/// Number of texels per world unit, averaged over both texel axes.
+/// Zero for degenerate mappings.
+pub fn texel_density(jacobian: [[f32; 2]; 2]) -> f32 {
+    let (max, min) = singular_values(jacobian);
+    if min > 0.0 {
+        (max * min).sqrt().recip()
+    } else {
+        0.0
+    }
+}
+
+/// Creates a map of UV stretch for each texel inside a triangle, see `stretch`.
+/// Texels outside of triangles are zero.
+pub fn stretch_map(gbuffer: &GBuffer) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+    diagnostic_map(gbuffer, stretch)
+}
+
+/// Creates a map of texels per world unit for each texel inside a triangle,
+/// see `texel_density`. Texels outside of triangles are zero.
+pub fn texel_density_map(gbuffer: &GBuffer) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+    diagnostic_map(gbuffer, texel_density)
+}
+
+fn diagnostic_map<F>(gbuffer: &GBuffer, diagnostic: F) -> ImageBuffer<Luma<f32>, Vec<f32>>
+where
+    F: Fn([[f32; 2]; 2]) -> f32,
+{
+    ImageBuffer::from_fn(gbuffer.width() as u32, gbuffer.height() as u32, |x, y| {
+        let value = match gbuffer.texel_at(x as usize, y as usize) {
+            Some(g) if g.covered => diagnostic(g.jacobian),
+            _ => 0.0,
+        };
+        Luma { data: [value] }
+    })
+}
+
+/// Summary of UV distortion and texel density of the triangles of an entity at a
+/// specific texture resolution.
+///
+/// Averages are weighted by the world-space area of the triangles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UvReport {
+    pub entity_name: String,
+    pub triangle_count: usize,
+    /// Triangles with zero area in texture space but not in world space.
+    pub degenerate_uv_triangle_count: usize,
+    pub world_area: f32,
+    /// Lowest texels per world unit, ignoring degenerate triangles.
+    pub min_texel_density: f32,
+    pub max_texel_density: f32,
+    pub mean_texel_density: f32,
+    /// Ratio of the highest to the lowest texel density, 1 for perfectly consistent UVs.
+    pub texel_density_ratio: f32,
+    /// Highest ratio of longer to shorter texel axis on the surface, see `stretch`.
+    pub max_stretch: f32,
+    pub mean_stretch: f32,
+}
+
+impl UvReport {
+    /// Analyzes the UV mapping of the given entity for a texture with the given dimensions.
+    pub fn new(entity: &Entity, width: usize, height: usize) -> Self {
+        let mut report = UvReport {
+            entity_name: entity.name.clone(),
+            triangle_count: 0,
+            degenerate_uv_triangle_count: 0,
+            world_area: 0.0,
+            min_texel_density: INFINITY,
+            max_texel_density: NEG_INFINITY,
+            mean_texel_density: 0.0,
+            texel_density_ratio: 0.0,
+            max_stretch: NEG_INFINITY,
+            mean_stretch: 0.0,
+        };
+
+        for triangle in entity.mesh.triangles() {
+            report.triangle_count += 1;
+
+            let area = triangle.area();
+            if area == 0.0 {
+                continue;
+            }
+
+            let jacobian = jacobian(&triangle_into_uv_image_space(triangle, width, height));
+            let density = texel_density(jacobian);
+            if density == 0.0 {
+                report.degenerate_uv_triangle_count += 1;
+                continue;
+            }
+
+            let stretch = stretch(jacobian);
+            report.world_area += area;
+            report.min_texel_density = report.min_texel_density.min(density);
+            report.max_texel_density = report.max_texel_density.max(density);
+            report.mean_texel_density += area * density;
+            report.max_stretch = report.max_stretch.max(stretch);
+            report.mean_stretch += area * stretch;
+        }
+
+        if report.world_area > 0.0 {
+            report.mean_texel_density /= report.world_area;
+            report.mean_stretch /= report.world_area;
+            report.texel_density_ratio = report.max_texel_density / report.min_texel_density;
+        } else {
+            report.min_texel_density = 0.0;
+            report.max_texel_density = 0.0;
+            report.max_stretch = 0.0;
+        }
+
+        report
+    }
+}
+
+impl fmt::Display for UvReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "UV report for {}", self.entity_name)?;
+        writeln!(
+            f,
+            "  triangles: {} ({} degenerate in UV space)",
+            self.triangle_count, self.degenerate_uv_triangle_count
+        )?;
+        writeln!(f, "  world area: {}", self.world_area)?;
+        writeln!(
+            f,
+            "  texels per unit: min {}, mean {}, max {} (ratio {})",
+            self.min_texel_density,
+            self.mean_texel_density,
+            self.max_texel_density,
+            self.texel_density_ratio
+        )?;
+        write!(
+            f,
+            "  stretch: mean {}, max {}",
+            self.mean_stretch, self.max_stretch
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures::{self, FixtureVertex};
+    use std::f32::consts::SQRT_2;
+
+    /// Four texels per world unit along both legs at 8x8 texels, without stretch.
+    fn even_triangle() -> [FixtureVertex; 3] {
+        [
+            ([0.0, 0.0, 0.0], [0.0, 0.0]),
+            ([1.0, 0.0, 0.0], [0.5, 0.0]),
+            ([0.0, 1.0, 0.0], [0.0, 0.5]),
+        ]
+    }
+
+    /// Like `even_triangle`, but twice as wide in world space, stretching texels to 2:1.
+    fn stretched_triangle() -> [FixtureVertex; 3] {
+        [
+            ([2.0, 0.0, 0.0], [0.5, 0.5]),
+            ([4.0, 0.0, 0.0], [1.0, 0.5]),
+            ([2.0, 1.0, 0.0], [0.5, 1.0]),
+        ]
+    }
+
+    /// Texture coordinates on a line.
+    fn degenerate_uv_triangle() -> [FixtureVertex; 3] {
+        [
+            ([0.0, 0.0, 1.0], [0.1, 0.1]),
+            ([1.0, 0.0, 1.0], [0.2, 0.2]),
+            ([0.0, 1.0, 1.0], [0.3, 0.3]),
+        ]
+    }
+
+    /// Positions on a line.
+    fn degenerate_world_triangle() -> [FixtureVertex; 3] {
+        [
+            ([0.0, 0.0, 2.0], [0.0, 0.0]),
+            ([1.0, 1.0, 2.0], [0.5, 0.0]),
+            ([2.0, 2.0, 2.0], [0.0, 0.5]),
+        ]
+    }
+
+    #[test]
+    fn singular_values_of_diagonal() {
+        let (max, min) = singular_values([[0.5, 0.0], [0.0, 2.0]]);
+        assert_ulps_eq!(max, 2.0);
+        assert_ulps_eq!(min, 0.5);
+
+        assert_ulps_eq!(stretch([[0.5, 0.0], [0.0, 2.0]]), 4.0);
+        assert_ulps_eq!(texel_density([[0.5, 0.0], [0.0, 2.0]]), 1.0);
+    }
+
+    #[test]
+    fn sheared_jacobian() {
+        // Singular values of a shear are not the diagonal entries
+        let (max, min) = singular_values([[1.0, 1.0], [0.0, 1.0]]);
+        let golden_ratio = (1.0 + 5.0_f32.sqrt()) / 2.0;
+        assert_ulps_eq!(max, golden_ratio);
+        assert_ulps_eq!(min, golden_ratio.recip(), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn degenerate_jacobian() {
+        assert_eq!(stretch([[0.0; 2]; 2]), INFINITY);
+        assert_eq!(texel_density([[0.0; 2]; 2]), 0.0);
+    }
+
+    #[test]
+    fn report_of_mixed_triangles() {
+        let entity = fixtures::entity(&[
+            even_triangle(),
+            stretched_triangle(),
+            degenerate_uv_triangle(),
+            degenerate_world_triangle(),
+        ]);
+
+        let report = UvReport::new(&entity, 8, 8);
+
+        // Texels of the stretched triangle are 0.5 by 0.25 units on the surface
+        let stretched_density = 2.0 * SQRT_2;
+        assert_eq!(report.entity_name, "Fixture");
+        assert_eq!(report.triangle_count, 4);
+        assert_eq!(report.degenerate_uv_triangle_count, 1);
+        assert_ulps_eq!(report.world_area, 1.5);
+        assert_abs_diff_eq!(report.min_texel_density, stretched_density, epsilon = 1e-5);
+        assert_abs_diff_eq!(report.max_texel_density, 4.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(
+            report.mean_texel_density,
+            (0.5 * 4.0 + 1.0 * stretched_density) / 1.5,
+            epsilon = 1e-5
+        );
+        assert_abs_diff_eq!(report.texel_density_ratio, SQRT_2, epsilon = 1e-5);
+        assert_abs_diff_eq!(report.max_stretch, 2.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(
+            report.mean_stretch,
+            (0.5 * 1.0 + 1.0 * 2.0) / 1.5,
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn report_of_only_degenerate_triangles() {
+        let entity = fixtures::entity(&[degenerate_uv_triangle(), degenerate_world_triangle()]);
+
+        let report = UvReport::new(&entity, 8, 8);
+
+        assert_eq!(report.triangle_count, 2);
+        assert_eq!(report.degenerate_uv_triangle_count, 1);
+        assert_eq!(report.world_area, 0.0);
+        assert_eq!(report.min_texel_density, 0.0);
+        assert_eq!(report.max_texel_density, 0.0);
+        assert_eq!(report.mean_texel_density, 0.0);
+        assert_eq!(report.texel_density_ratio, 0.0);
+        assert_eq!(report.max_stretch, 0.0);
+        assert_eq!(report.mean_stretch, 0.0);
+    }
+
+    #[test]
+    fn maps_inside_triangles() {
+        let entity = fixtures::entity(&[even_triangle(), stretched_triangle()]);
+        let gbuffer = GBuffer::bake(&entity, 8, 8, 0);
+
+        let density = texel_density_map(&gbuffer);
+        let stretch = stretch_map(&gbuffer);
+
+        // Bottom left texel is in the even triangle, the one at (4, 3) in the stretched
+        // triangle and the top right one in neither
+        assert_abs_diff_eq!(density.get_pixel(0, 7).data[0], 4.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(stretch.get_pixel(0, 7).data[0], 1.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(density.get_pixel(4, 3).data[0], 2.0 * SQRT_2, epsilon = 1e-5);
+        assert_abs_diff_eq!(stretch.get_pixel(4, 3).data[0], 2.0, epsilon = 1e-5);
+        assert_eq!(density.get_pixel(7, 0).data[0], 0.0);
+        assert_eq!(stretch.get_pixel(7, 0).data[0], 0.0);
+    }
+}
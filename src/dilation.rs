@@ -0,0 +1,402 @@
+//!
+//! Provides filling of undefined texels from nearby defined texels, so that texture
+//! filtering and mipmapping do not blend in the undefined color at island borders.
+//!
+
+use image::{ImageBuffer, Luma, Pixel, Primitive};
+use rayon::prelude::*;
+
+/// Subpixel types that can be interpolated by `push_pull_fill`.
+pub trait FillSubpixel: Primitive + Into<f32> {
+    /// Converts back from a floating point value, rounding and clamping integers.
+    fn from_f32(value: f32) -> Self;
+}
+
+impl FillSubpixel for u8 {
+    fn from_f32(value: f32) -> Self {
+        value.round().max(0.0).min(255.0) as u8
+    }
+}
+
+impl FillSubpixel for u16 {
+    fn from_f32(value: f32) -> Self {
+        value.round().max(0.0).min(65535.0) as u16
+    }
+}
+
+impl FillSubpixel for f32 {
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+/// Finds the nearest defined texel for each texel with the jump flooding algorithm,
+/// where texels are defined if they are non-zero in the given coverage mask, e.g.
+/// obtained with `Density::coverage_with_table`.
+///
+/// Texels farther than `max_distance` from any defined texel get `None`. If
+/// `max_distance` is `None`, every texel is assigned a defined texel, unless there
+/// are no defined texels at all. The result is approximate for a small fraction of
+/// texels, as usual with jump flooding.
+pub fn nearest_defined(
+    coverage: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    max_distance: Option<usize>,
+) -> Vec<Option<(u32, u32)>> {
+    let (width, height) = (coverage.width() as usize, coverage.height() as usize);
+    let mut nearest = coverage
+        .enumerate_pixels()
+        .map(|(x, y, p)| if p.data[0] != 0 { Some((x, y)) } else { None })
+        .collect::<Vec<_>>();
+
+    if width == 0 || height == 0 {
+        return nearest;
+    }
+
+    let dist_sqr = |x: usize, y: usize, seed: (u32, u32)| {
+        let dx = x as i64 - seed.0 as i64;
+        let dy = y as i64 - seed.1 as i64;
+        dx * dx + dy * dy
+    };
+
+    let reach = max_distance.unwrap_or(width.max(height)).min(width.max(height));
+    let mut step = (reach.next_power_of_two() / 2).max(1);
+    // A final pass with step one after reaching one fixes most of the remaining errors
+    let mut final_pass = false;
+
+    loop {
+        let previous = nearest.clone();
+        nearest.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for (x, texel) in row.iter_mut().enumerate() {
+                let mut best = *texel;
+                for &(dx, dy) in &[
+                    (-1, -1),
+                    (0, -1),
+                    (1, -1),
+                    (-1, 0),
+                    (1, 0),
+                    (-1, 1),
+                    (0, 1),
+                    (1, 1),
+                ] {
+                    let nx = x as i64 + dx * step as i64;
+                    let ny = y as i64 + dy * step as i64;
+                    if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                        continue;
+                    }
+
+                    if let Some(seed) = previous[ny as usize * width + nx as usize] {
+                        let closer = match best {
+                            None => true,
+                            Some(best) => dist_sqr(x, y, seed) < dist_sqr(x, y, best),
+                        };
+                        if closer {
+                            best = Some(seed);
+                        }
+                    }
+                }
+                *texel = best;
+            }
+        });
+
+        if step > 1 {
+            step /= 2;
+        } else if !final_pass {
+            final_pass = true;
+        } else {
+            break;
+        }
+    }
+
+    if let Some(max_distance) = max_distance {
+        let max_dist_sqr = (max_distance * max_distance) as i64;
+        for (idx, texel) in nearest.iter_mut().enumerate() {
+            let out_of_reach = match *texel {
+                Some(seed) => dist_sqr(idx % width, idx / width, seed) > max_dist_sqr,
+                None => false,
+            };
+            if out_of_reach {
+                *texel = None;
+            }
+        }
+    }
+
+    nearest
+}
+
+/// Fills undefined texels with a copy of the nearest defined texel, up to the given
+/// distance in texels or across the whole image if `max_distance` is `None`.
+///
+/// Texels are defined if they are non-zero in the given coverage mask.
+///
+/// # Panics
+/// Panics if the coverage mask has other dimensions than the image.
+pub fn jump_flood_fill<P>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    coverage: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    max_distance: Option<usize>,
+) where
+    P: Pixel + 'static,
+{
+    assert_eq!(image.dimensions(), coverage.dimensions());
+    let width = image.width() as usize;
+    let nearest = nearest_defined(coverage, max_distance);
+
+    for (idx, source) in nearest.into_iter().enumerate() {
+        let (x, y) = ((idx % width) as u32, (idx / width) as u32);
+        if let Some((source_x, source_y)) = source {
+            if (source_x, source_y) != (x, y) {
+                let pixel = *image.get_pixel(source_x, source_y);
+                image.put_pixel(x, y, pixel);
+            }
+        }
+    }
+}
+
+/// Fills undefined texels with a smooth extrapolation of the defined texels, obtained by
+/// averaging into successively coarser levels and interpolating back into the holes.
+/// Texels farther away than `max_distance` from any defined texel are left as they are.
+///
+/// Texels are defined if they are non-zero in the given coverage mask.
+///
+/// # Panics
+/// Panics if the coverage mask has other dimensions than the image.
+pub fn push_pull_fill<P>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    coverage: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    max_distance: Option<usize>,
+) where
+    P: Pixel + 'static,
+    P::Subpixel: FillSubpixel,
+{
+    assert_eq!(image.dimensions(), coverage.dimensions());
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let channels = P::channel_count() as usize;
+    let defined = coverage.pixels().map(|p| p.data[0] != 0).collect::<Vec<_>>();
+
+    let finest = Level {
+        width,
+        height,
+        colors: image
+            .pixels()
+            .flat_map(|p| p.channels().iter().map(|&c| c.into()))
+            .collect(),
+        weights: defined
+            .iter()
+            .map(|&defined| if defined { 1.0 } else { 0.0 })
+            .collect(),
+    };
+
+    // Pull: average defined texels into ever coarser levels
+    let mut levels = vec![finest];
+    loop {
+        let coarser = {
+            let last = levels.last().unwrap();
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+            last.pull(channels)
+        };
+        levels.push(coarser);
+    }
+
+    // Push: fill holes of the finer levels from the coarser ones
+    for level_idx in (0..(levels.len() - 1)).rev() {
+        let (finer, coarser) = levels.split_at_mut(level_idx + 1);
+        finer[level_idx].push(&coarser[0], channels);
+    }
+
+    let reachable = max_distance.map(|_| nearest_defined(coverage, max_distance));
+    let filled = &levels[0];
+    let mut subpixels = vec![<P::Subpixel as FillSubpixel>::from_f32(0.0); channels];
+
+    for (idx, &weight) in filled.weights.iter().enumerate() {
+        let in_reach = reachable.as_ref().map(|r| r[idx].is_some()).unwrap_or(true);
+        if defined[idx] || !in_reach || weight == 0.0 {
+            continue;
+        }
+
+        for c in 0..channels {
+            let color = filled.colors[idx * channels + c];
+            subpixels[c] = <P::Subpixel as FillSubpixel>::from_f32(color);
+        }
+        let (x, y) = ((idx % width) as u32, (idx / width) as u32);
+        image.put_pixel(x, y, *P::from_slice(&subpixels));
+    }
+}
+
+/// Level of the push-pull pyramid with colors that are not premultiplied and weights
+/// between zero and one.
+struct Level {
+    width: usize,
+    height: usize,
+    colors: Vec<f32>,
+    weights: Vec<f32>,
+}
+
+impl Level {
+    /// Creates a level with half the dimensions, averaging each 2x2 block weighted by
+    /// the weights of the texels.
+    fn pull(&self, channels: usize) -> Level {
+        let width = (self.width + 1) / 2;
+        let height = (self.height + 1) / 2;
+        let mut colors = vec![0.0; width * height * channels];
+        let mut weights = vec![0.0; width * height];
+
+        for y in 0..height {
+            for x in 0..width {
+                let idx = y * width + x;
+                let mut weight_sum = 0.0;
+
+                for fine_y in (2 * y)..(2 * y + 2).min(self.height) {
+                    for fine_x in (2 * x)..(2 * x + 2).min(self.width) {
+                        let fine_idx = fine_y * self.width + fine_x;
+                        let weight = self.weights[fine_idx];
+                        weight_sum += weight;
+                        for c in 0..channels {
+                            colors[idx * channels + c] +=
+                                weight * self.colors[fine_idx * channels + c];
+                        }
+                    }
+                }
+
+                if weight_sum > 0.0 {
+                    for c in 0..channels {
+                        colors[idx * channels + c] /= weight_sum;
+                    }
+                }
+                weights[idx] = weight_sum.min(1.0);
+            }
+        }
+
+        Level {
+            width,
+            height,
+            colors,
+            weights,
+        }
+    }
+
+    /// Blends the bilinearly interpolated colors of the given coarser level into texels
+    /// with weights below one.
+    fn push(&mut self, coarser: &Level, channels: usize) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let idx = y * self.width + x;
+                let weight = self.weights[idx];
+                if weight >= 1.0 {
+                    continue;
+                }
+
+                let (coarse_colors, coarse_weight) = coarser.sample(
+                    (x as f32 + 0.5) / 2.0 - 0.5,
+                    (y as f32 + 0.5) / 2.0 - 0.5,
+                    channels,
+                );
+                if coarse_weight == 0.0 {
+                    continue;
+                }
+
+                for c in 0..channels {
+                    let color = &mut self.colors[idx * channels + c];
+                    *color = weight * *color + (1.0 - weight) * coarse_colors[c];
+                }
+                self.weights[idx] = weight + (1.0 - weight) * coarse_weight.min(1.0);
+            }
+        }
+    }
+
+    /// Interpolates the four texels around the given texel coordinates, ignoring
+    /// texels without weight.
+    fn sample(&self, x: f32, y: f32, channels: usize) -> (Vec<f32>, f32) {
+        let x = x.max(0.0).min((self.width - 1) as f32);
+        let y = y.max(0.0).min((self.height - 1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let mut colors = vec![0.0; channels];
+        let mut weight_sum = 0.0;
+
+        for &(sx, sy, bilinear) in &[
+            (x0, y0, (1.0 - fx) * (1.0 - fy)),
+            (x1, y0, fx * (1.0 - fy)),
+            (x0, y1, (1.0 - fx) * fy),
+            (x1, y1, fx * fy),
+        ] {
+            let idx = sy * self.width + sx;
+            let weight = bilinear * self.weights[idx];
+            weight_sum += weight;
+            for c in 0..channels {
+                colors[c] += weight * self.colors[idx * channels + c];
+            }
+        }
+
+        if weight_sum > 0.0 {
+            for color in &mut colors {
+                *color /= weight_sum;
+            }
+        }
+
+        (colors, weight_sum)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn single_texel_coverage(
+        width: u32,
+        height: u32,
+        x: u32,
+        y: u32,
+    ) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(width, height, |px, py| Luma {
+            data: [if (px, py) == (x, y) { 255 } else { 0 }],
+        })
+    }
+
+    #[test]
+    fn jump_flood_limited_distance() {
+        let coverage = single_texel_coverage(16, 8, 3, 4);
+        let mut image = ImageBuffer::from_fn(16, 8, |x, y| Luma {
+            data: [if (x, y) == (3, 4) { 200 } else { 0_u8 }],
+        });
+
+        jump_flood_fill(&mut image, &coverage, Some(2));
+        assert_eq!(image.get_pixel(5, 4).data[0], 200);
+        assert_eq!(image.get_pixel(4, 5).data[0], 200);
+        assert_eq!(image.get_pixel(6, 4).data[0], 0);
+
+        jump_flood_fill(&mut image, &coverage, None);
+        assert!(image.pixels().all(|p| p.data[0] == 200));
+    }
+
+    #[test]
+    fn nearest_of_two_seeds() {
+        let coverage = ImageBuffer::from_fn(9, 1, |x, _| Luma {
+            data: [if x == 0 || x == 8 { 255 } else { 0 }],
+        });
+        let nearest = nearest_defined(&coverage, None);
+
+        assert_eq!(nearest[2], Some((0, 0)));
+        assert_eq!(nearest[6], Some((8, 0)));
+    }
+
+    #[test]
+    fn push_pull_fills_everything() {
+        let coverage = single_texel_coverage(7, 5, 1, 1);
+        let mut image = ImageBuffer::from_fn(7, 5, |x, y| Luma {
+            data: [if (x, y) == (1, 1) { 0.75 } else { -1.0_f32 }],
+        });
+
+        push_pull_fill(&mut image, &coverage, None);
+        for p in image.pixels() {
+            assert_relative_eq!(p.data[0], 0.75, epsilon = 1e-5);
+        }
+    }
+}
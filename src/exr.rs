@@ -0,0 +1,546 @@
+//!
+//! Provides a minimal writer for OpenEXR images with floating point channels.
+//!
+//! Only single-part scanline images with 32 bit float channels are written.
+//! Supported compression methods are no compression and the lossless ZIPS, ZIP and PIZ
+//! methods. ZIP typically compresses smooth density textures well, while PIZ is usually
+//! better for noisy data such as unfiltered densities or position buffers.
+//!
+
+use deflate::deflate_bytes_zlib;
+use image::{ImageBuffer, Pixel};
+use piz::piz_compress;
+use std::fs::File;
+use std::io::{self, BufWriter, Error, ErrorKind, Write};
+use std::ops::Deref;
+use std::path::Path;
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+/// Version 2, single-part scanline file with short names
+const VERSION: [u8; 4] = [2, 0, 0, 0];
+/// Flag in the second byte of the version field allowing names of up to 255 bytes
+const LONG_NAMES_FLAG: u8 = 0x04;
+/// Maximum length of attribute and channel names in bytes, without and with the long names flag
+const MAX_SHORT_NAME_LEN: usize = 31;
+const MAX_LONG_NAME_LEN: usize = 255;
+/// Pixel type identifier for 32 bit floating point channels
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+/// Compression method used for the pixel data of an EXR image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExrCompression {
+    /// Pixel data is stored as is.
+    None,
+    /// Lossless zlib compression of individual scanlines.
+    Zips,
+    /// Lossless zlib compression of blocks of 16 scanlines.
+    Zip,
+    /// Lossless wavelet and Huffman compression of blocks of 32 scanlines.
+    Piz,
+}
+
+impl ExrCompression {
+    fn id(&self) -> u8 {
+        match *self {
+            ExrCompression::None => 0,
+            ExrCompression::Zips => 2,
+            ExrCompression::Zip => 3,
+            ExrCompression::Piz => 4,
+        }
+    }
+
+    fn lines_per_chunk(&self) -> usize {
+        match *self {
+            ExrCompression::None => 1,
+            ExrCompression::Zips => 1,
+            ExrCompression::Zip => 16,
+            ExrCompression::Piz => 32,
+        }
+    }
+}
+
+/// Saves the given named channels into an EXR file at the given path.
+///
+/// See `write_exr` for details.
+pub fn save_exr<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    channels: &[(&str, &[f32])],
+    compression: ExrCompression,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_exr(&mut writer, width, height, channels, compression)?;
+    writer.flush()
+}
+
+/// Saves the given floating point image into an EXR file at the given path, using the
+/// given names for the channels of the pixels, e.g. `&["R", "G", "B"]`.
+///
+/// See `write_image_exr` for details.
+pub fn save_image_exr<Pa, P, C>(
+    path: Pa,
+    image: &ImageBuffer<P, C>,
+    channel_names: &[&str],
+    compression: ExrCompression,
+) -> io::Result<()>
+where
+    Pa: AsRef<Path>,
+    P: Pixel<Subpixel = f32> + 'static,
+    C: Deref<Target = [f32]>,
+{
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_image_exr(&mut writer, image, channel_names, compression)?;
+    writer.flush()
+}
+
+/// Writes a floating point image, e.g. `ImageBuffer<Luma<f32>, Vec<f32>>` as obtained from
+/// `Density::collect_f32`, as EXR, using the given names for the channels of its pixels.
+///
+/// # Errors
+/// Fails if the number of names does not match the number of channels of the pixel type,
+/// or if writing fails.
+pub fn write_image_exr<W, P, C>(
+    writer: &mut W,
+    image: &ImageBuffer<P, C>,
+    channel_names: &[&str],
+    compression: ExrCompression,
+) -> io::Result<()>
+where
+    W: Write,
+    P: Pixel<Subpixel = f32> + 'static,
+    C: Deref<Target = [f32]>,
+{
+    let channel_count = P::channel_count() as usize;
+    if channel_names.len() != channel_count {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Got {} channel names for an image with {} channels",
+                channel_names.len(),
+                channel_count
+            ),
+        ));
+    }
+
+    // De-interleave into one plane per channel
+    let planes = (0..channel_count)
+        .map(|channel| {
+            image
+                .pixels()
+                .map(|p| p.channels()[channel])
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<_>>();
+
+    let channels = channel_names
+        .iter()
+        .zip(planes.iter())
+        .map(|(&name, plane)| (name, &plane[..]))
+        .collect::<Vec<_>>();
+
+    write_exr(
+        writer,
+        image.width() as usize,
+        image.height() as usize,
+        &channels,
+        compression,
+    )
+}
+
+/// Writes the given named channels, each holding `width * height` values in scanline order,
+/// as a single-part scanline EXR image.
+///
+/// Channel names can be arbitrary, e.g. one channel per substance, but applications
+/// typically interpret `R`, `G`, `B`, `A` and `Y` as color, alpha and luminance. Names
+/// longer than 31 bytes are flagged as long names in the file version, which readers
+/// predating OpenEXR 1.7 do not support.
+///
+/// # Errors
+/// Fails if the width or height is zero, if no channels are given, if a channel name is
+/// empty, longer than 255 bytes or used twice, if a channel has the wrong number of values,
+/// or if writing fails.
+pub fn write_exr<W: Write>(
+    writer: &mut W,
+    width: usize,
+    height: usize,
+    channels: &[(&str, &[f32])],
+    compression: ExrCompression,
+) -> io::Result<()> {
+    validate_channels(width, height, channels)?;
+
+    // Channels must be stored in alphabetical order
+    let mut channels = channels.to_vec();
+    channels.sort_by(|&(a, _), &(b, _)| a.cmp(b));
+
+    let chunks = (0..height)
+        .step_by(compression.lines_per_chunk())
+        .map(|first_line| {
+            let end_line = (first_line + compression.lines_per_chunk()).min(height);
+            let raw = chunk_pixel_data(width, first_line, end_line, &channels);
+            let data = match compression {
+                ExrCompression::None => raw,
+                ExrCompression::Zips | ExrCompression::Zip => zip_compress(raw),
+                ExrCompression::Piz => {
+                    let lines = end_line - first_line;
+                    let compressed = piz_compress(&raw, width, lines, channels.len());
+                    // Readers detect uncompressed chunks by their size, like for ZIP
+                    if compressed.len() < raw.len() {
+                        compressed
+                    } else {
+                        raw
+                    }
+                }
+            };
+            (first_line, data)
+        })
+        .collect::<Vec<_>>();
+
+    let header = header(width, height, &channels, compression);
+    let version = version(&channels);
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&version)?;
+    writer.write_all(&header)?;
+
+    // Offset table, with offsets relative to the start of the file
+    let mut offset = (MAGIC.len() + version.len() + header.len() + 8 * chunks.len()) as u64;
+    for &(_, ref data) in &chunks {
+        writer.write_all(&offset.to_le_bytes())?;
+        offset += 8 + data.len() as u64;
+    }
+
+    for (first_line, data) in chunks {
+        writer.write_all(&(first_line as i32).to_le_bytes())?;
+        writer.write_all(&(data.len() as i32).to_le_bytes())?;
+        writer.write_all(&data)?;
+    }
+
+    Ok(())
+}
+
+fn validate_channels(
+    width: usize,
+    height: usize,
+    channels: &[(&str, &[f32])],
+) -> io::Result<()> {
+    let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidInput, msg));
+
+    // The data window cannot describe an empty image
+    if width == 0 || height == 0 {
+        return invalid(format!("Tried to write EXR image of size {}x{}", width, height));
+    }
+
+    if channels.is_empty() {
+        return invalid("Tried to write EXR image without channels".to_string());
+    }
+
+    for (idx, &(name, data)) in channels.iter().enumerate() {
+        if name.is_empty() {
+            return invalid("EXR channel names must not be empty".to_string());
+        }
+
+        if name.len() > MAX_LONG_NAME_LEN {
+            return invalid(format!(
+                "EXR channel name {} is longer than {} bytes",
+                name, MAX_LONG_NAME_LEN
+            ));
+        }
+
+        if channels[..idx].iter().any(|&(other, _)| other == name) {
+            return invalid(format!("EXR channel name {} used more than once", name));
+        }
+
+        if data.len() != width * height {
+            return invalid(format!(
+                "EXR channel {} has {} values, but expected {}x{}",
+                name,
+                data.len(),
+                width,
+                height
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Version field for the given channels, flagging long names if any channel name
+/// does not fit into the default limit.
+fn version(channels: &[(&str, &[f32])]) -> [u8; 4] {
+    let mut version = VERSION;
+    if channels
+        .iter()
+        .any(|&(name, _)| name.len() > MAX_SHORT_NAME_LEN)
+    {
+        version[1] |= LONG_NAMES_FLAG;
+    }
+    version
+}
+
+fn header(
+    width: usize,
+    height: usize,
+    channels: &[(&str, &[f32])],
+    compression: ExrCompression,
+) -> Vec<u8> {
+    let mut header = Vec::new();
+
+    let mut chlist = Vec::new();
+    for &(name, _) in channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // pLinear and three reserved bytes
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        // x and y sampling
+        chlist.extend_from_slice(&1_i32.to_le_bytes());
+        chlist.extend_from_slice(&1_i32.to_le_bytes());
+    }
+    chlist.push(0);
+    attribute(&mut header, "channels", "chlist", &chlist);
+
+    attribute(&mut header, "compression", "compression", &[compression.id()]);
+
+    let mut window = Vec::new();
+    for &coord in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&coord.to_le_bytes());
+    }
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+
+    // Increasing y
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0_f32.to_bits().to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0_f32.to_bits().to_le_bytes(),
+    );
+
+    // End of header
+    header.push(0);
+    header
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(type_name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+/// Lays out the pixels of the given lines, storing all values of one channel in a line
+/// before proceeding with the next channel.
+fn chunk_pixel_data(
+    width: usize,
+    first_line: usize,
+    end_line: usize,
+    channels: &[(&str, &[f32])],
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity((end_line - first_line) * width * channels.len() * 4);
+
+    for line in first_line..end_line {
+        for &(_, values) in channels {
+            for value in &values[(line * width)..((line + 1) * width)] {
+                data.extend_from_slice(&value.to_bits().to_le_bytes());
+            }
+        }
+    }
+
+    data
+}
+
+/// Compresses pixel data like the reference implementation, by first reordering
+/// bytes, then applying a delta predictor and finally zlib compression.
+///
+/// If compression does not make the data smaller, the raw data is returned, which
+/// readers detect by comparing the size with the expected uncompressed size.
+fn zip_compress(raw: Vec<u8>) -> Vec<u8> {
+    let compressed = deflate_bytes_zlib(&predict(&interleave(&raw)));
+
+    if compressed.len() < raw.len() {
+        compressed
+    } else {
+        raw
+    }
+}
+
+/// Stores the bytes at even offsets in the first half and the bytes at odd offsets
+/// in the second half.
+fn interleave(raw: &[u8]) -> Vec<u8> {
+    raw.iter()
+        .step_by(2)
+        .chain(raw.iter().skip(1).step_by(2))
+        .cloned()
+        .collect()
+}
+
+/// Replaces each byte except the first with its difference to the previous byte, offset by 128.
+fn predict(data: &[u8]) -> Vec<u8> {
+    let mut predicted = data.to_vec();
+    for idx in 1..data.len() {
+        predicted[idx] = data[idx].wrapping_sub(data[idx - 1]).wrapping_add(128);
+    }
+    predicted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Luma;
+
+    fn read_u64(data: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[offset..(offset + 8)]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn read_i32(data: &[u8], offset: usize) -> i32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&data[offset..(offset + 4)]);
+        i32::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn uncompressed_layout() {
+        let (width, height) = (3, 2);
+        let density = vec![0.0, 0.5, 1.0, 1.5, 2.0, 2.5];
+        let moss = vec![-1.0; 6];
+        let mut exr = Vec::new();
+
+        // Given in reverse alphabetical order, will be sorted when written
+        write_exr(
+            &mut exr,
+            width,
+            height,
+            &[("rust", &density[..]), ("moss", &moss[..])],
+            ExrCompression::None,
+        ).unwrap();
+
+        assert_eq!(&exr[0..4], &MAGIC);
+
+        let header_len = header(
+            width,
+            height,
+            &[("moss", &moss[..]), ("rust", &density[..])],
+            ExrCompression::None,
+        ).len();
+        let offsets_start = 8 + header_len;
+        let first_chunk = read_u64(&exr, offsets_start) as usize;
+        let second_chunk = read_u64(&exr, offsets_start + 8) as usize;
+        let line_bytes = width * 2 * 4;
+
+        assert_eq!(first_chunk, offsets_start + 2 * 8);
+        assert_eq!(second_chunk, first_chunk + 8 + line_bytes);
+        assert_eq!(exr.len(), second_chunk + 8 + line_bytes);
+
+        // Second line, with moss first, then rust
+        assert_eq!(read_i32(&exr, second_chunk), 1);
+        assert_eq!(read_i32(&exr, second_chunk + 4) as usize, line_bytes);
+        let first_rust_value = second_chunk + 8 + width * 4;
+        assert_eq!(read_i32(&exr, first_rust_value) as u32, 1.5_f32.to_bits());
+    }
+
+    #[test]
+    fn zip_chunks_cover_all_lines() {
+        let image = ImageBuffer::from_pixel(7, 40, Luma { data: [0.25_f32] });
+        let mut exr = Vec::new();
+
+        write_image_exr(&mut exr, &image, &["Y"], ExrCompression::Zip).unwrap();
+
+        // 40 lines in blocks of 16 lines
+        let header_len = header(7, 40, &[("Y", &[][..])], ExrCompression::Zip).len();
+        let offsets_start = 8 + header_len;
+        let chunk_lines = (0..3)
+            .map(|chunk| read_i32(&exr, read_u64(&exr, offsets_start + chunk * 8) as usize))
+            .collect::<Vec<_>>();
+
+        assert_eq!(chunk_lines, vec![0, 16, 32]);
+    }
+
+    #[test]
+    fn piz_chunks_cover_all_lines() {
+        let image = ImageBuffer::from_fn(5, 70, |x, y| Luma {
+            data: [(x + y) as f32 * 0.5],
+        });
+        let mut exr = Vec::new();
+
+        write_image_exr(&mut exr, &image, &["Y"], ExrCompression::Piz).unwrap();
+
+        // 70 lines in blocks of 32 lines, the last one shorter
+        let header_len = header(5, 70, &[("Y", &[][..])], ExrCompression::Piz).len();
+        let offsets_start = 8 + header_len;
+        let chunks = (0..3)
+            .map(|chunk| read_u64(&exr, offsets_start + chunk * 8) as usize)
+            .collect::<Vec<_>>();
+        let chunk_lines = chunks
+            .iter()
+            .map(|&chunk| read_i32(&exr, chunk))
+            .collect::<Vec<_>>();
+
+        assert_eq!(chunk_lines, vec![0, 32, 64]);
+        // The gradient compresses
+        assert!((read_i32(&exr, chunks[0] + 4) as usize) < 5 * 32 * 4);
+        assert_eq!(exr.len(), chunks[2] + 8 + read_i32(&exr, chunks[2] + 4) as usize);
+    }
+
+    #[test]
+    fn wrong_channel_count_is_rejected() {
+        let image = ImageBuffer::from_pixel(2, 2, Luma { data: [0.0_f32] });
+        let mut exr = Vec::new();
+
+        assert!(write_image_exr(&mut exr, &image, &["R", "G"], ExrCompression::None).is_err());
+        assert!(exr.is_empty());
+    }
+
+    #[test]
+    fn empty_image_is_rejected() {
+        let mut exr = Vec::new();
+
+        let err = write_exr(&mut exr, 0, 3, &[("Y", &[][..])], ExrCompression::Zip).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(write_exr(&mut exr, 3, 0, &[("Y", &[][..])], ExrCompression::None).is_err());
+        assert!(exr.is_empty());
+    }
+
+    #[test]
+    fn long_channel_names_are_flagged() {
+        let long_name = "rust_on_the_lower_part_of_the_bridge";
+        let values = [0.0];
+        let mut short = Vec::new();
+        let mut long = Vec::new();
+
+        write_exr(&mut short, 1, 1, &[("rust", &values[..])], ExrCompression::None).unwrap();
+        write_exr(&mut long, 1, 1, &[(long_name, &values[..])], ExrCompression::None).unwrap();
+
+        assert!(long_name.len() > MAX_SHORT_NAME_LEN);
+        assert_eq!(&short[4..8], &[2, 0, 0, 0]);
+        assert_eq!(&long[4..8], &[2, LONG_NAMES_FLAG, 0, 0]);
+
+        let too_long = "x".repeat(MAX_LONG_NAME_LEN + 1);
+        let err = write_exr(
+            &mut Vec::new(),
+            1,
+            1,
+            &[(&too_long[..], &values[..])],
+            ExrCompression::None,
+        ).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn predictor_of_constant_data() {
+        assert_eq!(predict(&[7, 7, 7, 8]), vec![7, 128, 128, 129]);
+        assert_eq!(interleave(&[0, 1, 2, 3, 4]), vec![0, 2, 4, 1, 3]);
+    }
+}
@@ -0,0 +1,40 @@
+//!
+//! Provides small entities for tests.
+//!
+
+use scene::{DeinterleavedIndexedMeshBuf, Entity, MaterialBuilder};
+use std::rc::Rc;
+
+/// Position and texture coordinates of a vertex.
+pub type FixtureVertex = ([f32; 3], [f32; 2]);
+
+/// Creates an entity with one triangle for each of the given vertex triples, all facing
+/// in positive z direction.
+///
+/// Vertices are not shared between triangles in the index buffer, but triangles with
+/// equal positions and texture coordinates at an edge are connected in UV space.
+pub fn entity(triangles: &[[FixtureVertex; 3]]) -> Entity {
+    let vertices = triangles.iter().flat_map(|t| t.iter());
+    let positions = vertices
+        .clone()
+        .flat_map(|&(position, _)| position.to_vec())
+        .collect::<Vec<f32>>();
+    let texcoords = vertices
+        .flat_map(|&(_, texcoords)| texcoords.to_vec())
+        .collect::<Vec<f32>>();
+    let vertex_count = triangles.len() * 3;
+    let normals = (0..vertex_count)
+        .flat_map(|_| vec![0.0, 0.0, 1.0])
+        .collect::<Vec<f32>>();
+
+    Entity {
+        name: "Fixture".to_string(),
+        material: Rc::new(MaterialBuilder::new().name("FixtureMaterial".to_string()).build()),
+        mesh: Rc::new(DeinterleavedIndexedMeshBuf {
+            positions,
+            normals,
+            texcoords,
+            indices: (0..vertex_count as u32).collect(),
+        }),
+    }
+}
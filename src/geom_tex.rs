@@ -1,15 +1,442 @@
-use geom::{Interpolation, Position, Triangle, Vec3};
+use blend::normal_to_pixel;
+use geom::prelude::ElementWise;
+use geom::{FromVertices, InnerSpace, Triangle, TupleTriangle, Vec2, Vec3};
+use image::{ImageBuffer, Luma, Pixel, Rgb, Rgba};
+use islands::UvIslands;
 use line2d::Line2D;
-use raster::Rasterize;
+use raster::{rasterize_triangles, RasterMode, Rasterize};
 use scene::{Entity, Mesh};
-use uv_triangle::triangle_into_uv_image_space;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::slice;
+use std::sync::Arc;
+use uv_triangle::{triangle_into_uv_image_space, UvVtx};
 
-#[derive(Clone)]
+/// Geometry of the entity surface at a texel, or at one sample of a texel if
+/// supersampling is used.
+#[derive(Debug, Clone)]
 pub struct GeomTexel {
+    /// Interpolated world-space position.
     pub position: Vec3,
+    /// Interpolated and renormalized world-space normal.
     pub normal: Vec3,
-    // The ratio between the area in world space to the area in texture space.
-    //pub scale: f32
+    /// Index of the mesh triangle the texel was obtained from.
+    pub triangle_idx: usize,
+    /// Barycentric coordinates of the texel on the triangle, in the vertex order of the mesh.
+    ///
+    /// Coordinates can be outside of 0..1 for texels in the island bleed.
+    pub barycentric: Vec3,
+    /// Index of the UV island of the triangle.
+    pub island_idx: usize,
+    /// `true` if the texel is inside the triangle, `false` if it is only part of the
+    /// island bleed around it.
+    pub covered: bool,
+    /// The ratio between the area in world space to the area in texture space, that is,
+    /// the world-space area covered by one texel. Zero for degenerate UV triangles.
+    pub scale: f32,
+    /// Row-major matrix mapping offsets in texel units, with x pointing right and y
+    /// pointing up in UV space, to world-space offsets in the tangent plane of the triangle.
+    ///
+    /// The first tangent axis points along increasing x, making the matrix upper triangular.
+    /// Its determinant equals `scale`. Zero for degenerate UV triangles.
+    pub jacobian: [[f32; 2]; 2],
+    /// Index of the sample within the texel, see `Supersampling::offsets`. Always zero
+    /// for texels in the island bleed.
+    pub sample_idx: usize,
+    /// Fraction of the texel area represented by the sample, one without supersampling.
+    pub weight: f32,
+}
+
+/// Specifies which triangles are used for texels covered by more than one triangle,
+/// as is common for mirrored or stacked UV islands.
+///
+/// Triangle interiors always take precedence over the island bleed of other triangles.
+#[derive(Clone)]
+pub enum OverlapPolicy {
+    /// Uses the triangle that comes first in the mesh.
+    KeepFirst,
+    /// Uses the triangle that comes last in the mesh, that is, the last one rasterized.
+    KeepLast,
+    /// Uses all overlapping triangles, so surfels are gathered at every overlapping
+    /// position and the density is averaged over all of them.
+    Average,
+    /// Uses the texel with the highest priority as returned by the function, preferring
+    /// the earlier triangle on ties.
+    Priority(Arc<Fn(&GeomTexel) -> f32 + Send + Sync>),
+}
+
+impl Default for OverlapPolicy {
+    fn default() -> Self {
+        OverlapPolicy::KeepLast
+    }
+}
+
+impl fmt::Debug for OverlapPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OverlapPolicy::KeepFirst => write!(f, "KeepFirst"),
+            OverlapPolicy::KeepLast => write!(f, "KeepLast"),
+            OverlapPolicy::Average => write!(f, "Average"),
+            OverlapPolicy::Priority(_) => write!(f, "Priority(..)"),
+        }
+    }
+}
+
+/// The kind of an `OverlapPolicy`, without the priority function of `Priority` policies,
+/// e.g. to record the policy a surfel lookup table was built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapKind {
+    KeepFirst,
+    KeepLast,
+    Average,
+    Priority,
+}
+
+impl OverlapPolicy {
+    /// Gets the kind of this policy. All `Priority` policies are of the same kind,
+    /// regardless of their priority function.
+    pub fn kind(&self) -> OverlapKind {
+        match *self {
+            OverlapPolicy::KeepFirst => OverlapKind::KeepFirst,
+            OverlapPolicy::KeepLast => OverlapKind::KeepLast,
+            OverlapPolicy::Average => OverlapKind::Average,
+            OverlapPolicy::Priority(_) => OverlapKind::Priority,
+        }
+    }
+
+    /// Selects the texels to use from the candidates in rasterization order, separately
+    /// for each sample of the texel.
+    fn select(&self, candidates: &[GeomTexel]) -> Vec<GeomTexel> {
+        if let OverlapPolicy::Average = *self {
+            return candidates.to_vec();
+        }
+
+        let mut sample_idxs = candidates.iter().map(|g| g.sample_idx).collect::<Vec<_>>();
+        sample_idxs.sort();
+        sample_idxs.dedup();
+
+        sample_idxs
+            .into_iter()
+            .map(|sample_idx| {
+                let sample_candidates = candidates
+                    .iter()
+                    .filter(|g| g.sample_idx == sample_idx)
+                    .collect::<Vec<_>>();
+                self.select_one(&sample_candidates).clone()
+            })
+            .collect()
+    }
+
+    fn select_one<'a>(&self, candidates: &[&'a GeomTexel]) -> &'a GeomTexel {
+        match *self {
+            OverlapPolicy::KeepFirst | OverlapPolicy::Average => candidates[0],
+            OverlapPolicy::KeepLast => candidates[candidates.len() - 1],
+            OverlapPolicy::Priority(ref priority) => {
+                let mut best = candidates[0];
+                let mut best_priority = priority(best);
+                for &candidate in &candidates[1..] {
+                    let candidate_priority = priority(candidate);
+                    if candidate_priority > best_priority {
+                        best = candidate;
+                        best_priority = candidate_priority;
+                    }
+                }
+                best
+            }
+        }
+    }
+}
+
+/// Specifies the positions within each texel at which the geometry is sampled.
+///
+/// Offsets are relative to the lower left texel corner in texel units, with y pointing up
+/// in UV space. Each sample is tested against the triangles separately, so triangles
+/// smaller than a texel are still found if they contain a sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Supersampling {
+    /// Samples the lower left corner of each texel only.
+    Single,
+    /// Samples the centers of the cells of an n×n grid inside each texel.
+    Grid(usize),
+    /// Samples an n×n grid rotated by atan(1/2) around the texel center, wrapped into the
+    /// texel, which avoids samples sharing rows and columns for horizontal and vertical edges.
+    RotatedGrid(usize),
+}
+
+impl Default for Supersampling {
+    fn default() -> Self {
+        Supersampling::Single
+    }
+}
+
+impl Supersampling {
+    /// Number of samples per texel.
+    pub fn sample_count(&self) -> usize {
+        match *self {
+            Supersampling::Single => 1,
+            Supersampling::Grid(n) | Supersampling::RotatedGrid(n) => n * n,
+        }
+    }
+
+    /// Offsets of the samples from the lower left texel corner, in texel units.
+    ///
+    /// # Panics
+    /// Panics for grids with zero samples.
+    pub fn offsets(&self) -> Vec<Vec2> {
+        let (n, rotated) = match *self {
+            Supersampling::Single => return vec![Vec2::new(0.0, 0.0)],
+            Supersampling::Grid(n) => (n, false),
+            Supersampling::RotatedGrid(n) => (n, true),
+        };
+
+        assert!(n > 0, "Supersampling grid must have at least one sample");
+
+        // cos and sin of atan(1/2)
+        let (cos, sin) = (2.0 / 5.0_f32.sqrt(), 1.0 / 5.0_f32.sqrt());
+        let mut offsets = Vec::with_capacity(n * n);
+        for j in 0..n {
+            for i in 0..n {
+                let x = (i as f32 + 0.5) / n as f32;
+                let y = (j as f32 + 0.5) / n as f32;
+                offsets.push(if rotated {
+                    let (x, y) = (x - 0.5, y - 0.5);
+                    let (x, y) = (x * cos - y * sin + 0.5, x * sin + y * cos + 0.5);
+                    Vec2::new(x - x.floor(), y - y.floor())
+                } else {
+                    Vec2::new(x, y)
+                });
+            }
+        }
+        offsets
+    }
+}
+
+/// Options for rasterizing an entity into a `GBuffer`.
+#[derive(Debug, Clone, Default)]
+pub struct BakeOptions {
+    /// Specifies the triangles to use for texels covered by more than one triangle.
+    pub overlap_policy: OverlapPolicy,
+    /// Specifies the positions sampled in each texel.
+    pub supersampling: Supersampling,
+    /// Specifies which texels triangles are rasterized into. With conservative
+    /// rasterization, every sample of a touched texel is considered covered.
+    pub raster_mode: RasterMode,
+    /// If `true`, geometry is interpolated at the nearest point inside the triangle for
+    /// samples outside of it, e.g. with conservative rasterization or in the island bleed,
+    /// instead of extrapolating beyond the triangle.
+    pub clamp_interpolation: bool,
+}
+
+/// World-space geometry of an entity rasterized into texture space.
+#[derive(Debug, Clone)]
+pub struct GBuffer {
+    width: usize,
+    height: usize,
+    island_bleed: usize,
+    /// Options the buffer was baked with
+    options: BakeOptions,
+    /// First selected texel at each location
+    texels: Vec<Option<GeomTexel>>,
+    /// All selected texels for locations with more than one selected texel, due to
+    /// supersampling or the overlap policy
+    layers: HashMap<usize, Vec<GeomTexel>>,
+    /// Number of triangles covering each texel, saturating at 255
+    overlap_counts: Vec<u8>,
+}
+
+impl GBuffer {
+    /// Rasterizes the triangles of the entity into a buffer with the given dimensions,
+    /// drawing the triangle outlines with the given thickness around each UV island so
+    /// there are margins around the islands.
+    ///
+    /// Texels covered by more than one triangle use the last triangle.
+    pub fn bake(entity: &Entity, width: usize, height: usize, island_bleed: usize) -> Self {
+        Self::bake_with_policy(
+            entity,
+            width,
+            height,
+            island_bleed,
+            &OverlapPolicy::default(),
+        )
+    }
+
+    /// Rasterizes the triangles of the entity like `bake`, resolving texels covered by
+    /// more than one triangle with the given policy.
+    pub fn bake_with_policy(
+        entity: &Entity,
+        width: usize,
+        height: usize,
+        island_bleed: usize,
+        policy: &OverlapPolicy,
+    ) -> Self {
+        let options = BakeOptions {
+            overlap_policy: policy.clone(),
+            ..BakeOptions::default()
+        };
+        Self::bake_with_options(entity, width, height, island_bleed, &options)
+    }
+
+    /// Rasterizes the triangles of the entity like `bake`, with the given options.
+    ///
+    /// With supersampling, all covered samples of a texel are kept, so surfels are
+    /// gathered at each of them. Note that this multiplies the memory used per texel
+    /// by the number of samples.
+    pub fn bake_with_options(
+        entity: &Entity,
+        width: usize,
+        height: usize,
+        island_bleed: usize,
+        options: &BakeOptions,
+    ) -> Self {
+        GBufferBaker::new(entity, width, height, island_bleed, options).bake_lines(0..height)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Thickness of the margins drawn around UV islands.
+    pub fn island_bleed(&self) -> usize {
+        self.island_bleed
+    }
+
+    /// Options the buffer was baked with.
+    pub fn bake_options(&self) -> &BakeOptions {
+        &self.options
+    }
+
+    /// Gets the geometry at the given coordinates, with y = 0 being the top line,
+    /// or `None` if the texel is not used by the entity.
+    pub fn texel_at(&self, x: usize, y: usize) -> Option<&GeomTexel> {
+        self.texels[y * self.width + x].as_ref()
+    }
+
+    /// Gets the geometry of all texels in scanline order.
+    pub fn texels(&self) -> &[Option<GeomTexel>] {
+        &self.texels
+    }
+
+    pub fn into_texels(self) -> Vec<Option<GeomTexel>> {
+        self.texels
+    }
+
+    /// Gets all texels selected by the overlap policy at the given texel index in
+    /// scanline order, or an empty slice if the texel is not used by the entity.
+    ///
+    /// More than one texel is selected with supersampling or `OverlapPolicy::Average`.
+    pub fn selected(&self, idx: usize) -> &[GeomTexel] {
+        match self.layers.get(&idx) {
+            Some(layers) => layers,
+            None => match self.texels[idx] {
+                Some(ref texel) => slice::from_ref(texel),
+                None => &[],
+            },
+        }
+    }
+
+    /// Gets the number of triangles covering the texel at the given coordinates,
+    /// not counting island bleed, saturating at 255.
+    pub fn overlap_count(&self, x: usize, y: usize) -> usize {
+        self.overlap_counts[y * self.width + x] as usize
+    }
+
+    /// Gets the fraction of the samples of the texel at the given coordinates that are
+    /// covered by a triangle, which is zero for texels only in the island bleed.
+    pub fn coverage(&self, x: usize, y: usize) -> f32 {
+        self.selected(y * self.width + x)
+            .iter()
+            .filter(|g| g.covered)
+            .map(|g| g.weight)
+            .sum::<f32>()
+            .min(1.0)
+    }
+
+    /// Counts the texels covered by more than one triangle.
+    pub fn overlapping_texel_count(&self) -> usize {
+        self.overlap_counts.iter().filter(|&&count| count > 1).count()
+    }
+
+    /// Creates an image of the number of triangles covering each texel, see `overlap_count`.
+    pub fn overlap_image(&self) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        ImageBuffer::from_raw(
+            self.width as u32,
+            self.height as u32,
+            self.overlap_counts.clone(),
+        ).unwrap()
+    }
+
+    /// Creates an image of world-space positions, e.g. for saving with `save_image_exr`.
+    /// Unused texels are black.
+    pub fn position_image(&self) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+        self.image_from_fn(|g| {
+            let position = g.map(|g| g.position).unwrap_or(Vec3::new(0.0, 0.0, 0.0));
+            Rgb {
+                data: [position.x, position.y, position.z],
+            }
+        })
+    }
+
+    /// Creates an image of world-space normals with components in -1..1, e.g. for saving
+    /// with `save_image_exr`. Unused texels are black.
+    pub fn normal_image(&self) -> ImageBuffer<Rgb<f32>, Vec<f32>> {
+        self.image_from_fn(|g| {
+            let normal = g.map(|g| g.normal).unwrap_or(Vec3::new(0.0, 0.0, 0.0));
+            Rgb {
+                data: [normal.x, normal.y, normal.z],
+            }
+        })
+    }
+
+    /// Creates a world-space normal map with components mapped to 0..255.
+    /// Unused texels are transparent.
+    pub fn normal_map(&self) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        self.image_from_fn(|g| match g {
+            Some(g) => normal_to_pixel(g.normal),
+            None => Rgba { data: [0, 0, 0, 0] },
+        })
+    }
+
+    /// Creates an image of the world-space area covered by each texel, e.g. for a
+    /// texel density heatmap. Unused texels are zero.
+    pub fn scale_image(&self) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+        self.image_from_fn(|g| Luma {
+            data: [g.map(|g| g.scale).unwrap_or(0.0)],
+        })
+    }
+
+    /// Creates an image of the fraction of covered samples of each texel, see `coverage`.
+    pub fn coverage_weight_image(&self) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| Luma {
+            data: [self.coverage(x as usize, y as usize)],
+        })
+    }
+
+    /// Creates a mask that is 255 for texels inside triangles, 128 for texels in the
+    /// island bleed and 0 for unused texels.
+    pub fn coverage_image(&self) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        self.image_from_fn(|g| Luma {
+            data: [match g {
+                Some(&GeomTexel { covered: true, .. }) => 255,
+                Some(_) => 128,
+                None => 0,
+            }],
+        })
+    }
+
+    fn image_from_fn<P, F>(&self, pixel_of: F) -> ImageBuffer<P, Vec<P::Subpixel>>
+    where
+        P: Pixel + 'static,
+        F: Fn(Option<&GeomTexel>) -> P,
+    {
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            pixel_of(self.texel_at(x as usize, y as usize))
+        })
+    }
 }
 
 pub fn geom_tex(
@@ -18,64 +445,603 @@ pub fn geom_tex(
     height: usize,
     island_bleed: usize,
 ) -> Vec<Option<GeomTexel>> {
-    let mut geom_texels = vec![None; width * height];
-
-    // 15 for 4096x4096, 9 for 2048x2048, 6 for 1024x1024, 3 for everything below
-    //let island_bleed = (width / 1024) * 3 + 3;
-
-    //let min_area = 0.15; // At least 15% of a pixel
-
-    let uv_triangles = entity
-        .mesh
-        .triangles()
-        .map(|t| triangle_into_uv_image_space(t, width, height));
-
-    // Before drawing the triangles, draw the outlines in a thick stroke to
-    // ensure there will be margins around the UV islands.
-    // If there is no padding, blender will display it wrong.
-    if island_bleed > 0 {
-        uv_triangles.for_each(|t| {
-            let verts = t.iter().map(Position::position);
-            let next_verts = t.iter().map(Position::position).cycle().skip(1);
-
-            let lines = verts.zip(next_verts).map(|(start, end)| Line2D {
-                start,
-                end,
-                stroke_width: island_bleed * 2,
-            });
+    GBuffer::bake(entity, width, height, island_bleed).into_texels()
+}
+
+/// The triangles of an entity prepared for rasterizing a `GBuffer` in bands of lines,
+/// so that a large buffer never has to be held in memory at once.
+pub struct GBufferBaker<'a> {
+    width: usize,
+    height: usize,
+    island_bleed: usize,
+    options: &'a BakeOptions,
+    islands: UvIslands,
+    uv_triangles: Vec<TupleTriangle<UvVtx>>,
+    jacobians: Vec<[[f32; 2]; 2]>,
+}
+
+impl<'a> GBufferBaker<'a> {
+    /// Prepares rasterizing the given entity like `GBuffer::bake_with_options`.
+    pub fn new(
+        entity: &Entity,
+        width: usize,
+        height: usize,
+        island_bleed: usize,
+        options: &'a BakeOptions,
+    ) -> Self {
+        // 15 for 4096x4096, 9 for 2048x2048, 6 for 1024x1024, 3 for everything below
+        //let island_bleed = (width / 1024) * 3 + 3;
+
+        //let min_area = 0.15; // At least 15% of a pixel
+
+        let uv_triangles = entity
+            .mesh
+            .triangles()
+            .map(|t| triangle_into_uv_image_space(t, width, height))
+            .collect::<Vec<_>>();
+        let jacobians = uv_triangles.iter().map(jacobian).collect::<Vec<_>>();
+
+        GBufferBaker {
+            width,
+            height,
+            island_bleed,
+            options,
+            islands: UvIslands::new(entity),
+            uv_triangles,
+            jacobians,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn island_bleed(&self) -> usize {
+        self.island_bleed
+    }
+
+    pub fn options(&self) -> &BakeOptions {
+        self.options
+    }
+
+    /// Rasterizes the given range of lines, with 0 being the top line, into a buffer
+    /// holding only these lines, with y = 0 being the first line of the range.
+    ///
+    /// The texels are the same as in the corresponding lines of the whole buffer.
+    pub fn bake_lines(&self, lines: Range<usize>) -> GBuffer {
+        let options = self.options;
+        let (mut texels, candidate_lists) = self.candidates(lines.clone());
+
+        let mut overlap_counts = texels
+            .iter()
+            .map(|g| match *g {
+                Some(GeomTexel { covered: true, .. }) => 1,
+                _ => 0,
+            })
+            .collect::<Vec<u8>>();
+
+        let mut layers = HashMap::new();
+        for (idx, candidates) in candidate_lists {
+            let mut covered_triangles = candidates
+                .iter()
+                .filter(|g| g.covered)
+                .map(|g| g.triangle_idx)
+                .collect::<Vec<_>>();
+            covered_triangles.sort();
+            covered_triangles.dedup();
+            overlap_counts[idx] = covered_triangles.len().min(255) as u8;
+
+            let selected = options.overlap_policy.select(&candidates);
+            texels[idx] = Some(selected[0].clone());
+            if selected.len() > 1 {
+                layers.insert(idx, selected);
+            }
+        }
+
+        GBuffer {
+            width: self.width,
+            height: lines.len(),
+            island_bleed: self.island_bleed,
+            options: options.clone(),
+            texels,
+            layers,
+            overlap_counts,
+        }
+    }
+
+    /// Rasterizes the given range of lines, keeping the last candidate in the returned
+    /// vector and all candidates in rasterization order in the map for texels hit by more
+    /// than one triangle.
+    fn candidates(
+        &self,
+        lines: Range<usize>,
+    ) -> (Vec<Option<GeomTexel>>, HashMap<usize, Vec<GeomTexel>>) {
+        let (width, height, island_bleed) = (self.width, self.height, self.island_bleed);
+        let options = self.options;
+        let islands = &self.islands;
+        let uv_triangles = &self.uv_triangles;
+        let jacobians = &self.jacobians;
+
+        let mut geom_texels = vec![None; width * lines.len()];
+        let mut candidates = HashMap::new();
+        let clamp = |weights: Vec3| {
+            if options.clamp_interpolation {
+                clamp_barycentric(weights)
+            } else {
+                weights
+            }
+        };
+
+        // The y axis of the raster points upwards, so the band starts at the raster line
+        // of the last line in the range
+        let (min_y, end_y) = (height - lines.end, height - lines.start);
+        let in_band = |lowest: f32, highest: f32, margin: f32| {
+            highest + margin >= min_y as f32 && lowest - margin <= end_y as f32
+        };
 
-            for l in lines {
-                l.rasterize_to_slice(&mut geom_texels[..], width, height, |x, y| {
-                    Some(GeomTexel {
-                        position: t.interpolate_at(Vec3::new(x as f32, y as f32, 0.0), |v| {
-                            v.world_position
-                        }),
-                        normal: t
-                            .interpolate_at(Vec3::new(x as f32, y as f32, 0.0), |v| v.world_normal),
-                        //scale: unimplemented!("Texel-to-world scale compensation currently unimplemented")
-                    })
-                })
+        // Before drawing the triangles, draw the island outlines in a thick stroke to
+        // ensure there will be margins around the UV islands.
+        // If there is no padding, blender will display it wrong.
+        // Edges inside of islands are skipped since the triangles would cover their stroke.
+        if island_bleed > 0 {
+            let scale = Vec2::new(width as f32, height as f32);
+            let boundary_edges = islands
+                .islands()
+                .iter()
+                .flat_map(|island| island.boundary_edges.iter());
+
+            for edge in boundary_edges {
+                let start = edge.texcoords.0.mul_element_wise(scale);
+                let end = edge.texcoords.1.mul_element_wise(scale);
+                let stroke_width = island_bleed * 2;
+                if !in_band(start.y.min(end.y), start.y.max(end.y), (stroke_width + 1) as f32) {
+                    continue;
+                }
+
+                let triangle_idx = edge.triangle_idx;
+                let t = &uv_triangles[triangle_idx];
+                let island_idx = islands.island_of(triangle_idx);
+                let line = Line2D {
+                    start: start.extend(0.0),
+                    end: end.extend(0.0),
+                    stroke_width,
+                };
+
+                // Lines are clipped by skipping texels outside of the band, so the strokes
+                // are the same as when rasterizing all lines
+                line.rasterize(width, height, |x, y| {
+                    if y < min_y || y >= end_y {
+                        return;
+                    }
+
+                    let jacobian = jacobians[triangle_idx];
+                    let weights = clamp(barycentric(t, Vec2::new(x as f32, y as f32)));
+                    let texel = geom_texel(t, triangle_idx, island_idx, jacobian, weights, false);
+                    let idx = (height - 1 - y - lines.start) * width + x;
+                    push_candidate(&mut geom_texels, &mut candidates, idx, texel);
+                });
             }
-        });
-    }
-
-    // Cannot clone the iterator because the closures cannot be cloned, creating it again
-    let uv_triangles = entity
-        .mesh
-        .triangles()
-        .map(|t| triangle_into_uv_image_space(t, width, height));
-
-    // Next, draw the insides of the triangles, the real star of the show
-    uv_triangles.for_each(|t| {
-        t.rasterize_to_slice(&mut geom_texels[..], width, height, |x, y| {
-            Some(GeomTexel {
-                position: t
-                    .interpolate_at(Vec3::new(x as f32, y as f32, 0.0), |v| v.world_position),
-                normal: t.interpolate_at(Vec3::new(x as f32, y as f32, 0.0), |v| v.world_normal),
-                //scale: unimplemented!("Texel-to-world scale compensation currently unimplemented")
+        }
+
+        // Next, draw the insides of the triangles, the real star of the show.
+        // Triangles far enough outside the band to not touch any sample are skipped.
+        let band_triangles = uv_triangles
+            .iter()
+            .enumerate()
+            .filter(|&(_, t)| {
+                let (v0, v1, v2) = t.vertices();
+                let (y0, y1, y2) = (v0.uv_position.y, v1.uv_position.y, v2.uv_position.y);
+                in_band(y0.min(y1).min(y2), y0.max(y1).max(y2), 2.0)
             })
-        })
-    });
+            .map(|(triangle_idx, _)| triangle_idx)
+            .collect::<Vec<_>>();
+
+        let offsets = options.supersampling.offsets();
+        let weight = 1.0 / offsets.len() as f32;
+        for (sample_idx, &offset) in offsets.iter().enumerate() {
+            // Texels are emitted for their lower left corner, moving the triangles by the
+            // negated offset instead emits them if the sample is inside the triangle.
+            // The barycentric weights of the moved triangle at the corner are the weights of
+            // the original triangle at the sample.
+            // Moving the triangles down by the whole number of lines below the band is exact
+            // and clips them to a raster holding only the lines of the band.
+            let band_offset = Vec2::new(0.0, min_y as f32);
+            let shifted = band_triangles.iter().map(|&triangle_idx| {
+                translate(&translate(&uv_triangles[triangle_idx], -offset), -band_offset)
+            });
+            rasterize_triangles(
+                shifted,
+                options.raster_mode,
+                width,
+                lines.len(),
+                |x, y, weights, band_triangle_idx| {
+                    let triangle_idx = band_triangles[band_triangle_idx];
+                    let t = &uv_triangles[triangle_idx];
+                    let island_idx = islands.island_of(triangle_idx);
+                    let jacobian = jacobians[triangle_idx];
+                    let texel = GeomTexel {
+                        sample_idx,
+                        weight,
+                        ..geom_texel(t, triangle_idx, island_idx, jacobian, clamp(weights), true)
+                    };
+                    let idx = (lines.len() - 1 - y) * width + x;
+                    push_candidate(&mut geom_texels, &mut candidates, idx, texel);
+                },
+            );
+        }
+
+        (geom_texels, candidates)
+    }
+}
+
+/// Records a rasterized texel, moving the candidates of a location into the map once
+/// a second triangle is rasterized there.
+///
+/// Covered texels discard earlier island bleed, and island bleed is ignored where a
+/// triangle covers the texel. For each triangle only the latest texel is kept.
+fn push_candidate(
+    geom_texels: &mut [Option<GeomTexel>],
+    candidates: &mut HashMap<usize, Vec<GeomTexel>>,
+    idx: usize,
+    texel: GeomTexel,
+) {
+    if let Some(existing) = candidates.get_mut(&idx) {
+        add_candidate(existing, texel);
+        geom_texels[idx] = existing.last().cloned();
+        return;
+    }
+
+    match geom_texels[idx].take() {
+        None => geom_texels[idx] = Some(texel),
+        Some(previous) => {
+            let mut existing = vec![previous];
+            add_candidate(&mut existing, texel);
+            geom_texels[idx] = existing.last().cloned();
+            if existing.len() > 1 {
+                candidates.insert(idx, existing);
+            }
+        }
+    }
+}
+
+fn add_candidate(existing: &mut Vec<GeomTexel>, texel: GeomTexel) {
+    if texel.covered {
+        existing.retain(|g| g.covered);
+    } else if existing.iter().any(|g| g.covered) {
+        return;
+    }
+
+    existing.retain(|g| g.triangle_idx != texel.triangle_idx || g.sample_idx != texel.sample_idx);
+    existing.push(texel);
+}
+
+/// Moves the given UV triangle by the given offset in UV image space.
+fn translate(t: &TupleTriangle<UvVtx>, offset: Vec2) -> TupleTriangle<UvVtx> {
+    let (v0, v1, v2) = t.vertices();
+    let moved = |v: &UvVtx| UvVtx {
+        uv_position: v.uv_position + offset,
+        world_normal: v.world_normal,
+        world_position: v.world_position,
+        corner: v.corner,
+    };
+    TupleTriangle::new(moved(&v0), moved(&v1), moved(&v2))
+}
+
+/// Interpolates the geometry of the given triangle with the given barycentric weights
+/// in the vertex order of the UV triangle, as the only sample of a texel.
+fn geom_texel(
+    t: &TupleTriangle<UvVtx>,
+    triangle_idx: usize,
+    island_idx: usize,
+    jacobian: [[f32; 2]; 2],
+    weights: Vec3,
+    covered: bool,
+) -> GeomTexel {
+    let (v0, v1, v2) = t.vertices();
+
+    let position = v0.world_position * weights.x
+        + v1.world_position * weights.y
+        + v2.world_position * weights.z;
+    let normal =
+        v0.world_normal * weights.x + v1.world_normal * weights.y + v2.world_normal * weights.z;
+
+    // Report weights in the vertex order of the mesh, which the UV triangle may have changed
+    let mut barycentric = [0.0; 3];
+    barycentric[v0.corner] = weights.x;
+    barycentric[v1.corner] = weights.y;
+    barycentric[v2.corner] = weights.z;
 
-    geom_texels
+    GeomTexel {
+        position,
+        normal: normal.normalize(),
+        triangle_idx,
+        barycentric: Vec3::new(barycentric[0], barycentric[1], barycentric[2]),
+        island_idx,
+        covered,
+        scale: jacobian[0][0] * jacobian[1][1],
+        jacobian,
+        sample_idx: 0,
+        weight: 1.0,
+    }
+}
+
+/// Calculates the matrix mapping texel offsets to world-space offsets on the given
+/// triangle, see `GeomTexel::jacobian`.
+pub fn jacobian(t: &TupleTriangle<UvVtx>) -> [[f32; 2]; 2] {
+    let (v0, v1, v2) = t.vertices();
+    let (e1, e2) = (
+        v1.world_position - v0.world_position,
+        v2.world_position - v0.world_position,
+    );
+    let (d1, d2) = (v1.uv_position - v0.uv_position, v2.uv_position - v0.uv_position);
+
+    let det = d1.x * d2.y - d2.x * d1.y;
+    if det == 0.0 {
+        return [[0.0; 2]; 2];
+    }
+
+    // World-space derivatives along texture x and y
+    let dp_dx = (e1 * d2.y - e2 * d1.y) / det;
+    let dp_dy = (e2 * d1.x - e1 * d2.x) / det;
+
+    let normal = dp_dx.cross(dp_dy);
+    if normal.magnitude2() == 0.0 {
+        return [[0.0; 2]; 2];
+    }
+
+    let tangent = dp_dx.normalize();
+    let bitangent = normal.normalize().cross(tangent);
+
+    [
+        [dp_dx.magnitude(), dp_dy.dot(tangent)],
+        [0.0, dp_dy.dot(bitangent)],
+    ]
+}
+
+/// Calculates the barycentric coordinates of the given point in UV image space with
+/// respect to the vertices of the given UV triangle.
+///
+/// Degenerate triangles weight all vertices equally.
+fn barycentric(t: &TupleTriangle<UvVtx>, point: Vec2) -> Vec3 {
+    let (a, b, c) = t.positions();
+    let (ab, ac, ap) = (b - a, c - a, point.extend(0.0) - a);
+
+    let denominator = ab.x * ac.y - ac.x * ab.y;
+    if denominator == 0.0 {
+        return Vec3::new(1.0, 1.0, 1.0) / 3.0;
+    }
+
+    let w1 = (ap.x * ac.y - ac.x * ap.y) / denominator;
+    let w2 = (ab.x * ap.y - ap.x * ab.y) / denominator;
+    Vec3::new(1.0 - w1 - w2, w1, w2)
+}
+
+/// Moves barycentric coordinates outside of the triangle onto its border by dropping
+/// negative coordinates and renormalizing.
+fn clamp_barycentric(weights: Vec3) -> Vec3 {
+    let clamped = Vec3::new(weights.x.max(0.0), weights.y.max(0.0), weights.z.max(0.0));
+    let sum = clamped.x + clamped.y + clamped.z;
+    if sum > 0.0 {
+        clamped / sum
+    } else {
+        Vec3::new(1.0, 1.0, 1.0) / 3.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures;
+    use geom::FromVertices;
+
+    fn uv_vtx(x: f32, y: f32, corner: usize) -> UvVtx {
+        UvVtx {
+            uv_position: Vec2::new(x, y),
+            world_normal: Vec3::new(0.0, 0.0, 1.0),
+            world_position: Vec3::new(x, y, 0.0),
+            corner,
+        }
+    }
+
+    #[test]
+    fn barycentric_corners_and_center() {
+        let t = TupleTriangle::new(
+            uv_vtx(0.0, 0.0, 0),
+            uv_vtx(3.0, 0.0, 1),
+            uv_vtx(0.0, 3.0, 2),
+        );
+
+        assert_eq!(barycentric(&t, Vec2::new(0.0, 0.0)), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(barycentric(&t, Vec2::new(3.0, 0.0)), Vec3::new(0.0, 1.0, 0.0));
+        let center = barycentric(&t, Vec2::new(1.0, 1.0));
+        assert_ulps_eq!(center.x, 1.0 / 3.0);
+        assert_ulps_eq!(center.y, 1.0 / 3.0);
+    }
+
+    #[test]
+    fn geom_texel_in_mesh_vertex_order() {
+        // Second and third vertex swapped, as done for clockwise UV triangles
+        let t = TupleTriangle::new(
+            uv_vtx(0.0, 0.0, 0),
+            uv_vtx(4.0, 0.0, 2),
+            uv_vtx(0.0, 4.0, 1),
+        );
+        let weights = barycentric(&t, Vec2::new(4.0, 0.0));
+        let texel = geom_texel(&t, 7, 1, jacobian(&t), weights, true);
+
+        assert_eq!(texel.barycentric, Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(texel.position, Vec3::new(4.0, 0.0, 0.0));
+        assert_eq!(texel.triangle_idx, 7);
+    }
+
+    #[test]
+    fn jacobian_of_scaled_triangle() {
+        // Two world units per texel along x, half a unit along y
+        let world_at = |vtx: UvVtx, world_position: Vec3| UvVtx {
+            world_position,
+            ..vtx
+        };
+        let t = TupleTriangle::new(
+            uv_vtx(0.0, 0.0, 0),
+            world_at(uv_vtx(2.0, 0.0, 1), Vec3::new(4.0, 0.0, 0.0)),
+            world_at(uv_vtx(0.0, 2.0, 2), Vec3::new(0.0, 1.0, 0.0)),
+        );
+
+        let jacobian = jacobian(&t);
+        assert_ulps_eq!(jacobian[0][0], 2.0);
+        assert_ulps_eq!(jacobian[0][1], 0.0);
+        assert_ulps_eq!(jacobian[1][0], 0.0);
+        assert_ulps_eq!(jacobian[1][1], 0.5);
+
+        let weights = barycentric(&t, Vec2::new(1.0, 1.0));
+        let texel = geom_texel(&t, 0, 0, jacobian, weights, true);
+        assert_ulps_eq!(texel.scale, 1.0);
+    }
+
+    #[test]
+    fn overlapping_candidates() {
+        let t = TupleTriangle::new(
+            uv_vtx(0.0, 0.0, 0),
+            uv_vtx(4.0, 0.0, 1),
+            uv_vtx(0.0, 4.0, 2),
+        );
+        let texel = |triangle_idx, x, covered| {
+            let weights = barycentric(&t, Vec2::new(x, 0.0));
+            geom_texel(&t, triangle_idx, 0, jacobian(&t), weights, covered)
+        };
+
+        let mut texels = vec![None];
+        let mut candidates = HashMap::new();
+        push_candidate(&mut texels, &mut candidates, 0, texel(0, 0.0, false));
+        push_candidate(&mut texels, &mut candidates, 0, texel(1, 1.0, true));
+        assert!(candidates.is_empty(), "Covered texel should replace island bleed");
+
+        push_candidate(&mut texels, &mut candidates, 0, texel(2, 2.0, true));
+        push_candidate(&mut texels, &mut candidates, 0, texel(3, 3.0, false));
+        assert_eq!(texels[0].as_ref().unwrap().triangle_idx, 2);
+
+        let candidates = &candidates[&0];
+        let selected_triangles = |policy: OverlapPolicy| {
+            policy
+                .select(candidates)
+                .iter()
+                .map(|g| g.triangle_idx)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(selected_triangles(OverlapPolicy::KeepFirst), vec![1]);
+        assert_eq!(selected_triangles(OverlapPolicy::KeepLast), vec![2]);
+        assert_eq!(selected_triangles(OverlapPolicy::Average), vec![1, 2]);
+        assert_eq!(
+            selected_triangles(OverlapPolicy::Priority(Arc::new(|g| -g.position.x))),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn supersampling_offsets() {
+        assert_eq!(Supersampling::Single.offsets(), vec![Vec2::new(0.0, 0.0)]);
+        assert_eq!(
+            Supersampling::Grid(2).offsets(),
+            vec![
+                Vec2::new(0.25, 0.25),
+                Vec2::new(0.75, 0.25),
+                Vec2::new(0.25, 0.75),
+                Vec2::new(0.75, 0.75),
+            ]
+        );
+
+        let rotated = Supersampling::RotatedGrid(4).offsets();
+        assert_eq!(rotated.len(), Supersampling::RotatedGrid(4).sample_count());
+        assert!(rotated
+            .iter()
+            .all(|o| o.x >= 0.0 && o.x < 1.0 && o.y >= 0.0 && o.y < 1.0));
+
+        // No two samples of the rotated 2x2 grid share a row or column
+        let rotated = Supersampling::RotatedGrid(2).offsets();
+        for (i, a) in rotated.iter().enumerate() {
+            for b in &rotated[(i + 1)..] {
+                assert!((a.x - b.x).abs() > 0.1 && (a.y - b.y).abs() > 0.1);
+            }
+        }
+    }
+
+    #[test]
+    fn samples_selected_separately() {
+        let t = TupleTriangle::new(
+            uv_vtx(0.0, 0.0, 0),
+            uv_vtx(4.0, 0.0, 1),
+            uv_vtx(0.0, 4.0, 2),
+        );
+        let sample = |triangle_idx, sample_idx| GeomTexel {
+            sample_idx,
+            weight: 0.25,
+            ..geom_texel(&t, triangle_idx, 0, jacobian(&t), Vec3::new(1.0, 0.0, 0.0), true)
+        };
+
+        let candidates = vec![sample(0, 0), sample(0, 1), sample(1, 1)];
+        let selected = OverlapPolicy::KeepFirst.select(&candidates);
+        assert_eq!(
+            selected
+                .iter()
+                .map(|g| (g.triangle_idx, g.sample_idx))
+                .collect::<Vec<_>>(),
+            vec![(0, 0), (0, 1)]
+        );
+        let selected = OverlapPolicy::KeepLast.select(&candidates);
+        assert_eq!(selected[1].triangle_idx, 1);
+    }
+
+    #[test]
+    fn clamped_barycentric() {
+        assert_eq!(
+            clamp_barycentric(Vec3::new(1.5, -0.5, 0.0)),
+            Vec3::new(1.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            clamp_barycentric(Vec3::new(0.25, 0.25, 0.5)),
+            Vec3::new(0.25, 0.25, 0.5)
+        );
+    }
+
+    #[test]
+    fn bands_equal_whole_buffer() {
+        let entity = fixtures::entity(&[[
+            ([0.0, 0.0, 0.0], [0.2, 0.1]),
+            ([1.0, 0.0, 0.0], [0.8, 0.3]),
+            ([0.0, 1.0, 0.0], [0.4, 0.9]),
+        ]]);
+        let options = BakeOptions {
+            supersampling: Supersampling::Grid(2),
+            ..BakeOptions::default()
+        };
+        let (width, height) = (8, 13);
+        let baker = GBufferBaker::new(&entity, width, height, 1, &options);
+        let whole = baker.bake_lines(0..height);
+
+        for lines in vec![0..5, 5..6, 6..13] {
+            let band = baker.bake_lines(lines.clone());
+            assert_eq!(band.height(), lines.len());
+            for y in lines.clone() {
+                for x in 0..width {
+                    let expected = whole.selected(y * width + x);
+                    let actual = band.selected((y - lines.start) * width + x);
+                    assert_eq!(actual.len(), expected.len());
+                    for (a, e) in actual.iter().zip(expected) {
+                        assert_eq!(a.position, e.position);
+                        assert_eq!(a.covered, e.covered);
+                        assert_eq!(a.sample_idx, e.sample_idx);
+                    }
+                    assert_eq!(
+                        band.overlap_count(x, y - lines.start),
+                        whole.overlap_count(x, y)
+                    );
+                }
+            }
+        }
+        assert!((0..width * height).any(|idx| !whole.selected(idx).is_empty()));
+    }
 }
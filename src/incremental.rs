@@ -0,0 +1,251 @@
+//!
+//! Provides incremental updates of density textures between simulation iterations.
+//!
+
+use density::{Density, Surface};
+use image::{ImageBuffer, Rgba};
+use lookup_table::SurfelLookupTable;
+use rayon::prelude::*;
+use reverse_index::ReverseIndex;
+use scene::Entity;
+
+/// Keeps a density texture along with its lookup table and updates only the texels
+/// influenced by surfels that changed since the last update.
+pub struct IncrementalDensity {
+    density: Density,
+    table: SurfelLookupTable,
+    reverse: ReverseIndex,
+    image: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    /// Substance of each surfel when last collected, for finding changed surfels
+    snapshot: Vec<f32>,
+}
+
+impl IncrementalDensity {
+    /// Builds a lookup table for the given entity and performs a full initial collection.
+    pub fn new(density: Density, entity: &Entity, surf: &Surface) -> Self {
+        let table = density.table_options().build_table(entity, surf);
+        Self::with_table(density, table, surf)
+    }
+
+    /// Performs a full initial collection with an existing lookup table.
+    ///
+    /// The surface must have the same surfels as the one the table was built for.
+    pub fn with_table(density: Density, table: SurfelLookupTable, surf: &Surface) -> Self {
+        // Any change of a surfel in a texel may change its color, regardless of weight
+        let reverse = ReverseIndex::new(&table, surf.samples.len());
+        let snapshot = substances(&density, surf);
+        Self::with_snapshot(density, table, reverse, snapshot)
+    }
+
+    /// Performs a full initial collection from the given substance of each surfel.
+    fn with_snapshot(
+        density: Density,
+        table: SurfelLookupTable,
+        reverse: ReverseIndex,
+        snapshot: Vec<f32>,
+    ) -> Self {
+        let image = density.collect_from(&table, &|surfel_idx| snapshot[surfel_idx]);
+
+        IncrementalDensity {
+            density,
+            table,
+            reverse,
+            image,
+            snapshot,
+        }
+    }
+
+    /// The density texture as of the last update.
+    pub fn image(&self) -> &ImageBuffer<Rgba<u8>, Vec<u8>> {
+        &self.image
+    }
+
+    pub fn table(&self) -> &SurfelLookupTable {
+        &self.table
+    }
+
+    /// Updates the texels influenced by the given surfels, e.g. the surfels known to
+    /// have been hit by gammatons in the last iteration, and returns the updated texture.
+    pub fn update<I>(
+        &mut self,
+        surf: &Surface,
+        changed_surfels: I,
+    ) -> &ImageBuffer<Rgba<u8>, Vec<u8>>
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let substance_idx = self.density.substance_idx();
+        self.update_from(changed_surfels, |surfel_idx| {
+            surf.samples[surfel_idx].data().substances[substance_idx]
+        })
+    }
+
+    /// Finds the surfels whose substance changed since the last update by comparing
+    /// with the previous substances, and updates the texels they influence.
+    pub fn update_changed(&mut self, surf: &Surface) -> &ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let current = substances(&self.density, surf);
+        self.update_changed_from(current)
+    }
+
+    /// Updates the texels influenced by the given surfels with their current substance
+    /// obtained from `value_of`.
+    fn update_from<I, F>(
+        &mut self,
+        changed_surfels: I,
+        value_of: F,
+    ) -> &ImageBuffer<Rgba<u8>, Vec<u8>>
+    where
+        I: IntoIterator<Item = usize>,
+        F: Fn(usize) -> f32,
+    {
+        let changed_surfels = changed_surfels.into_iter().collect::<Vec<_>>();
+        for &surfel_idx in &changed_surfels {
+            self.snapshot[surfel_idx] = value_of(surfel_idx);
+        }
+
+        self.recollect(&changed_surfels);
+        &self.image
+    }
+
+    /// Updates the texels influenced by surfels whose current substance differs from
+    /// the snapshot.
+    fn update_changed_from(&mut self, current: Vec<f32>) -> &ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let changed_surfels = current
+            .iter()
+            .zip(self.snapshot.iter())
+            .enumerate()
+            .filter(|&(_, (now, before))| now != before)
+            .map(|(surfel_idx, _)| surfel_idx)
+            .collect::<Vec<_>>();
+
+        self.snapshot = current;
+        self.recollect(&changed_surfels);
+        &self.image
+    }
+
+    /// Recollects the texels influenced by the given surfels from the substances in the
+    /// snapshot, in the thread pool of the table options.
+    fn recollect(&mut self, changed_surfels: &[usize]) {
+        let mut affected = changed_surfels
+            .iter()
+            .flat_map(|&surfel_idx| self.reverse.texels(surfel_idx).iter().cloned())
+            .collect::<Vec<_>>();
+        affected.sort_unstable();
+        affected.dedup();
+
+        let density = &self.density;
+        let table = &self.table;
+        let snapshot = &self.snapshot;
+        let value_of = |surfel_idx: usize| snapshot[surfel_idx];
+        let colors = density.table_options().install(|| {
+            affected
+                .par_iter()
+                .map(|&texel_idx| density.color_from(table.texel(texel_idx as usize), &value_of))
+                .collect::<Vec<_>>()
+        });
+
+        let width = self.table.width() as u32;
+        for (texel_idx, color) in affected.into_iter().zip(colors) {
+            self.image.put_pixel(texel_idx % width, texel_idx / width, color);
+        }
+    }
+}
+
+fn substances(density: &Density, surf: &Surface) -> Vec<f32> {
+    let substance_idx = density.substance_idx();
+    surf.samples
+        .iter()
+        .map(|s| s.data().substances[substance_idx])
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lookup_table::TableSettings;
+    use reconstruction::SubstanceFilter;
+    use surfel_table::Gather;
+
+    fn incremental(substances: Vec<f32>) -> IncrementalDensity {
+        let texels = vec![
+            vec![(1.0, 0)],
+            vec![(1.0, 1), (1.0, 0)],
+            vec![],
+            vec![(1.0, 2)],
+        ];
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        let table = SurfelLookupTable::from_texels(settings, 0, texels);
+        let reverse = ReverseIndex::new(&table, substances.len());
+        let density = Density::new(
+            0,
+            2,
+            2,
+            0,
+            0.0,
+            2.0,
+            Rgba { data: [0, 0, 0, 0] },
+            Rgba { data: [0, 0, 0, 255] },
+            Rgba {
+                data: [255, 255, 255, 255],
+            },
+            SubstanceFilter::Flat,
+        );
+        IncrementalDensity::with_snapshot(density, table, reverse, substances)
+    }
+
+    #[test]
+    fn only_affected_texels_recollected() {
+        let mut incremental = incremental(vec![0.0, 1.0, 2.0]);
+        let before = incremental.image().clone();
+
+        // Surfel 0 changes as well, but is not reported yet
+        let substances = [2.0, 1.0, 0.0];
+        incremental.snapshot = substances.to_vec();
+        incremental.recollect(&[2]);
+        let after = incremental
+            .density
+            .collect_from(&incremental.table, &|idx| substances[idx]);
+        assert_eq!(incremental.image().get_pixel(0, 0), before.get_pixel(0, 0));
+        assert_eq!(incremental.image().get_pixel(1, 0), before.get_pixel(1, 0));
+        assert_eq!(incremental.image().get_pixel(1, 1), after.get_pixel(1, 1));
+        assert_ne!(after.get_pixel(1, 1), before.get_pixel(1, 1));
+
+        incremental.recollect(&[0]);
+        assert_eq!(*incremental.image(), after);
+    }
+
+    #[test]
+    fn update_changed_matches_full_collection() {
+        let mut incremental = incremental(vec![0.0, 1.0, 2.0]);
+        let substances = vec![2.0, 1.0, 0.5];
+
+        let updated = incremental.update_changed_from(substances.clone()).clone();
+
+        let full = incremental
+            .density
+            .collect_from(&incremental.table, &|idx| substances[idx]);
+        assert_eq!(updated, full);
+    }
+
+    #[test]
+    fn update_keeps_unreported_surfels() {
+        let initial = [0.0, 1.0, 2.0];
+        let mut incremental = incremental(initial.to_vec());
+
+        // All surfels change, but only surfel 0 is reported
+        let substances = [2.0, 0.0, 0.0];
+        let updated = incremental.update_from(vec![0], |idx| substances[idx]).clone();
+
+        let expected = [substances[0], initial[1], initial[2]];
+        let full = incremental
+            .density
+            .collect_from(&incremental.table, &|idx| expected[idx]);
+        assert_eq!(updated, full);
+        // Surfel 2 alone covers (1, 1), which keeps its color until surfel 2 is reported
+        let before = incremental
+            .density
+            .collect_from(&incremental.table, &|idx| initial[idx]);
+        assert_eq!(updated.get_pixel(1, 1), before.get_pixel(1, 1));
+        assert_ne!(updated.get_pixel(0, 0), before.get_pixel(0, 0));
+    }
+}
@@ -0,0 +1,359 @@
+//!
+//! Provides detection of UV islands, i.e. groups of triangles connected in texture space.
+//!
+
+use geom::{Position, Texcoords, Triangle, Vec2, Vec3};
+use image::{ImageBuffer, Luma, Rgba};
+use raster::Rasterize;
+use scene::{Entity, Mesh};
+use std::collections::HashMap;
+use std::f32::{INFINITY, NEG_INFINITY};
+use uv_triangle::triangle_into_uv_image_space;
+
+/// Finds the UV island of each triangle of the entity mesh.
+///
+/// Two triangles are in the same island if they share an edge, that is, both edge
+/// vertices have the same texture coordinates and positions. Islands are numbered
+/// in order of their first triangle.
+pub fn triangle_islands(entity: &Entity) -> Vec<usize> {
+    UvIslands::new(entity).triangle_islands
+}
+
+/// An edge of a UV island that is not shared with another triangle of the island.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundaryEdge {
+    /// Index of the mesh triangle the edge belongs to.
+    pub triangle_idx: usize,
+    /// Index of the start vertex of the edge in the triangle, the end vertex follows it.
+    pub corner: usize,
+    /// Texture coordinates of the start and end vertex.
+    pub texcoords: (Vec2, Vec2),
+    /// World-space positions of the start and end vertex.
+    pub positions: (Vec3, Vec3),
+}
+
+/// A group of triangles connected in texture space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UvIsland {
+    /// Indexes of the mesh triangles in the island, in ascending order.
+    pub triangles: Vec<usize>,
+    /// Lower left corner of the bounds of the island in texture coordinates.
+    pub uv_min: Vec2,
+    /// Upper right corner of the bounds of the island in texture coordinates.
+    pub uv_max: Vec2,
+    /// Area of the island in texture space, where 1 is the whole texture.
+    pub uv_area: f32,
+    /// Area of the island in world space.
+    pub world_area: f32,
+    /// Edges on the outline of the island, including the outlines of holes.
+    pub boundary_edges: Vec<BoundaryEdge>,
+}
+
+/// UV islands of an entity, see `triangle_islands`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UvIslands {
+    triangle_islands: Vec<usize>,
+    islands: Vec<UvIsland>,
+}
+
+impl UvIslands {
+    /// Finds the UV islands of the given entity.
+    pub fn new(entity: &Entity) -> Self {
+        let mut parents = Vec::new();
+        let mut corners = Vec::new();
+        let mut world_areas = Vec::new();
+        // Maps edges to the triangles and corners they were encountered in
+        let mut edges = HashMap::new();
+
+        for (triangle_idx, triangle) in entity.mesh.triangles().enumerate() {
+            parents.push(triangle_idx);
+            world_areas.push(triangle.area());
+
+            let (v0, v1, v2) = triangle.vertices();
+            let vertices = [
+                (v0.texcoords(), v0.position()),
+                (v1.texcoords(), v1.position()),
+                (v2.texcoords(), v2.position()),
+            ];
+            let keys = [vertex_key(&v0), vertex_key(&v1), vertex_key(&v2)];
+
+            for corner in 0..3 {
+                let start = keys[corner];
+                let end = keys[(corner + 1) % 3];
+                let edge = if start < end { (start, end) } else { (end, start) };
+
+                let users = edges.entry(edge).or_insert_with(Vec::new);
+                if let Some(&(other_idx, _)) = users.first() {
+                    union(&mut parents, triangle_idx, other_idx);
+                }
+                users.push((triangle_idx, corner));
+            }
+
+            corners.push(vertices);
+        }
+
+        // Number islands in order of appearance
+        let mut island_idxs = HashMap::new();
+        let triangle_islands = (0..parents.len())
+            .map(|triangle_idx| {
+                let root = find(&mut parents, triangle_idx);
+                let next_idx = island_idxs.len();
+                *island_idxs.entry(root).or_insert(next_idx)
+            })
+            .collect::<Vec<_>>();
+
+        let mut islands = vec![
+            UvIsland {
+                triangles: Vec::new(),
+                uv_min: Vec2::new(INFINITY, INFINITY),
+                uv_max: Vec2::new(NEG_INFINITY, NEG_INFINITY),
+                uv_area: 0.0,
+                world_area: 0.0,
+                boundary_edges: Vec::new(),
+            };
+            island_idxs.len()
+        ];
+
+        for (triangle_idx, vertices) in corners.iter().enumerate() {
+            let island = &mut islands[triangle_islands[triangle_idx]];
+            island.triangles.push(triangle_idx);
+            island.world_area += world_areas[triangle_idx];
+
+            let (a, b, c) = (vertices[0].0, vertices[1].0, vertices[2].0);
+            let (ab, ac) = (b - a, c - a);
+            island.uv_area += 0.5 * (ab.x * ac.y - ac.x * ab.y).abs();
+
+            for &(texcoords, _) in vertices {
+                island.uv_min = Vec2::new(
+                    island.uv_min.x.min(texcoords.x),
+                    island.uv_min.y.min(texcoords.y),
+                );
+                island.uv_max = Vec2::new(
+                    island.uv_max.x.max(texcoords.x),
+                    island.uv_max.y.max(texcoords.y),
+                );
+            }
+        }
+
+        // Edges used by only one triangle are on the outline of an island
+        let mut boundary_edges = edges
+            .values()
+            .filter(|users| users.len() == 1)
+            .map(|users| users[0])
+            .collect::<Vec<_>>();
+        boundary_edges.sort();
+
+        for (triangle_idx, corner) in boundary_edges {
+            let start = corners[triangle_idx][corner];
+            let end = corners[triangle_idx][(corner + 1) % 3];
+            islands[triangle_islands[triangle_idx]]
+                .boundary_edges
+                .push(BoundaryEdge {
+                    triangle_idx,
+                    corner,
+                    texcoords: (start.0, end.0),
+                    positions: (start.1, end.1),
+                });
+        }
+
+        UvIslands {
+            triangle_islands,
+            islands,
+        }
+    }
+
+    /// Number of islands.
+    pub fn len(&self) -> usize {
+        self.islands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.islands.is_empty()
+    }
+
+    pub fn islands(&self) -> &[UvIsland] {
+        &self.islands
+    }
+
+    /// Gets the index of the island of each triangle of the mesh.
+    pub fn triangle_islands(&self) -> &[usize] {
+        &self.triangle_islands
+    }
+
+    /// Gets the index of the island the triangle with the given index belongs to.
+    pub fn island_of(&self, triangle_idx: usize) -> usize {
+        self.triangle_islands[triangle_idx]
+    }
+
+    /// Rasterizes the island index plus one into each texel covered by a triangle of
+    /// the given entity, which should be the entity the islands were found in.
+    /// Unused texels are zero.
+    pub fn id_image(
+        &self,
+        entity: &Entity,
+        width: usize,
+        height: usize,
+    ) -> ImageBuffer<Luma<u32>, Vec<u32>> {
+        let mut ids = vec![0; width * height];
+
+        for (triangle_idx, t) in entity.mesh.triangles().enumerate() {
+            let id = self.triangle_islands[triangle_idx] as u32 + 1;
+            triangle_into_uv_image_space(t, width, height)
+                .rasterize_to_slice(&mut ids[..], width, height, |_, _| id);
+        }
+
+        ImageBuffer::from_raw(width as u32, height as u32, ids).unwrap()
+    }
+
+    /// Creates an image with a distinct color for each island from an image obtained
+    /// with `id_image`, for visual inspection. Unused texels are transparent.
+    pub fn id_color_image(
+        ids: &ImageBuffer<Luma<u32>, Vec<u32>>,
+    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(ids.width(), ids.height(), |x, y| {
+            match ids.get_pixel(x, y).data[0] {
+                0 => Rgba { data: [0, 0, 0, 0] },
+                id => {
+                    // Scramble the bits so neighboring ids get different colors
+                    let hash = id.wrapping_mul(0x9E37_79B9);
+                    Rgba {
+                        data: [(hash >> 24) as u8, (hash >> 16) as u8, (hash >> 8) as u8, 255],
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Bit patterns of texture coordinates and position, usable as hash key.
+type VertexKey = [u32; 5];
+
+fn vertex_key<V: Position + Texcoords>(vertex: &V) -> VertexKey {
+    let texcoords = vertex.texcoords();
+    let position = vertex.position();
+    [
+        texcoords.x.to_bits(),
+        texcoords.y.to_bits(),
+        position.x.to_bits(),
+        position.y.to_bits(),
+        position.z.to_bits(),
+    ]
+}
+
+fn find(parents: &mut [usize], idx: usize) -> usize {
+    let mut root = idx;
+    while parents[root] != root {
+        root = parents[root];
+    }
+
+    // Compress the path for faster lookups later
+    let mut idx = idx;
+    while parents[idx] != root {
+        let next = parents[idx];
+        parents[idx] = root;
+        idx = next;
+    }
+
+    root
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let root_a = find(parents, a);
+    let root_b = find(parents, b);
+    // Keep lower index as root so numbering follows triangle order
+    if root_a < root_b {
+        parents[root_b] = root_a;
+    } else {
+        parents[root_a] = root_b;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures;
+
+    /// A unit square in the lower left quarter of the texture, made of two triangles,
+    /// and a separate triangle with twice the world area in the upper right quarter.
+    fn two_islands() -> Entity {
+        fixtures::entity(&[
+            [
+                ([0.0, 0.0, 0.0], [0.0, 0.0]),
+                ([1.0, 0.0, 0.0], [0.5, 0.0]),
+                ([1.0, 1.0, 0.0], [0.5, 0.5]),
+            ],
+            [
+                ([0.0, 0.0, 0.0], [0.0, 0.0]),
+                ([1.0, 1.0, 0.0], [0.5, 0.5]),
+                ([0.0, 1.0, 0.0], [0.0, 0.5]),
+            ],
+            [
+                ([2.0, 0.0, 0.0], [0.5, 0.5]),
+                ([4.0, 0.0, 0.0], [1.0, 0.5]),
+                ([2.0, 2.0, 0.0], [0.5, 1.0]),
+            ],
+        ])
+    }
+
+    #[test]
+    fn islands_of_triangles() {
+        let islands = UvIslands::new(&two_islands());
+
+        assert_eq!(islands.len(), 2);
+        assert_eq!(islands.triangle_islands(), &[0, 0, 1]);
+        assert_eq!(islands.islands()[0].triangles, vec![0, 1]);
+        assert_eq!(islands.islands()[1].triangles, vec![2]);
+    }
+
+    #[test]
+    fn island_areas_and_bounds() {
+        let islands = UvIslands::new(&two_islands());
+        let (square, triangle) = (&islands.islands()[0], &islands.islands()[1]);
+
+        assert_ulps_eq!(square.uv_area, 0.25);
+        assert_ulps_eq!(square.world_area, 1.0);
+        assert_eq!(square.uv_min, Vec2::new(0.0, 0.0));
+        assert_eq!(square.uv_max, Vec2::new(0.5, 0.5));
+
+        assert_ulps_eq!(triangle.uv_area, 0.125);
+        assert_ulps_eq!(triangle.world_area, 2.0);
+        assert_eq!(triangle.uv_min, Vec2::new(0.5, 0.5));
+        assert_eq!(triangle.uv_max, Vec2::new(1.0, 1.0));
+    }
+
+    #[test]
+    fn shared_edges_are_not_boundary() {
+        let islands = UvIslands::new(&two_islands());
+        let edges_of = |island_idx: usize| {
+            islands.islands()[island_idx]
+                .boundary_edges
+                .iter()
+                .map(|e| (e.triangle_idx, e.corner))
+                .collect::<Vec<_>>()
+        };
+
+        // The diagonal of the square is shared by both of its triangles
+        assert_eq!(edges_of(0), vec![(0, 0), (0, 1), (1, 1), (1, 2)]);
+        assert_eq!(edges_of(1), vec![(2, 0), (2, 1), (2, 2)]);
+
+        let edge = islands.islands()[0].boundary_edges[1];
+        assert_eq!(edge.texcoords, (Vec2::new(0.5, 0.0), Vec2::new(0.5, 0.5)));
+        assert_eq!(
+            edge.positions,
+            (Vec3::new(1.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn id_image_of_islands() {
+        let entity = two_islands();
+        let ids = UvIslands::new(&entity).id_image(&entity, 8, 8);
+
+        // Image rows start at the top of the texture
+        assert_eq!(ids.get_pixel(1, 5).data[0], 1);
+        assert_eq!(ids.get_pixel(2, 6).data[0], 1);
+        assert_eq!(ids.get_pixel(5, 2).data[0], 2);
+        assert_eq!(ids.get_pixel(1, 1).data[0], 0);
+        assert_eq!(ids.get_pixel(6, 6).data[0], 0);
+    }
+}
@@ -2,6 +2,7 @@ extern crate aitios_geom as geom;
 extern crate aitios_scene as scene;
 extern crate aitios_sim as sim;
 extern crate aitios_surf as surf;
+extern crate deflate;
 extern crate image;
 extern crate rayon;
 #[cfg(test)]
@@ -10,14 +11,57 @@ extern crate approx;
 
 mod blend;
 mod density;
+mod diagnostics;
+mod dilation;
+mod exr;
+#[cfg(test)]
+mod fixtures;
 mod geom_tex;
+mod incremental;
+mod islands;
 mod line2d;
+mod lookup_table;
+mod mask;
+mod packed;
+mod parallel;
+mod piz;
+mod ramp;
 mod raster;
+mod reconstruction;
+mod reverse_index;
+mod seams;
 mod surfel_table;
+mod table_options;
 mod texcoords;
 mod uv_triangle;
+mod validation;
 
 pub use blend::*;
-pub use density::{Density, SubstanceFilter};
+pub use density::Density;
+pub use diagnostics::{
+    singular_values, stretch, stretch_map, texel_density, texel_density_map, UvReport,
+};
+pub use dilation::{jump_flood_fill, nearest_defined, push_pull_fill, FillSubpixel};
+pub use exr::{save_exr, save_image_exr, write_exr, write_image_exr, ExrCompression};
+pub use geom_tex::{BakeOptions, GBuffer, GeomTexel, OverlapKind, OverlapPolicy, Supersampling};
 pub use image::*;
-pub use surfel_table::build_surfel_lookup_table;
+pub use incremental::IncrementalDensity;
+pub use islands::{BoundaryEdge, UvIsland, UvIslands};
+pub use lookup_table::{
+    source_hash, SurfelLookupTable, TableSettings, TexelSurfels, TexelSurfelsIter,
+};
+pub use mask::{apply_mask_at_texcoords, apply_mask_with_table, SurfelProperty};
+pub use packed::{Channel, PackedDensity};
+pub use ramp::{ColorRamp, RampInterpolation};
+pub use raster::{rasterize_triangles, RasterMode, RasterizeBarycentric};
+pub use reconstruction::{
+    Gaussian, InverseDistance, Nearest, Reconstruction, SubstanceFilter, Wendland,
+};
+pub use reverse_index::ReverseIndex;
+pub use seams::{find_seams, seam_texel, stitch_seams, Seam, SeamAdjacency, SeamStitch};
+pub use surfel_table::{
+    build_surfel_lookup_table, build_surfel_lookup_table_with_gather,
+    build_surfel_lookup_table_with_gbuffer, EntityFilter, EntityIdx, Gather, MAX_QUERY_COUNT,
+};
+pub use table_options::TableOptions;
+pub use validation::UvValidation;
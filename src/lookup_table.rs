@@ -0,0 +1,1167 @@
+//!
+//! Provides a persistent table of the surfels influencing each texel.
+//!
+
+use geom::{Normal, Position, Texcoords, Triangle, Vec2, Vec3};
+use geom_tex::{BakeOptions, OverlapKind, Supersampling};
+use raster::RasterMode;
+use scene::{Entity, Mesh};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use surf::Surface;
+use surfel_table::{EntityFilter, Gather};
+
+const MAGIC: [u8; 4] = *b"ASLT";
+const VERSION: u32 = 4;
+
+const DISTANCES_FULL: u8 = 0;
+const DISTANCES_QUANTIZED: u8 = 1;
+
+const WEIGHTS_NONE: u8 = 0;
+const WEIGHTS_QUANTIZED: u8 = 1;
+
+/// Quantized weight of surfels gathered at all samples of a texel
+const FULL_WEIGHT: u16 = 0xFFFF;
+
+/// Settings a surfel lookup table was built with.
+///
+/// The settings are saved along with the table, so that tables built with other settings
+/// can be rejected when loading them, see `TableOptions::load_table`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSettings {
+    pub width: usize,
+    pub height: usize,
+    /// Thickness of the margins drawn around UV islands
+    pub island_bleed: usize,
+    /// Specifies the surfels gathered for each texel
+    pub gather: Gather,
+    /// Specifies the entities whose surfels were gathered
+    pub entity_filter: EntityFilter,
+    /// Kind of policy used for texels covered by more than one triangle
+    pub overlap: OverlapKind,
+    /// Positions sampled in each texel
+    pub supersampling: Supersampling,
+    /// Specifies which texels triangles were rasterized into
+    pub raster_mode: RasterMode,
+    /// `true` if geometry was interpolated at the nearest point inside the triangle for
+    /// samples outside of it
+    pub clamp_interpolation: bool,
+}
+
+impl TableSettings {
+    /// Creates settings for a table with the given dimensions, island bleed and gather mode,
+    /// gathering surfels of all entities for texels baked with the default `BakeOptions`.
+    pub fn new(width: usize, height: usize, island_bleed: usize, gather: Gather) -> Self {
+        let options = BakeOptions::default();
+        TableSettings {
+            width,
+            height,
+            island_bleed,
+            gather,
+            entity_filter: EntityFilter::All,
+            overlap: options.overlap_policy.kind(),
+            supersampling: options.supersampling,
+            raster_mode: options.raster_mode,
+            clamp_interpolation: options.clamp_interpolation,
+        }
+    }
+
+    /// Replaces the overlap policy, supersampling, raster mode and clamping with the ones
+    /// of the given options.
+    pub fn with_bake_options(self, options: &BakeOptions) -> Self {
+        TableSettings {
+            overlap: options.overlap_policy.kind(),
+            supersampling: options.supersampling,
+            raster_mode: options.raster_mode,
+            clamp_interpolation: options.clamp_interpolation,
+            ..self
+        }
+    }
+}
+
+/// Holds the squared distances and indexes of the surfels gathered for each texel
+/// of a texture, in scanline order.
+///
+/// With supersampling, surfels can additionally have a weight in their texel, see
+/// `push_weighted_texel`.
+///
+/// The table depends only on the mesh, the surfel positions and the settings it was built
+/// with, so it can be saved once and loaded again for later simulation iterations.
+///
+/// To keep large tables small, texels outside of UV islands only take up a single bit,
+/// surfel indexes are stored as `u32` and distances can optionally be quantized to 16 bit
+/// with `quantize_distances`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfelLookupTable {
+    settings: TableSettings,
+    /// Hash of the mesh and the surfel positions and normals the table was built from
+    source_hash: u64,
+    /// Number of texels pushed so far
+    texel_count: usize,
+    /// One bit per texel in scanline order, set if the texel has at least one surfel
+    covered: Vec<u64>,
+    /// Number of covered texels before each word in `covered`
+    covered_before: Vec<u32>,
+    /// Index of the first entry of each covered texel, followed by the total entry count
+    offsets: Vec<u32>,
+    /// Surfel indexes of all covered texels, one texel after another
+    surfel_idxs: Vec<u32>,
+    /// Squared distances to the surfels in `surfel_idxs`
+    dist_sqrs: Distances,
+    /// Weights of the surfels in `surfel_idxs`, scaled so that `FULL_WEIGHT` is one,
+    /// or empty if all surfels have full weight
+    weights: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Distances {
+    Full(Vec<f32>),
+    /// Distances scaled so that `u16::max_value()` is the maximum distance
+    Quantized { max: f32, values: Vec<u16> },
+}
+
+/// The surfels of a single texel in a `SurfelLookupTable`, borrowed from the table.
+#[derive(Debug, Clone, Copy)]
+pub struct TexelSurfels<'a> {
+    surfel_idxs: &'a [u32],
+    dist_sqrs: DistanceSlice<'a>,
+    /// Quantized weights, empty if all surfels have full weight
+    weights: &'a [u16],
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DistanceSlice<'a> {
+    Full(&'a [f32]),
+    Quantized { scale: f32, values: &'a [u16] },
+}
+
+impl<'a> TexelSurfels<'a> {
+    /// Creates a view on the given surfel indexes and squared distances.
+    ///
+    /// # Panics
+    /// Panics if the number of indexes and distances differ.
+    pub fn new(surfel_idxs: &'a [u32], dist_sqrs: &'a [f32]) -> Self {
+        assert_eq!(
+            surfel_idxs.len(),
+            dist_sqrs.len(),
+            "Surfel index and distance count must match"
+        );
+
+        TexelSurfels {
+            surfel_idxs,
+            dist_sqrs: DistanceSlice::Full(dist_sqrs),
+            weights: &[],
+        }
+    }
+
+    /// Weights the surfels with the given weights, scaled so that `u16::max_value()`
+    /// is a weight of one, see `weight`.
+    ///
+    /// # Panics
+    /// Panics if the number of weights differs from the number of surfels.
+    pub fn with_weights(self, weights: &'a [u16]) -> Self {
+        assert_eq!(
+            self.surfel_idxs.len(),
+            weights.len(),
+            "Surfel index and weight count must match"
+        );
+
+        TexelSurfels { weights, ..self }
+    }
+
+    /// A texel without surfels.
+    pub fn empty() -> Self {
+        TexelSurfels::new(&[], &[])
+    }
+
+    pub fn len(&self) -> usize {
+        self.surfel_idxs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.surfel_idxs.is_empty()
+    }
+
+    /// Gets the squared distance and surfel index of the entry at the given index.
+    pub fn get(&self, idx: usize) -> (f32, usize) {
+        let dist_sqr = match self.dist_sqrs {
+            DistanceSlice::Full(dist_sqrs) => dist_sqrs[idx],
+            DistanceSlice::Quantized { scale, values } => f32::from(values[idx]) * scale,
+        };
+
+        (dist_sqr, self.surfel_idxs[idx] as usize)
+    }
+
+    /// Gets the weight of the entry at the given index in the texel, which is the
+    /// fraction of the samples of the texel the surfel was gathered at.
+    ///
+    /// Surfels of texels without supersampling have a weight of one.
+    pub fn weight(&self, idx: usize) -> f32 {
+        if self.weights.is_empty() {
+            1.0
+        } else {
+            f32::from(self.weights[idx]) / f32::from(FULL_WEIGHT)
+        }
+    }
+
+    /// Iterates over squared distances and surfel indexes.
+    pub fn iter(&self) -> TexelSurfelsIter<'a> {
+        TexelSurfelsIter {
+            surfels: *self,
+            next: 0,
+        }
+    }
+
+    /// Collects squared distances and surfel indexes into a vector.
+    pub fn to_vec(&self) -> Vec<(f32, usize)> {
+        self.iter().collect()
+    }
+}
+
+/// Iterator over the squared distances and surfel indexes of a texel.
+#[derive(Debug, Clone)]
+pub struct TexelSurfelsIter<'a> {
+    surfels: TexelSurfels<'a>,
+    next: usize,
+}
+
+impl<'a> Iterator for TexelSurfelsIter<'a> {
+    type Item = (f32, usize);
+
+    fn next(&mut self) -> Option<(f32, usize)> {
+        if self.next < self.surfels.len() {
+            self.next += 1;
+            Some(self.surfels.get(self.next - 1))
+        } else {
+            None
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.surfels.len() - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for TexelSurfelsIter<'a> {}
+
+impl SurfelLookupTable {
+    /// Creates an empty table without texels, to be filled with `push_texel`.
+    pub fn new(settings: TableSettings, source_hash: u64) -> Self {
+        let word_count = (settings.width * settings.height + 63) / 64;
+        SurfelLookupTable {
+            settings,
+            source_hash,
+            texel_count: 0,
+            covered: Vec::with_capacity(word_count),
+            covered_before: Vec::with_capacity(word_count),
+            offsets: vec![0],
+            surfel_idxs: Vec::new(),
+            dist_sqrs: Distances::Full(Vec::new()),
+            weights: Vec::new(),
+        }
+    }
+
+    /// Creates a table from the surfels of each texel in scanline order.
+    ///
+    /// # Panics
+    /// Panics if the number of texels does not match width and height.
+    pub fn from_texels(
+        settings: TableSettings,
+        source_hash: u64,
+        texels: Vec<Vec<(f32, usize)>>,
+    ) -> Self {
+        assert_eq!(
+            texels.len(),
+            settings.width * settings.height,
+            "Texel count of surfel lookup table does not match its dimensions"
+        );
+
+        let mut table = Self::new(settings, source_hash);
+        for texel in texels {
+            table.push_texel(&texel);
+        }
+        table
+    }
+
+    /// Appends the surfels of the next texel in scanline order, all with full weight.
+    ///
+    /// # Panics
+    /// Panics if all texels have already been pushed, if distances have been quantized,
+    /// or if a surfel index or the total number of entries does not fit into 32 bits.
+    pub fn push_texel(&mut self, surfels: &[(f32, usize)]) {
+        self.push_entries(surfels, |_| FULL_WEIGHT);
+    }
+
+    /// Appends the surfels of the next texel in scanline order along with the weight of
+    /// each surfel in the texel, from zero to one.
+    ///
+    /// Weights are stored with 16 bit precision, but never rounded to zero. They are
+    /// only stored once a surfel has less than full weight.
+    ///
+    /// # Panics
+    /// Panics like `push_texel`, or if the number of weights differs from the number
+    /// of surfels.
+    pub fn push_weighted_texel(&mut self, surfels: &[(f32, usize)], weights: &[f32]) {
+        assert_eq!(
+            surfels.len(),
+            weights.len(),
+            "Surfel and weight count must match"
+        );
+
+        self.push_entries(surfels, |idx| {
+            let weight = weights[idx].max(0.0).min(1.0);
+            ((weight * f32::from(FULL_WEIGHT)).round() as u16).max(1)
+        });
+    }
+
+    fn push_entries<F>(&mut self, surfels: &[(f32, usize)], weight_of: F)
+    where
+        F: Fn(usize) -> u16,
+    {
+        let texel_idx = self.texel_count;
+        assert!(
+            texel_idx < self.settings.width * self.settings.height,
+            "Pushed more texels than fit into surfel lookup table"
+        );
+
+        let (word, bit) = (texel_idx / 64, texel_idx % 64);
+        if bit == 0 {
+            let covered_before = match self.covered.last() {
+                Some(&last) => self.covered_before[word - 1] + last.count_ones(),
+                None => 0,
+            };
+            self.covered.push(0);
+            self.covered_before.push(covered_before);
+        }
+
+        if !surfels.is_empty() {
+            let max_u32 = u32::max_value() as usize;
+            assert!(
+                surfels.iter().all(|&(_, surfel_idx)| surfel_idx <= max_u32),
+                "Surfel indexes of surfel lookup tables must fit into 32 bits"
+            );
+            assert!(
+                self.surfel_idxs.len() + surfels.len() <= max_u32,
+                "Surfel lookup table cannot hold more than {} entries",
+                max_u32
+            );
+
+            let dist_sqrs = match self.dist_sqrs {
+                Distances::Full(ref mut dist_sqrs) => dist_sqrs,
+                Distances::Quantized { .. } => {
+                    panic!("Cannot push texels after quantizing distances")
+                }
+            };
+
+            let weighted = (0..surfels.len()).any(|idx| weight_of(idx) != FULL_WEIGHT);
+            if weighted && self.weights.is_empty() {
+                self.weights = vec![FULL_WEIGHT; self.surfel_idxs.len()];
+            }
+
+            self.covered[word] |= 1 << bit;
+            for (idx, &(dist_sqr, surfel_idx)) in surfels.iter().enumerate() {
+                dist_sqrs.push(dist_sqr);
+                self.surfel_idxs.push(surfel_idx as u32);
+                if !self.weights.is_empty() {
+                    self.weights.push(weight_of(idx));
+                }
+            }
+            self.offsets.push(self.surfel_idxs.len() as u32);
+        }
+
+        self.texel_count += 1;
+    }
+
+    /// Converts squared distances to 16 bit, relative to the maximum squared distance,
+    /// halving the memory used for distances.
+    pub fn quantize_distances(&mut self) {
+        let quantized = match self.dist_sqrs {
+            Distances::Full(ref dist_sqrs) => {
+                let max = dist_sqrs.iter().cloned().fold(0.0, f32::max);
+                let scale = if max > 0.0 {
+                    f32::from(u16::max_value()) / max
+                } else {
+                    0.0
+                };
+                let values = dist_sqrs
+                    .iter()
+                    .map(|d| (d * scale).round() as u16)
+                    .collect();
+                Distances::Quantized { max, values }
+            }
+            Distances::Quantized { .. } => return,
+        };
+
+        self.dist_sqrs = quantized;
+    }
+
+    /// Settings the table was built with.
+    pub fn settings(&self) -> &TableSettings {
+        &self.settings
+    }
+
+    pub fn width(&self) -> usize {
+        self.settings.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.settings.height
+    }
+
+    pub fn island_bleed(&self) -> usize {
+        self.settings.island_bleed
+    }
+
+    pub fn gather(&self) -> Gather {
+        self.settings.gather
+    }
+
+    /// Hash of the mesh and surfels the table was built from, see `source_hash`.
+    pub fn source_hash(&self) -> u64 {
+        self.source_hash
+    }
+
+    /// Checks if the texel with the given offset in scanline order has surfels.
+    pub fn is_covered(&self, texel_idx: usize) -> bool {
+        self.covered[texel_idx / 64] & (1 << (texel_idx % 64)) != 0
+    }
+
+    /// Gets the surfels of the texel with the given offset in scanline order.
+    pub fn texel(&self, texel_idx: usize) -> TexelSurfels {
+        if !self.is_covered(texel_idx) {
+            return TexelSurfels::empty();
+        }
+
+        let (word, bit) = (texel_idx / 64, texel_idx % 64);
+        let lower_bits = self.covered[word] & ((1 << bit) - 1);
+        let rank = (self.covered_before[word] + lower_bits.count_ones()) as usize;
+
+        let start = self.offsets[rank] as usize;
+        let end = self.offsets[rank + 1] as usize;
+
+        let dist_sqrs = match self.dist_sqrs {
+            Distances::Full(ref dist_sqrs) => DistanceSlice::Full(&dist_sqrs[start..end]),
+            Distances::Quantized { max, ref values } => DistanceSlice::Quantized {
+                scale: max / f32::from(u16::max_value()),
+                values: &values[start..end],
+            },
+        };
+
+        let weights: &[u16] = if self.weights.is_empty() {
+            &[]
+        } else {
+            &self.weights[start..end]
+        };
+
+        TexelSurfels {
+            surfel_idxs: &self.surfel_idxs[start..end],
+            dist_sqrs,
+            weights,
+        }
+    }
+
+    /// Gets the surfels of the texel at the given coordinates, with y = 0 being the top line.
+    pub fn texel_at(&self, x: usize, y: usize) -> TexelSurfels {
+        self.texel(y * self.settings.width + x)
+    }
+
+    /// Iterates over the surfels of all texels in scanline order.
+    pub fn texels<'a>(&'a self) -> impl Iterator<Item = TexelSurfels<'a>> {
+        let texel_count = self.settings.width * self.settings.height;
+        (0..texel_count).map(move |texel_idx| self.texel(texel_idx))
+    }
+
+    /// Saves the table into a file at the given path.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Loads a table from the file at the given path, rejecting it if it was built from
+    /// another mesh or surface, see `read`.
+    ///
+    /// Use `TableOptions::load_table` to also reject tables built with other settings.
+    pub fn load<P, S>(path: P, entity: &Entity, surf: &Surface<S>) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        S: Position + Normal,
+    {
+        Self::read(&mut BufReader::new(File::open(path)?), entity, surf)
+    }
+
+    /// Writes the table in a versioned little-endian binary format.
+    ///
+    /// # Panics
+    /// Panics if not all texels have been pushed.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        assert_eq!(
+            self.texel_count,
+            self.settings.width * self.settings.height,
+            "Tried to write incomplete surfel lookup table"
+        );
+
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+
+        write_settings(writer, &self.settings)?;
+        write_u64(writer, self.source_hash)?;
+        write_u64(writer, self.surfel_idxs.len() as u64)?;
+
+        for &word in &self.covered {
+            write_u64(writer, word)?;
+        }
+
+        // Storing counts instead of offsets, since they compress better
+        for range in self.offsets.windows(2) {
+            writer.write_all(&(range[1] - range[0]).to_le_bytes())?;
+        }
+
+        for &surfel_idx in &self.surfel_idxs {
+            writer.write_all(&surfel_idx.to_le_bytes())?;
+        }
+
+        match self.dist_sqrs {
+            Distances::Full(ref dist_sqrs) => {
+                writer.write_all(&[DISTANCES_FULL])?;
+                for &dist_sqr in dist_sqrs {
+                    writer.write_all(&dist_sqr.to_bits().to_le_bytes())?;
+                }
+            }
+            Distances::Quantized { max, ref values } => {
+                writer.write_all(&[DISTANCES_QUANTIZED])?;
+                writer.write_all(&max.to_bits().to_le_bytes())?;
+                for &value in values {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+
+        if self.weights.is_empty() {
+            writer.write_all(&[WEIGHTS_NONE])?;
+        } else {
+            writer.write_all(&[WEIGHTS_QUANTIZED])?;
+            for &weight in &self.weights {
+                writer.write_all(&weight.to_le_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a table written with `write`.
+    ///
+    /// # Errors
+    /// Fails with `ErrorKind::InvalidData` if the data is not a surfel lookup table,
+    /// was written by an unsupported version, is truncated or corrupt, or if the table
+    /// was built from a different mesh or surfel positions than the given ones.
+    ///
+    /// The length of the remaining data is obtained by seeking, so that corrupt counts
+    /// are rejected before memory is allocated for them.
+    pub fn read<R, S>(reader: &mut R, entity: &Entity, surf: &Surface<S>) -> io::Result<Self>
+    where
+        R: Read + Seek,
+        S: Position + Normal,
+    {
+        Self::read_checked(reader, self::source_hash(entity, surf), surf.samples.len())
+    }
+
+    /// Reads a table like `read`, given the expected source hash and the number of surfels
+    /// on the surface.
+    fn read_checked<R>(reader: &mut R, expected_hash: u64, surfel_count: usize) -> io::Result<Self>
+    where
+        R: Read + Seek,
+    {
+        let start = reader.seek(SeekFrom::Current(0))?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
+
+        let mut reader = BoundedReader {
+            reader,
+            remaining: end.saturating_sub(start),
+        };
+
+        Self::read_bounded(&mut reader, expected_hash, surfel_count).map_err(|err| {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                invalid_data("Surfel lookup table is truncated".to_string())
+            } else {
+                err
+            }
+        })
+    }
+
+    fn read_bounded<R: Read>(
+        reader: &mut BoundedReader<R>,
+        expected_hash: u64,
+        surfel_count: usize,
+    ) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("Not a surfel lookup table".to_string()));
+        }
+
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported surfel lookup table version {}, expected {}",
+                version, VERSION
+            )));
+        }
+
+        let settings = read_settings(reader)?;
+
+        let source_hash = read_u64(reader)?;
+        if source_hash != expected_hash {
+            return Err(invalid_data(
+                "Surfel lookup table is stale, mesh or surfels have changed".to_string(),
+            ));
+        }
+
+        let entry_count = read_u64(reader)?;
+
+        let texel_count = settings
+            .width
+            .checked_mul(settings.height)
+            .ok_or_else(|| invalid_data("Surfel lookup table is too large".to_string()))?;
+        let word_count = reader.check_count(
+            texel_count as u64 / 64 + u64::from(texel_count % 64 != 0),
+            8,
+            "coverage words",
+        )?;
+        let mut covered = Vec::with_capacity(word_count);
+        let mut covered_before = Vec::with_capacity(word_count);
+        let mut covered_count = 0_u32;
+        for _ in 0..word_count {
+            let word = read_u64(reader)?;
+            covered.push(word);
+            covered_before.push(covered_count);
+            covered_count = covered_count
+                .checked_add(word.count_ones())
+                .ok_or_else(|| invalid_data("Surfel lookup table is too large".to_string()))?;
+        }
+
+        let covered_count = reader.check_count(u64::from(covered_count), 4, "texel counts")?;
+        let mut offsets = Vec::with_capacity(covered_count + 1);
+        let mut offset = 0_u32;
+        offsets.push(offset);
+        for _ in 0..covered_count {
+            offset = offset
+                .checked_add(read_u32(reader)?)
+                .ok_or_else(|| invalid_data("Surfel lookup table is too large".to_string()))?;
+            offsets.push(offset);
+        }
+
+        if u64::from(offset) != entry_count {
+            return Err(invalid_data(format!(
+                "Surfel lookup table has {} entries, but texels refer to {}",
+                entry_count, offset
+            )));
+        }
+
+        let entry_count = reader.check_count(entry_count, 4, "surfel indexes")?;
+        let mut surfel_idxs = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            let surfel_idx = read_u32(reader)?;
+
+            if surfel_idx as usize >= surfel_count {
+                return Err(invalid_data(format!(
+                    "Surfel lookup table refers to surfel {}, but surface has {} surfels",
+                    surfel_idx, surfel_count
+                )));
+            }
+
+            surfel_idxs.push(surfel_idx);
+        }
+
+        let mut distances_tag = [0];
+        reader.read_exact(&mut distances_tag)?;
+        let dist_sqrs = match distances_tag[0] {
+            DISTANCES_FULL => {
+                reader.check_count(entry_count as u64, 4, "distances")?;
+                Distances::Full(
+                    (0..entry_count)
+                        .map(|_| read_u32(reader).map(f32::from_bits))
+                        .collect::<io::Result<_>>()?,
+                )
+            }
+            DISTANCES_QUANTIZED => {
+                let max = f32::from_bits(read_u32(reader)?);
+                reader.check_count(entry_count as u64, 2, "distances")?;
+                let values = (0..entry_count)
+                    .map(|_| read_u16(reader))
+                    .collect::<io::Result<_>>()?;
+                Distances::Quantized { max, values }
+            }
+            tag => {
+                return Err(invalid_data(format!(
+                    "Unknown distance encoding {} in surfel lookup table",
+                    tag
+                )))
+            }
+        };
+
+        let weights = match read_u8(reader)? {
+            WEIGHTS_NONE => Vec::new(),
+            WEIGHTS_QUANTIZED => {
+                reader.check_count(entry_count as u64, 2, "weights")?;
+                (0..entry_count)
+                    .map(|_| read_u16(reader))
+                    .collect::<io::Result<_>>()?
+            }
+            tag => {
+                return Err(invalid_data(format!(
+                    "Unknown weight encoding {} in surfel lookup table",
+                    tag
+                )))
+            }
+        };
+
+        Ok(SurfelLookupTable {
+            settings,
+            source_hash,
+            texel_count,
+            covered,
+            covered_before,
+            offsets,
+            surfel_idxs,
+            dist_sqrs,
+            weights,
+        })
+    }
+}
+
+/// Reader that keeps track of the bytes left in the underlying stream, so that counts
+/// read from corrupt files can be rejected before allocating memory for them.
+struct BoundedReader<'a, R: 'a> {
+    reader: &'a mut R,
+    remaining: u64,
+}
+
+impl<'a, R> BoundedReader<'a, R> {
+    /// Checks that `count` values of `value_size` bytes each fit into the remaining bytes,
+    /// returning the count.
+    fn check_count(&self, count: u64, value_size: u64, name: &str) -> io::Result<usize> {
+        match count.checked_mul(value_size) {
+            Some(len) if len <= self.remaining && count <= usize::max_value() as u64 => {
+                Ok(count as usize)
+            }
+            _ => Err(invalid_data(format!(
+                "Surfel lookup table is truncated or corrupt, {} {} do not fit into the \
+                 remaining {} bytes",
+                count, name, self.remaining
+            ))),
+        }
+    }
+}
+
+impl<'a, R: Read> Read for BoundedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.remaining = self.remaining.saturating_sub(len as u64);
+        Ok(len)
+    }
+}
+
+/// Calculates a hash of the mesh geometry of the given entity and the positions and
+/// normals of the surfels, which are the inputs to building a surfel lookup table.
+///
+/// The hash is stable across program runs and platforms, unlike the hashers in `std`.
+pub fn source_hash<S>(entity: &Entity, surf: &Surface<S>) -> u64
+where
+    S: Position + Normal,
+{
+    let mut hash = Fnv1a::new();
+
+    for triangle in entity.mesh.triangles() {
+        let (v0, v1, v2) = triangle.vertices();
+        for vertex in &[v0, v1, v2] {
+            hash.vec3(vertex.position());
+            hash.vec3(vertex.normal());
+            hash.vec2(vertex.texcoords());
+        }
+    }
+
+    for surfel in &surf.samples {
+        hash.vec3(surfel.position());
+        hash.vec3(surfel.normal());
+    }
+
+    hash.finish()
+}
+
+/// 64 bit FNV-1a hash.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn vec2(&mut self, v: Vec2) {
+        self.bytes(&v.x.to_bits().to_le_bytes());
+        self.bytes(&v.y.to_bits().to_le_bytes());
+    }
+
+    fn vec3(&mut self, v: Vec3) {
+        self.bytes(&v.x.to_bits().to_le_bytes());
+        self.bytes(&v.y.to_bits().to_le_bytes());
+        self.bytes(&v.z.to_bits().to_le_bytes());
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn write_settings<W: Write>(writer: &mut W, settings: &TableSettings) -> io::Result<()> {
+    write_u64(writer, settings.width as u64)?;
+    write_u64(writer, settings.height as u64)?;
+    write_u64(writer, settings.island_bleed as u64)?;
+    write_gather(writer, settings.gather)?;
+    write_entity_filter(writer, &settings.entity_filter)?;
+
+    let overlap = match settings.overlap {
+        OverlapKind::KeepFirst => 0_u8,
+        OverlapKind::KeepLast => 1,
+        OverlapKind::Average => 2,
+        OverlapKind::Priority => 3,
+    };
+    let (supersampling, grid_size) = match settings.supersampling {
+        Supersampling::Single => (0_u8, 1),
+        Supersampling::Grid(n) => (1, n),
+        Supersampling::RotatedGrid(n) => (2, n),
+    };
+    let raster_mode = match settings.raster_mode {
+        RasterMode::Exact => 0_u8,
+        RasterMode::Conservative => 1,
+    };
+
+    writer.write_all(&[overlap, supersampling])?;
+    write_u64(writer, grid_size as u64)?;
+    writer.write_all(&[raster_mode, settings.clamp_interpolation as u8])
+}
+
+fn read_settings<R: Read>(reader: &mut BoundedReader<R>) -> io::Result<TableSettings> {
+    let width = read_u64(reader)? as usize;
+    let height = read_u64(reader)? as usize;
+    let island_bleed = read_u64(reader)? as usize;
+    let gather = read_gather(reader)?;
+    let entity_filter = read_entity_filter(reader)?;
+
+    let overlap = match read_u8(reader)? {
+        0 => OverlapKind::KeepFirst,
+        1 => OverlapKind::KeepLast,
+        2 => OverlapKind::Average,
+        3 => OverlapKind::Priority,
+        tag => return Err(unknown_setting("overlap policy", tag)),
+    };
+    let supersampling_tag = read_u8(reader)?;
+    let grid_size = read_u64(reader)? as usize;
+    let supersampling = match supersampling_tag {
+        0 => Supersampling::Single,
+        1 => Supersampling::Grid(grid_size),
+        2 => Supersampling::RotatedGrid(grid_size),
+        tag => return Err(unknown_setting("supersampling", tag)),
+    };
+    let raster_mode = match read_u8(reader)? {
+        0 => RasterMode::Exact,
+        1 => RasterMode::Conservative,
+        tag => return Err(unknown_setting("raster mode", tag)),
+    };
+    let clamp_interpolation = match read_u8(reader)? {
+        0 => false,
+        1 => true,
+        tag => return Err(unknown_setting("interpolation clamping", tag)),
+    };
+
+    Ok(TableSettings {
+        width,
+        height,
+        island_bleed,
+        gather,
+        entity_filter,
+        overlap,
+        supersampling,
+        raster_mode,
+        clamp_interpolation,
+    })
+}
+
+fn write_gather<W: Write>(writer: &mut W, gather: Gather) -> io::Result<()> {
+    let (tag, count, radius) = match gather {
+        Gather::Nearest(count) => (0_u8, count, 0.0),
+        Gather::NearestWithin { count, radius } => (1, count, radius),
+        Gather::Within(radius) => (2, 0, radius),
+    };
+
+    writer.write_all(&[tag])?;
+    write_u64(writer, count as u64)?;
+    writer.write_all(&radius.to_bits().to_le_bytes())
+}
+
+fn read_gather<R: Read>(reader: &mut R) -> io::Result<Gather> {
+    let tag = read_u8(reader)?;
+    let count = read_u64(reader)? as usize;
+    let radius = f32::from_bits(read_u32(reader)?);
+
+    match tag {
+        0 => Ok(Gather::Nearest(count)),
+        1 => Ok(Gather::NearestWithin { count, radius }),
+        2 => Ok(Gather::Within(radius)),
+        tag => Err(unknown_setting("gather mode", tag)),
+    }
+}
+
+fn write_entity_filter<W: Write>(writer: &mut W, entity_filter: &EntityFilter) -> io::Result<()> {
+    match *entity_filter {
+        EntityFilter::All => writer.write_all(&[0]),
+        EntityFilter::Own(entity_idx) => {
+            writer.write_all(&[1])?;
+            write_u64(writer, entity_idx as u64)
+        }
+        EntityFilter::Entities(ref entities) => {
+            writer.write_all(&[2])?;
+            write_u64(writer, entities.len() as u64)?;
+            for &entity_idx in entities {
+                write_u64(writer, entity_idx as u64)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn read_entity_filter<R: Read>(reader: &mut BoundedReader<R>) -> io::Result<EntityFilter> {
+    match read_u8(reader)? {
+        0 => Ok(EntityFilter::All),
+        1 => Ok(EntityFilter::Own(read_u64(reader)? as usize)),
+        2 => {
+            let count = read_u64(reader)?;
+            let count = reader.check_count(count, 8, "entity indexes")?;
+            let entities = (0..count)
+                .map(|_| read_u64(reader).map(|idx| idx as usize))
+                .collect::<io::Result<_>>()?;
+            Ok(EntityFilter::Entities(entities))
+        }
+        tag => Err(unknown_setting("entity filter", tag)),
+    }
+}
+
+fn unknown_setting(name: &str, tag: u8) -> Error {
+    invalid_data(format!("Unknown {} {} in surfel lookup table", name, tag))
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn texels() -> Vec<Vec<(f32, usize)>> {
+        vec![vec![(1.0, 3), (2.0, 4)], vec![], vec![(0.5, 1)], vec![]]
+    }
+
+    #[test]
+    fn flat_layout() {
+        let texels = texels();
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        let table = SurfelLookupTable::from_texels(settings, 0, texels.clone());
+
+        assert_eq!(table.texel_at(0, 0).to_vec(), texels[0]);
+        assert!(table.texel_at(1, 0).is_empty());
+        assert_eq!(table.texel_at(0, 1).to_vec(), texels[2]);
+        assert_eq!(
+            table.texels().map(|t| t.to_vec()).collect::<Vec<_>>(),
+            texels
+        );
+    }
+
+    #[test]
+    fn coverage_across_words() {
+        let mut texels = vec![Vec::new(); 200];
+        texels[0] = vec![(1.0, 0)];
+        texels[130] = vec![(2.0, 1), (3.0, 2)];
+        texels[199] = vec![(4.0, 3)];
+        let settings = TableSettings::new(20, 10, 0, Gather::Nearest(2));
+        let table = SurfelLookupTable::from_texels(settings, 0, texels.clone());
+
+        assert!(table.is_covered(130));
+        assert!(!table.is_covered(131));
+        assert_eq!(table.texel(130).to_vec(), texels[130]);
+        assert_eq!(table.texel(199).to_vec(), texels[199]);
+    }
+
+    #[test]
+    fn quantized_distances() {
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        let mut table = SurfelLookupTable::from_texels(settings, 0, texels());
+        table.quantize_distances();
+
+        let (max_dist_sqr, surfel_idx) = table.texel(0).get(1);
+        assert_eq!(surfel_idx, 4);
+        assert_ulps_eq!(max_dist_sqr, 2.0);
+
+        let (dist_sqr, _) = table.texel(2).get(0);
+        assert_abs_diff_eq!(dist_sqr, 0.5, epsilon = 2.0 / 65535.0);
+    }
+
+    fn weighted_table() -> SurfelLookupTable {
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        let mut table = SurfelLookupTable::new(settings, 7);
+        table.push_texel(&[(1.0, 3)]);
+        table.push_weighted_texel(&[(1.0, 1), (2.0, 2)], &[1.0, 0.25]);
+        table.push_texel(&[]);
+        table.push_weighted_texel(&[(0.5, 0)], &[1e-9]);
+        table
+    }
+
+    #[test]
+    fn weights_stored_once_needed() {
+        let table = SurfelLookupTable::from_texels(
+            TableSettings::new(2, 2, 0, Gather::Nearest(2)),
+            0,
+            texels(),
+        );
+        assert!(table.weights.is_empty());
+        assert_eq!(table.texel(0).weight(1), 1.0);
+
+        let table = weighted_table();
+        assert_eq!(table.texel(0).weight(0), 1.0);
+        assert_eq!(table.texel(1).weight(0), 1.0);
+        assert_abs_diff_eq!(table.texel(1).weight(1), 0.25, epsilon = 1.0 / 65535.0);
+        // Tiny weights do not become zero
+        assert!(table.texel(3).weight(0) > 0.0);
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(target_pointer_width = "64")]
+    fn surfel_index_beyond_32_bits() {
+        let settings = TableSettings::new(1, 1, 0, Gather::Nearest(1));
+        let mut table = SurfelLookupTable::new(settings, 0);
+        table.push_texel(&[(1.0, u32::max_value() as usize + 1)]);
+    }
+
+    fn written(table: &SurfelLookupTable) -> Vec<u8> {
+        let mut data = Vec::new();
+        table.write(&mut data).unwrap();
+        data
+    }
+
+    fn read(data: &[u8]) -> io::Result<SurfelLookupTable> {
+        SurfelLookupTable::read_checked(&mut Cursor::new(data), 7, 5)
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        let settings = TableSettings {
+            entity_filter: EntityFilter::Entities(vec![1, 4]),
+            overlap: OverlapKind::Priority,
+            supersampling: Supersampling::RotatedGrid(3),
+            raster_mode: RasterMode::Conservative,
+            clamp_interpolation: true,
+            ..TableSettings::new(2, 2, 3, Gather::NearestWithin { count: 2, radius: 0.5 })
+        };
+        let mut table = SurfelLookupTable::from_texels(settings, 7, texels());
+
+        assert_eq!(read(&written(&table)).unwrap(), table);
+
+        table.quantize_distances();
+        assert_eq!(read(&written(&table)).unwrap(), table);
+
+        let table = weighted_table();
+        assert_eq!(read(&written(&table)).unwrap(), table);
+    }
+
+    #[test]
+    fn truncated_table_rejected() {
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        let data = written(&SurfelLookupTable::from_texels(settings, 7, texels()));
+
+        for len in 0..data.len() {
+            let err = read(&data[..len]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "Truncated to {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn corrupt_counts_rejected() {
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        let table = SurfelLookupTable::from_texels(settings.clone(), 7, texels());
+        let mut header = Vec::new();
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        write_settings(&mut header, &settings).unwrap();
+        header.extend_from_slice(&7_u64.to_le_bytes());
+        let entry_count_offset = header.len();
+
+        // Entry count that does not match the texel counts
+        let mut data = written(&table);
+        data[entry_count_offset..(entry_count_offset + 8)]
+            .copy_from_slice(&u64::max_value().to_le_bytes());
+        assert_eq!(read(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // Dimensions that would overflow or require more coverage words than present
+        for &(width, height) in &[(u64::max_value(), 2), (1 << 40, 1 << 20)] {
+            let mut data = written(&table);
+            data[8..16].copy_from_slice(&width.to_le_bytes());
+            data[16..24].copy_from_slice(&height.to_le_bytes());
+            assert_eq!(read(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+
+        // Refers to more surfels than the surface has
+        let err = SurfelLookupTable::read_checked(&mut Cursor::new(written(&table)), 7, 4)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let stale = SurfelLookupTable::read_checked(&mut Cursor::new(written(&table)), 8, 5);
+        assert_eq!(stale.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fnv1a_reference() {
+        let mut hash = Fnv1a::new();
+        hash.bytes(b"a");
+        assert_eq!(hash.finish(), 0xaf63dc4c8601ec8c);
+    }
+}
@@ -0,0 +1,201 @@
+//!
+//! Provides functionality for initializing surfel data from painted texture masks,
+//! the inverse of collecting density textures from surfels.
+//!
+
+use density::Surface;
+use geom::Texcoords;
+use image::{GenericImage, Luma, Pixel, Primitive};
+use lookup_table::SurfelLookupTable;
+use reverse_index::ReverseIndex;
+use sim::SurfelData;
+use std::f32::EPSILON;
+use texcoords::{offset_to_uv, repeat_mirror, sample};
+
+/// Selects the value in `SurfelData` that a mask is written into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SurfelProperty {
+    /// Amount of the substance with the given index.
+    Substance(usize),
+    /// Deposition rate of the substance with the given index.
+    DepositionRate(usize),
+}
+
+/// Samples the luminosity of the mask at the texture coordinates of each surfel of the
+/// entity with the given index and writes the result of `combine` into the given property.
+///
+/// `combine` receives the current value of the property and the mask luminosity in
+/// the range 0..1, e.g. `|_, mask| mask` to replace the value or
+/// `|rate, mask| rate * mask` to modulate it.
+///
+/// Surfels of other entities are left unchanged.
+pub fn apply_mask_at_texcoords<I, F>(
+    surf: &mut Surface,
+    entity_idx: usize,
+    mask: &I,
+    property: SurfelProperty,
+    combine: F,
+) where
+    I: GenericImage,
+    <<I as GenericImage>::Pixel as Pixel>::Subpixel: Into<f32> + 'static,
+    F: Fn(f32, f32) -> f32,
+{
+    for surfel in surf.samples.iter_mut() {
+        if surfel.data().entity_idx != entity_idx {
+            continue;
+        }
+
+        let texcoords = surfel.texcoords();
+        let (u, v) = (clamp_uv(texcoords.x), clamp_uv(texcoords.y));
+        let mask_value = luminosity(sample(mask, u, v));
+
+        let value = property_mut(surfel.data_mut(), property);
+        *value = combine(*value, mask_value);
+    }
+}
+
+/// Writes the average luminosity of the mask over the texels influenced by each surfel
+/// into the given property of the surfels of the entity with the given index.
+///
+/// The influenced texels are obtained from a lookup table built for the entity,
+/// which makes this work even if surfels do not carry texture coordinates. The mask
+/// is sampled at the texel centers, so it need not have the dimensions of the table.
+///
+/// `combine` receives the current value and the averaged mask luminosity in the range
+/// 0..1, see `apply_mask_at_texcoords`. Surfels that do not influence any texel and
+/// surfels of other entities are left unchanged.
+pub fn apply_mask_with_table<I, F>(
+    surf: &mut Surface,
+    entity_idx: usize,
+    mask: &I,
+    table: &SurfelLookupTable,
+    property: SurfelProperty,
+    combine: F,
+) where
+    I: GenericImage,
+    <<I as GenericImage>::Pixel as Pixel>::Subpixel: Into<f32> + 'static,
+    F: Fn(f32, f32) -> f32,
+{
+    let values = surf.samples.iter_mut().map(|surfel| {
+        let data = surfel.data_mut();
+        (data.entity_idx, property_mut(data, property))
+    });
+    combine_with_footprint_averages(values, entity_idx, mask, table, combine);
+}
+
+/// Combines the values of the surfels of the entity with the given index with the
+/// average luminosity of the mask over the texels each surfel influences, given the
+/// entity index and value of each surfel in the order of the table.
+fn combine_with_footprint_averages<'a, V, I, F>(
+    values: V,
+    entity_idx: usize,
+    mask: &I,
+    table: &SurfelLookupTable,
+    combine: F,
+) where
+    V: IntoIterator<Item = (usize, &'a mut f32)>,
+    I: GenericImage,
+    <<I as GenericImage>::Pixel as Pixel>::Subpixel: Into<f32> + 'static,
+    F: Fn(f32, f32) -> f32,
+{
+    let values = values.into_iter().collect::<Vec<_>>();
+    let reverse = ReverseIndex::new(table, values.len());
+    let (width, height) = (table.width() as u32, table.height() as u32);
+
+    for (surfel_idx, (surfel_entity_idx, value)) in values.into_iter().enumerate() {
+        if surfel_entity_idx != entity_idx {
+            continue;
+        }
+
+        let (weighted_sum, weight_sum) = reverse
+            .footprint(surfel_idx)
+            .map(|(x, y, weight)| {
+                let (u, v) = offset_to_uv(x, y, width, height);
+                (weight * luminosity(sample(mask, u, v)), weight)
+            })
+            .fold((0.0, 0.0), |(sum, weights), (value, weight)| {
+                (sum + value, weights + weight)
+            });
+
+        if weight_sum > 0.0 {
+            *value = combine(*value, weighted_sum / weight_sum);
+        }
+    }
+}
+
+fn property_mut(data: &mut SurfelData, property: SurfelProperty) -> &mut f32 {
+    match property {
+        SurfelProperty::Substance(idx) => &mut data.substances[idx],
+        SurfelProperty::DepositionRate(idx) => &mut data.deposition_rates[idx],
+    }
+}
+
+/// Applies mirrored repeat and keeps texture coordinates on the border inside the image,
+/// since 1.0 would address the texel after the last one.
+fn clamp_uv(coord: f32) -> f32 {
+    repeat_mirror(coord).min(1.0 - EPSILON)
+}
+
+/// Luminosity of the given pixel in the range 0..1.
+fn luminosity<P>(pixel: P) -> f32
+where
+    P: Pixel,
+    P::Subpixel: Into<f32> + 'static,
+{
+    let Luma { data: [luma] } = pixel.to_luma();
+    normalize(luma)
+}
+
+fn normalize<T: Primitive + Into<f32>>(value: T) -> f32 {
+    value.into() / T::max_value().into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::ImageBuffer;
+    use lookup_table::TableSettings;
+    use surfel_table::Gather;
+
+    #[test]
+    fn luminosity_range() {
+        assert_eq!(luminosity(Luma { data: [255_u8] }), 1.0);
+        assert_eq!(luminosity(Luma { data: [0_u16] }), 0.0);
+        assert_ulps_eq!(luminosity(Luma { data: [32768_u16] }), 32768.0 / 65535.0);
+    }
+
+    #[test]
+    fn clamped_border() {
+        assert!(clamp_uv(1.0) < 1.0);
+        assert!(clamp_uv(3.0) < 1.0);
+        assert_eq!(clamp_uv(0.25), 0.25);
+    }
+
+    #[test]
+    fn averages_over_footprints() {
+        // Surfel 0 and 1 share the top left texel, surfel 2 influences no texel and
+        // surfel 3 belongs to another entity
+        let texels = vec![
+            vec![(1.0, 0), (1.0, 1)],
+            vec![(1.0, 1)],
+            vec![(1.0, 3)],
+            vec![(1.0, 0)],
+        ];
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        let table = SurfelLookupTable::from_texels(settings, 0, texels);
+        let mask: ImageBuffer<Luma<u8>, Vec<u8>> =
+            ImageBuffer::from_raw(2, 2, vec![255, 0, 51, 102]).unwrap();
+        let mut surfels = vec![(0, 0.5), (0, 0.5), (0, 0.5), (1, 0.5)];
+
+        let values = surfels
+            .iter_mut()
+            .map(|&mut (entity_idx, ref mut value)| (entity_idx, value));
+        combine_with_footprint_averages(values, 0, &mask, &table, |value, mask| value * mask);
+
+        // Surfels have half the weight in the shared texel
+        assert_abs_diff_eq!(surfels[0].1, 0.5 * (0.5 * 1.0 + 0.4) / 1.5, epsilon = 1e-6);
+        assert_abs_diff_eq!(surfels[1].1, 0.5 * (0.5 * 1.0 + 0.0) / 1.5, epsilon = 1e-6);
+        assert_eq!(surfels[2].1, 0.5);
+        assert_eq!(surfels[3].1, 0.5);
+    }
+}
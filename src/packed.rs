@@ -0,0 +1,300 @@
+//!
+//! Provides functionality for packing multiple substance densities into the
+//! channels of a single texture.
+//!
+
+use density::Surface;
+use image::{ImageBuffer, Rgba};
+use lookup_table::{SurfelLookupTable, TexelSurfels};
+use reconstruction::Reconstruction;
+use scene::Entity;
+use table_options::TableOptions;
+
+/// Specifies how to obtain the value of a single channel of a packed texture.
+pub enum Channel {
+    /// Uses the same value for every defined texel, e.g. 255 for an opaque alpha channel.
+    Constant(u8),
+    /// Maps the filtered amount of the substance with the given index into the channel.
+    Substance(usize),
+    /// Calculates a density from the filtered amounts of all substances, e.g. the sum
+    /// of two substances or the difference between them.
+    Expression(Box<Fn(&[f32]) -> f32 + Send + Sync>),
+}
+
+/// Collects up to four substance densities into the R/G/B/A channels of a single texture,
+/// sharing one surfel lookup table and one pass over the texels.
+pub struct PackedDensity {
+    channels: Vec<Channel>,
+    min_density: f32,
+    max_density: f32,
+    /// Color to use for locations in the texture unused by the mesh
+    undefined_color: Rgba<u8>,
+    filtering: Box<Reconstruction>,
+    /// Texture dimensions and settings for building surfel lookup tables
+    table_options: TableOptions,
+}
+
+impl PackedDensity {
+    /// Creates a packed density collector writing the given channels into R, G, B and A,
+    /// in that order.
+    ///
+    /// Missing color channels are set to zero, a missing alpha channel is set to 255.
+    ///
+    /// # Panics
+    /// Panics if more than four channels are specified.
+    pub fn new(
+        channels: impl IntoIterator<Item = Channel>,
+        tex_width: usize,
+        tex_height: usize,
+        island_bleed: usize,
+        min_density: f32,
+        max_density: f32,
+        undefined_color: Rgba<u8>,
+        filtering: impl Reconstruction + 'static,
+    ) -> Self {
+        let mut channels = channels.into_iter().collect::<Vec<_>>();
+
+        if channels.len() > 4 {
+            panic!(
+                "Tried to pack {} channels into a RGBA texture, at most four are supported",
+                channels.len()
+            );
+        }
+
+        while channels.len() < 3 {
+            channels.push(Channel::Constant(0));
+        }
+
+        if channels.len() < 4 {
+            channels.push(Channel::Constant(255));
+        }
+
+        PackedDensity {
+            channels,
+            min_density,
+            max_density,
+            undefined_color,
+            filtering: Box::new(filtering),
+            table_options: TableOptions::new(tex_width, tex_height, island_bleed),
+        }
+    }
+
+    /// Uses the given options for building surfel lookup tables, see
+    /// `Density::with_table_options`.
+    pub fn with_table_options(self, table_options: TableOptions) -> Self {
+        Self {
+            table_options,
+            ..self
+        }
+    }
+
+    pub fn table_options(&self) -> &TableOptions {
+        &self.table_options
+    }
+
+    pub fn collect(&self, entity: &Entity, surf: &Surface) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        self.collect_with_table(surf, &self.table_options.build_table(entity, surf))
+    }
+
+    pub fn collect_with_table(
+        &self,
+        surf: &Surface,
+        table: &SurfelLookupTable,
+    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let substance_count = surf
+            .samples
+            .first()
+            .map(|surfel| surfel.data().substances.len())
+            .unwrap_or(0);
+
+        self.collect_from(table, substance_count, &|surfel_idx, substance_idx| {
+            surf.samples[surfel_idx].data().substances[substance_idx]
+        })
+    }
+
+    /// Like `collect_with_table`, but obtains the amount of a substance from `value_of`,
+    /// called with a surfel index and a substance index, instead of a surface.
+    ///
+    /// Expressions receive the amounts of substances `0..substance_count`.
+    pub fn collect_from(
+        &self,
+        table: &SurfelLookupTable,
+        substance_count: usize,
+        value_of: &(Fn(usize, usize) -> f32 + Sync),
+    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let needs_all_substances = self.channels.iter().any(|c| match *c {
+            Channel::Expression(_) => true,
+            _ => false,
+        });
+        let substance_count = if needs_all_substances {
+            substance_count
+        } else {
+            0
+        };
+
+        self.table_options.image_from_fn(|x, y| {
+            let x = x as usize;
+            let y = y as usize;
+            let surfels = table.texel_at(x, y);
+
+            self.texel(surfels, substance_count, value_of)
+                .unwrap_or(self.undefined_color)
+        })
+    }
+
+    /// Calculates the packed color of a texel from the given close surfels, or `None`
+    /// if the texel is undefined.
+    ///
+    /// Only the first `substance_count` substances are filtered for expressions.
+    fn texel(
+        &self,
+        surfels: TexelSurfels,
+        substance_count: usize,
+        value_of: &Fn(usize, usize) -> f32,
+    ) -> Option<Rgba<u8>> {
+        if surfels.is_empty() {
+            return None;
+        }
+
+        let all_substances = (0..substance_count)
+            .map(|substance_idx| self.filter_substance(surfels, substance_idx, value_of))
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut data = [0_u8; 4];
+        for (channel, value) in self.channels.iter().zip(data.iter_mut()) {
+            *value = match *channel {
+                Channel::Constant(constant) => constant,
+                Channel::Substance(substance_idx) => {
+                    self.quantize(self.filter_substance(surfels, substance_idx, value_of)?)
+                }
+                Channel::Expression(ref expression) => self.quantize(expression(&all_substances)),
+            };
+        }
+
+        Some(Rgba { data })
+    }
+
+    fn filter_substance(
+        &self,
+        surfels: TexelSurfels,
+        substance_idx: usize,
+        value_of: &Fn(usize, usize) -> f32,
+    ) -> Option<f32> {
+        self.filtering
+            .reconstruct(surfels, &|surfel_idx| value_of(surfel_idx, substance_idx))
+    }
+
+    /// Maps a density into the range 0..255 according to minimum and maximum density.
+    fn quantize(&self, density: f32) -> u8 {
+        let alpha = (density.max(self.min_density).min(self.max_density) - self.min_density)
+            / (self.max_density - self.min_density);
+
+        (alpha * 255.0).round() as u8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use density::Density;
+    use lookup_table::TableSettings;
+    use reconstruction::SubstanceFilter;
+    use surfel_table::Gather;
+
+    const UNDEFINED: Rgba<u8> = Rgba {
+        data: [255, 0, 255, 255],
+    };
+
+    /// Amounts of two substances for three surfels.
+    const SUBSTANCES: [[f32; 2]; 3] = [[0.0, 1.0], [0.5, 0.25], [1.0, 0.75]];
+
+    fn table() -> SurfelLookupTable {
+        let texels = vec![
+            vec![(1.0, 0), (4.0, 1)],
+            vec![(1.0, 2)],
+            vec![],
+            vec![(9.0, 1), (1.0, 2)],
+        ];
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        SurfelLookupTable::from_texels(settings, 0, texels)
+    }
+
+    fn density(substance_idx: usize) -> Density {
+        let black = Rgba { data: [0, 0, 0, 255] };
+        let white = Rgba {
+            data: [255, 255, 255, 255],
+        };
+        Density::new(
+            substance_idx,
+            2,
+            2,
+            0,
+            0.0,
+            1.0,
+            UNDEFINED,
+            black,
+            white,
+            SubstanceFilter::Flat,
+        )
+    }
+
+    #[test]
+    fn channels_match_single_substance_densities() {
+        let table = table();
+        let packed = PackedDensity::new(
+            vec![Channel::Substance(0), Channel::Substance(1)],
+            2,
+            2,
+            0,
+            0.0,
+            1.0,
+            UNDEFINED,
+            SubstanceFilter::Flat,
+        );
+
+        let image = packed.collect_from(&table, 2, &|surfel_idx, substance_idx| {
+            SUBSTANCES[surfel_idx][substance_idx]
+        });
+
+        for channel in 0..2 {
+            let single = density(channel)
+                .collect_u16_from(&table, &|surfel_idx| SUBSTANCES[surfel_idx][channel], 0);
+
+            for (x, y, pixel) in image.enumerate_pixels() {
+                if table.texel_at(x as usize, y as usize).is_empty() {
+                    assert_eq!(*pixel, UNDEFINED);
+                } else {
+                    let expected = f32::from(single.get_pixel(x, y).data[0]) / 257.0;
+                    assert_eq!(pixel.data[channel], expected.round() as u8);
+                    assert_eq!(pixel.data[2], 0);
+                    assert_eq!(pixel.data[3], 255);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn expression_of_all_substances() {
+        let packed = PackedDensity::new(
+            vec![Channel::Expression(Box::new(|substances| {
+                substances[0] + substances[1]
+            }))],
+            2,
+            2,
+            0,
+            0.0,
+            2.0,
+            UNDEFINED,
+            SubstanceFilter::Flat,
+        );
+
+        let image = packed.collect_from(&table(), 2, &|surfel_idx, substance_idx| {
+            SUBSTANCES[surfel_idx][substance_idx]
+        });
+
+        // Texel 0 has substances 0.25 and 0.625, summing up to 0.875 of 2.0
+        assert_eq!(image.get_pixel(0, 0).data[0], (0.875_f32 / 2.0 * 255.0).round() as u8);
+        // Texel 1 has substances 1.0 and 0.75
+        assert_eq!(image.get_pixel(1, 0).data[0], (1.75_f32 / 2.0 * 255.0).round() as u8);
+    }
+}
@@ -0,0 +1,75 @@
+//!
+//! Provides helpers for evaluating textures in parallel.
+//!
+
+use image::{ImageBuffer, Pixel};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::sync::Arc;
+
+/// Like `ImageBuffer::from_fn`, but evaluates lines in parallel.
+///
+/// Each pixel is calculated independently, so the result is the same for any number
+/// of threads.
+pub fn par_from_fn<P, F>(
+    width: u32,
+    height: u32,
+    pixel_at: F,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+    P::Subpixel: Send + 'static,
+    F: Fn(u32, u32) -> P + Sync,
+{
+    let mut image = ImageBuffer::new(width, height);
+    let channel_count = P::channel_count() as usize;
+    let line_len = (width as usize * channel_count).max(1);
+
+    {
+        let raw: &mut [P::Subpixel] = &mut image;
+        raw.par_chunks_mut(line_len)
+            .enumerate()
+            .for_each(|(y, line)| {
+                for (x, pixel) in line.chunks_mut(channel_count).enumerate() {
+                    pixel.copy_from_slice(pixel_at(x as u32, y as u32).channels());
+                }
+            });
+    }
+
+    image
+}
+
+/// Runs the given operation in the given thread pool, or in the global rayon thread
+/// pool if `None`.
+pub fn install<R, F>(pool: &Option<Arc<ThreadPool>>, op: F) -> R
+where
+    R: Send,
+    F: FnOnce() -> R + Send,
+{
+    match *pool {
+        Some(ref pool) => pool.install(op),
+        None => op(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgb;
+    use rayon::ThreadPoolBuilder;
+
+    fn pixel_at(x: u32, y: u32) -> Rgb<u8> {
+        Rgb {
+            data: [x as u8, y as u8, (x * y) as u8],
+        }
+    }
+
+    #[test]
+    fn same_as_sequential() {
+        let expected = ImageBuffer::from_fn(37, 19, pixel_at);
+        assert_eq!(par_from_fn(37, 19, pixel_at), expected);
+
+        let pool = Some(Arc::new(ThreadPoolBuilder::new().num_threads(3).build().unwrap()));
+        assert_eq!(install(&pool, || par_from_fn(37, 19, pixel_at)), expected);
+    }
+}
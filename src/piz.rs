@@ -0,0 +1,759 @@
+//!
+//! Provides PIZ compression of EXR pixel data like the OpenEXR reference implementation.
+//!
+//! The 16 bit words of the data are mapped to a dense range of values, transformed with
+//! a two-dimensional Haar wavelet per channel and finally Huffman coded.
+//!
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+const USHORT_RANGE: usize = 1 << 16;
+/// Size of the bitmap of used 16 bit values, in bytes
+const BITMAP_SIZE: usize = USHORT_RANGE >> 3;
+
+/// Offsets and mask for the wavelet transform of words with more than 14 significant bits
+const A_OFFSET: i32 = 1 << 15;
+const M_OFFSET: i32 = 1 << 15;
+const MOD_MASK: i32 = (1 << 16) - 1;
+
+/// Number of Huffman symbols, all 16 bit values plus one symbol for runs
+const HUF_ENCSIZE: usize = USHORT_RANGE + 1;
+/// Maximum length of a Huffman code in bits
+const HUF_MAX_CODE_LEN: usize = 58;
+/// Code lengths in the packed encoding table that stand for runs of unused symbols
+const SHORT_ZEROCODE_RUN: u64 = 59;
+const LONG_ZEROCODE_RUN: u64 = 63;
+const SHORTEST_LONG_RUN: usize = 2 + (LONG_ZEROCODE_RUN - SHORT_ZEROCODE_RUN) as usize;
+const LONGEST_LONG_RUN: usize = 255 + SHORTEST_LONG_RUN;
+
+/// Compresses the pixel data of a chunk of the given number of lines, each holding the
+/// values of the given number of 32 bit channels one channel after another.
+///
+/// The result is not necessarily smaller than the input.
+pub fn piz_compress(raw: &[u8], width: usize, lines: usize, channel_count: usize) -> Vec<u8> {
+    let line_bytes = width * 4;
+    let channel_words = width * lines * 2;
+
+    // Split into little endian 16 bit words, storing all lines of a channel consecutively
+    let mut words = Vec::with_capacity(raw.len() / 2);
+    for channel in 0..channel_count {
+        for line in 0..lines {
+            let start = (line * channel_count + channel) * line_bytes;
+            words.extend(
+                raw[start..(start + line_bytes)]
+                    .chunks(2)
+                    .map(|bytes| bytes[0] as u16 | (bytes[1] as u16) << 8),
+            );
+        }
+    }
+
+    let bitmap = bitmap_from_data(&words);
+    let (lut, max_value) = forward_lut_from_bitmap(&bitmap);
+    for word in &mut words {
+        *word = lut[*word as usize];
+    }
+
+    let mut compressed = Vec::new();
+
+    // Only the range of the bitmap with used values is stored
+    let used = bitmap.iter().position(|&byte| byte != 0).map(|min_non_zero| {
+        let max_non_zero = bitmap.iter().rposition(|&byte| byte != 0).unwrap();
+        (min_non_zero, max_non_zero)
+    });
+    match used {
+        Some((min_non_zero, max_non_zero)) => {
+            compressed.extend_from_slice(&(min_non_zero as u16).to_le_bytes());
+            compressed.extend_from_slice(&(max_non_zero as u16).to_le_bytes());
+            compressed.extend_from_slice(&bitmap[min_non_zero..=max_non_zero]);
+        }
+        None => {
+            compressed.extend_from_slice(&(BITMAP_SIZE as u16 - 1).to_le_bytes());
+            compressed.extend_from_slice(&0_u16.to_le_bytes());
+        }
+    }
+
+    // Both words of each value are transformed separately
+    for channel in words.chunks_mut(channel_words) {
+        for first_word in 0..2 {
+            wav2_encode(&mut channel[first_word..], width, 2, lines, width * 2, max_value);
+        }
+    }
+
+    let huffman = huf_compress(&words);
+    compressed.extend_from_slice(&(huffman.len() as i32).to_le_bytes());
+    compressed.extend_from_slice(&huffman);
+    compressed
+}
+
+/// Marks the values occurring in the data, except for zero, which is assumed to occur.
+fn bitmap_from_data(words: &[u16]) -> Vec<u8> {
+    let mut bitmap = vec![0; BITMAP_SIZE];
+    for &word in words {
+        bitmap[(word >> 3) as usize] |= 1 << (word & 7);
+    }
+    bitmap[0] &= !1;
+    bitmap
+}
+
+/// Maps each value in the bitmap and zero to its index among these values, returning the
+/// mapping and the largest index.
+fn forward_lut_from_bitmap(bitmap: &[u8]) -> (Vec<u16>, u16) {
+    let mut lut = vec![0; USHORT_RANGE];
+    let mut next = 0;
+    for (value, mapped) in lut.iter_mut().enumerate() {
+        if value == 0 || bitmap[value >> 3] & (1 << (value & 7)) != 0 {
+            *mapped = next as u16;
+            next += 1;
+        }
+    }
+    (lut, (next - 1) as u16)
+}
+
+/// Applies the wavelet transform in place to `nx * ny` words with offsets `ox` and `oy`
+/// between horizontal and vertical neighbours, with values not exceeding `max_value`.
+///
+/// Values with 14 significant bits or less are transformed without modular arithmetic,
+/// which compresses better.
+fn wav2_encode(data: &mut [u16], nx: usize, ox: usize, ny: usize, oy: usize, max_value: u16) {
+    let w14 = max_value < (1 << 14);
+    let encode = |a, b| if w14 { wenc14(a, b) } else { wenc16(a, b) };
+
+    let n = nx.min(ny);
+    // Distance of the words combined on the current level and on the next level
+    let mut p = 1;
+    let mut p2 = 2;
+
+    while p2 <= n {
+        let (ox1, ox2, oy1, oy2) = (ox * p, ox * p2, oy * p, oy * p2);
+        let end_y = oy * (ny - p2);
+
+        let mut py = 0;
+        while py <= end_y {
+            let end_x = py + ox * (nx - p2);
+
+            let mut px = py;
+            while px <= end_x {
+                let p01 = px + ox1;
+                let p10 = px + oy1;
+                let p11 = p10 + ox1;
+
+                let (i00, i01) = encode(data[px], data[p01]);
+                let (i10, i11) = encode(data[p10], data[p11]);
+                let (l, h) = encode(i00, i10);
+                data[px] = l;
+                data[p10] = h;
+                let (l, h) = encode(i01, i11);
+                data[p01] = l;
+                data[p11] = h;
+
+                px += ox2;
+            }
+
+            // Odd column left over in this level
+            if nx & p != 0 {
+                let p10 = px + oy1;
+                let (l, h) = encode(data[px], data[p10]);
+                data[px] = l;
+                data[p10] = h;
+            }
+
+            py += oy2;
+        }
+
+        // Odd line left over in this level
+        if ny & p != 0 {
+            let end_x = py + ox * (nx - p2);
+
+            let mut px = py;
+            while px <= end_x {
+                let p01 = px + ox1;
+                let (l, h) = encode(data[px], data[p01]);
+                data[px] = l;
+                data[p01] = h;
+
+                px += ox2;
+            }
+        }
+
+        p = p2;
+        p2 <<= 1;
+    }
+}
+
+/// Mean and difference of two words interpreted as signed 16 bit integers.
+fn wenc14(a: u16, b: u16) -> (u16, u16) {
+    let (a, b) = (a as i16 as i32, b as i16 as i32);
+    (((a + b) >> 1) as u16, (a - b) as u16)
+}
+
+/// Mean and difference of two words, modulo 2^16.
+fn wenc16(a: u16, b: u16) -> (u16, u16) {
+    let (a, b) = (a as i32, b as i32);
+    let offset_a = (a + A_OFFSET) & MOD_MASK;
+    let mut mean = (offset_a + b) >> 1;
+    let difference = offset_a - b;
+
+    if difference < 0 {
+        mean = (mean + M_OFFSET) & MOD_MASK;
+    }
+
+    (mean as u16, (difference & MOD_MASK) as u16)
+}
+
+/// Huffman codes the given words, preceded by the encoding table.
+fn huf_compress(words: &[u16]) -> Vec<u8> {
+    if words.is_empty() {
+        return Vec::new();
+    }
+
+    let mut frequencies = vec![0; HUF_ENCSIZE];
+    for &word in words {
+        frequencies[word as usize] += 1;
+    }
+
+    let min_symbol = frequencies.iter().position(|&f| f > 0).unwrap();
+    // Runs of equal words are coded with an additional symbol after the last used one
+    let run_symbol = frequencies.iter().rposition(|&f| f > 0).unwrap() + 1;
+    frequencies[run_symbol] = 1;
+
+    let codes = canonical_codes(&code_lengths(&frequencies));
+    let table = pack_encoding_table(&codes, min_symbol, run_symbol);
+    let (data, bit_count) = huf_encode(&codes, words, run_symbol);
+
+    let mut compressed = Vec::with_capacity(20 + table.len() + data.len());
+    for &field in &[min_symbol, run_symbol, table.len(), bit_count, 0] {
+        compressed.extend_from_slice(&(field as u32).to_le_bytes());
+    }
+    compressed.extend_from_slice(&table);
+    compressed.extend_from_slice(&data);
+    compressed
+}
+
+/// Determines the length of the Huffman code of each symbol from the symbol frequencies,
+/// with zero for unused symbols.
+fn code_lengths(frequencies: &[u64]) -> Vec<u64> {
+    let mut frequencies = frequencies.to_vec();
+    let mut lengths = vec![0; frequencies.len()];
+    // Each symbol links to the next symbol in the same subtree, the last one to itself
+    let mut links = (0..frequencies.len()).collect::<Vec<_>>();
+
+    let mut heap = frequencies
+        .iter()
+        .enumerate()
+        .filter(|&(_, &frequency)| frequency > 0)
+        .map(|(symbol, &frequency)| Reverse((frequency, symbol)))
+        .collect::<BinaryHeap<_>>();
+
+    while heap.len() > 1 {
+        let Reverse((_, least)) = heap.pop().unwrap();
+        let Reverse((_, merged)) = heap.pop().unwrap();
+        frequencies[merged] += frequencies[least];
+        heap.push(Reverse((frequencies[merged], merged)));
+
+        // All symbols in both subtrees get one bit longer, append the lists of symbols
+        let mut symbol = merged;
+        loop {
+            lengths[symbol] += 1;
+            if links[symbol] == symbol {
+                links[symbol] = least;
+                break;
+            }
+            symbol = links[symbol];
+        }
+
+        let mut symbol = least;
+        loop {
+            lengths[symbol] += 1;
+            if links[symbol] == symbol {
+                break;
+            }
+            symbol = links[symbol];
+        }
+    }
+
+    lengths
+}
+
+/// Assigns canonical codes to the given code lengths, returning the code of each symbol
+/// shifted left by six bits and combined with its length.
+fn canonical_codes(lengths: &[u64]) -> Vec<u64> {
+    // Number of codes with each length, then the first code with each length
+    let mut next_code = [0; HUF_MAX_CODE_LEN + 1];
+    for &len in lengths {
+        next_code[len as usize] += 1;
+    }
+
+    let mut code = 0;
+    for len in (1..=HUF_MAX_CODE_LEN).rev() {
+        let next = (code + next_code[len]) >> 1;
+        next_code[len] = code;
+        code = next;
+    }
+
+    lengths
+        .iter()
+        .map(|&len| {
+            if len == 0 {
+                return 0;
+            }
+            let code = next_code[len as usize];
+            next_code[len as usize] += 1;
+            len | code << 6
+        })
+        .collect()
+}
+
+fn code_len(code: u64) -> u32 {
+    (code & 63) as u32
+}
+
+/// Stores the code lengths of the symbols in the given range with six bits each,
+/// shortening runs of unused symbols.
+fn pack_encoding_table(codes: &[u64], min_symbol: usize, max_symbol: usize) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    let mut symbol = min_symbol;
+    while symbol <= max_symbol {
+        let len = code_len(codes[symbol]);
+
+        if len == 0 {
+            let mut run = 1;
+            while symbol < max_symbol
+                && run < LONGEST_LONG_RUN
+                && code_len(codes[symbol + 1]) == 0
+            {
+                symbol += 1;
+                run += 1;
+            }
+
+            if run >= SHORTEST_LONG_RUN {
+                writer.write(6, LONG_ZEROCODE_RUN);
+                writer.write(8, (run - SHORTEST_LONG_RUN) as u64);
+                symbol += 1;
+                continue;
+            } else if run >= 2 {
+                writer.write(6, SHORT_ZEROCODE_RUN + run as u64 - 2);
+                symbol += 1;
+                continue;
+            }
+        }
+
+        writer.write(6, len as u64);
+        symbol += 1;
+    }
+
+    writer.finish().0
+}
+
+/// Codes the given words, replacing runs of equal words with the code of the word, the
+/// code of the run symbol and the run length where this is shorter.
+///
+/// Returns the coded bytes and the number of bits used in them.
+fn huf_encode(codes: &[u64], words: &[u16], run_symbol: usize) -> (Vec<u8>, usize) {
+    let mut writer = BitWriter::new();
+    let run_code = codes[run_symbol];
+
+    let mut word = words[0];
+    let mut run = 0;
+    for &next in &words[1..] {
+        if next == word && run < 255 {
+            run += 1;
+        } else {
+            send_code(&mut writer, codes[word as usize], run, run_code);
+            run = 0;
+        }
+        word = next;
+    }
+    send_code(&mut writer, codes[word as usize], run, run_code);
+
+    writer.finish()
+}
+
+/// Writes the given code once and then repeats it for the given number of times.
+fn send_code(writer: &mut BitWriter, code: u64, run: u32, run_code: u64) {
+    if code_len(code) + code_len(run_code) + 8 < code_len(code) * run {
+        writer.write_code(code);
+        writer.write_code(run_code);
+        writer.write(8, run as u64);
+    } else {
+        for _ in 0..=run {
+            writer.write_code(code);
+        }
+    }
+}
+
+/// Writes bits most significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    buffered: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            buffered: 0,
+        }
+    }
+
+    /// Writes the lowest `count` bits of `bits`, which must not have higher bits set.
+    fn write(&mut self, count: u32, bits: u64) {
+        // Keep the buffer from overflowing for long codes
+        if count > 32 {
+            self.write(count - 32, bits >> 32);
+            self.write(32, bits & 0xffff_ffff);
+            return;
+        }
+
+        self.buffer = self.buffer << count | bits;
+        self.buffered += count;
+
+        while self.buffered >= 8 {
+            self.buffered -= 8;
+            self.bytes.push((self.buffer >> self.buffered) as u8);
+        }
+    }
+
+    fn write_code(&mut self, code: u64) {
+        self.write(code_len(code), code >> 6);
+    }
+
+    /// Pads the last byte with zeros and returns the bytes along with the number of
+    /// bits written.
+    fn finish(mut self) -> (Vec<u8>, usize) {
+        let bit_count = self.bytes.len() * 8 + self.buffered as usize;
+        if self.buffered > 0 {
+            let last = (self.buffer << (8 - self.buffered)) as u8;
+            self.bytes.push(last);
+        }
+        (self.bytes, bit_count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        data[offset] as u16 | (data[offset + 1] as u16) << 8
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&data[offset..(offset + 4)]);
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Deterministic pseudo random numbers from a xorshift generator.
+    fn noise(count: usize) -> Vec<u32> {
+        let mut state = 0x2545_f491_u32;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn read(&mut self, count: usize) -> u64 {
+            let mut bits = 0;
+            for _ in 0..count {
+                let byte = self.bytes[self.position / 8];
+                bits = bits << 1 | ((byte >> (7 - self.position % 8)) & 1) as u64;
+                self.position += 1;
+            }
+            bits
+        }
+    }
+
+    fn wdec14(l: u16, h: u16) -> (u16, u16) {
+        let (l, h) = (l as i16 as i32, h as i16 as i32);
+        let a = l + (h & 1) + (h >> 1);
+        (a as u16, (a - h) as u16)
+    }
+
+    fn wdec16(l: u16, h: u16) -> (u16, u16) {
+        let (m, d) = (l as i32, h as i32);
+        let b = (m - (d >> 1)) & MOD_MASK;
+        let a = (d + b - A_OFFSET) & MOD_MASK;
+        (a as u16, b as u16)
+    }
+
+    /// Inverse of `wav2_encode`, like in the reference decoder.
+    fn wav2_decode(data: &mut [u16], nx: usize, ox: usize, ny: usize, oy: usize, max_value: u16) {
+        let w14 = max_value < (1 << 14);
+        let decode = |l, h| if w14 { wdec14(l, h) } else { wdec16(l, h) };
+
+        let n = nx.min(ny);
+        let mut p = 1;
+        while p <= n {
+            p <<= 1;
+        }
+        p >>= 1;
+        let mut p2 = p;
+        p >>= 1;
+
+        while p >= 1 {
+            let (ox1, ox2, oy1, oy2) = (ox * p, ox * p2, oy * p, oy * p2);
+            let end_y = oy * (ny - p2);
+
+            let mut py = 0;
+            while py <= end_y {
+                let end_x = py + ox * (nx - p2);
+
+                let mut px = py;
+                while px <= end_x {
+                    let p01 = px + ox1;
+                    let p10 = px + oy1;
+                    let p11 = p10 + ox1;
+
+                    let (i00, i10) = decode(data[px], data[p10]);
+                    let (i01, i11) = decode(data[p01], data[p11]);
+                    let (a, b) = decode(i00, i01);
+                    data[px] = a;
+                    data[p01] = b;
+                    let (a, b) = decode(i10, i11);
+                    data[p10] = a;
+                    data[p11] = b;
+
+                    px += ox2;
+                }
+
+                if nx & p != 0 {
+                    let p10 = px + oy1;
+                    let (a, b) = decode(data[px], data[p10]);
+                    data[px] = a;
+                    data[p10] = b;
+                }
+
+                py += oy2;
+            }
+
+            if ny & p != 0 {
+                let end_x = py + ox * (nx - p2);
+
+                let mut px = py;
+                while px <= end_x {
+                    let p01 = px + ox1;
+                    let (a, b) = decode(data[px], data[p01]);
+                    data[px] = a;
+                    data[p01] = b;
+
+                    px += ox2;
+                }
+            }
+
+            p2 = p;
+            p >>= 1;
+        }
+    }
+
+    /// Decodes Huffman coded words like the reference decoder, one bit at a time.
+    fn huf_uncompress(compressed: &[u8]) -> Vec<u16> {
+        let min_symbol = read_u32(compressed, 0) as usize;
+        let run_symbol = read_u32(compressed, 4) as usize;
+        let table_len = read_u32(compressed, 8) as usize;
+        let bit_count = read_u32(compressed, 12) as usize;
+        let data = &compressed[(20 + table_len)..];
+        assert_eq!(data.len(), (bit_count + 7) / 8);
+
+        let mut lengths = vec![0; HUF_ENCSIZE];
+        let mut table = BitReader {
+            bytes: &compressed[20..(20 + table_len)],
+            position: 0,
+        };
+        let mut symbol = min_symbol;
+        while symbol <= run_symbol {
+            let len = table.read(6);
+            if len == LONG_ZEROCODE_RUN {
+                symbol += table.read(8) as usize + SHORTEST_LONG_RUN;
+            } else if len >= SHORT_ZEROCODE_RUN {
+                symbol += (len - SHORT_ZEROCODE_RUN) as usize + 2;
+            } else {
+                lengths[symbol] = len;
+                symbol += 1;
+            }
+        }
+        assert_eq!(symbol, run_symbol + 1);
+
+        let symbols = canonical_codes(&lengths)
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, code)| code != 0)
+            .map(|(symbol, code)| (code, symbol))
+            .collect::<HashMap<_, _>>();
+
+        let mut reader = BitReader {
+            bytes: data,
+            position: 0,
+        };
+        let mut words = Vec::new();
+        let (mut code, mut len) = (0, 0);
+        while reader.position < bit_count {
+            code = code << 1 | reader.read(1);
+            len += 1;
+
+            if let Some(&symbol) = symbols.get(&(len | code << 6)) {
+                if symbol == run_symbol {
+                    let word = *words.last().unwrap();
+                    let run = reader.read(8);
+                    words.extend((0..run).map(|_| word));
+                } else {
+                    words.push(symbol as u16);
+                }
+                code = 0;
+                len = 0;
+            }
+        }
+        assert_eq!(len, 0);
+
+        words
+    }
+
+    /// Restores the pixel data of a chunk like the reference decoder.
+    fn piz_uncompress(
+        compressed: &[u8],
+        width: usize,
+        lines: usize,
+        channel_count: usize,
+    ) -> Vec<u8> {
+        let min_non_zero = read_u16(compressed, 0) as usize;
+        let max_non_zero = read_u16(compressed, 2) as usize;
+        let mut offset = 4;
+
+        let mut bitmap = vec![0; BITMAP_SIZE];
+        if min_non_zero <= max_non_zero {
+            let end = offset + max_non_zero - min_non_zero + 1;
+            bitmap[min_non_zero..=max_non_zero].copy_from_slice(&compressed[offset..end]);
+            offset = end;
+        }
+        let reverse_lut = (0..USHORT_RANGE)
+            .filter(|&value| value == 0 || bitmap[value >> 3] & (1 << (value & 7)) != 0)
+            .map(|value| value as u16)
+            .collect::<Vec<_>>();
+        let max_value = (reverse_lut.len() - 1) as u16;
+
+        let huffman_len = read_u32(compressed, offset) as usize;
+        offset += 4;
+        assert_eq!(compressed.len(), offset + huffman_len);
+
+        let mut words = huf_uncompress(&compressed[offset..]);
+        let channel_words = width * lines * 2;
+        assert_eq!(words.len(), channel_words * channel_count);
+
+        for channel in words.chunks_mut(channel_words) {
+            for first_word in 0..2 {
+                wav2_decode(&mut channel[first_word..], width, 2, lines, width * 2, max_value);
+            }
+        }
+
+        let mut raw = vec![0; words.len() * 2];
+        for (idx, &word) in words.iter().enumerate() {
+            let channel = idx / channel_words;
+            let line = idx / (width * 2) % lines;
+            let start = (line * channel_count + channel) * width * 4 + idx % (width * 2) * 2;
+            raw[start..(start + 2)].copy_from_slice(&reverse_lut[word as usize].to_le_bytes());
+        }
+        raw
+    }
+
+    fn assert_reversible(raw: &[u8], width: usize, lines: usize, channel_count: usize) {
+        let compressed = piz_compress(raw, width, lines, channel_count);
+        let restored = piz_uncompress(&compressed, width, lines, channel_count);
+        assert!(restored == raw);
+    }
+
+    #[test]
+    fn wavelet_is_reversible() {
+        // Odd sizes exercise the left over columns and lines on each level
+        let (nx, ny) = (13, 6);
+        for &max_value in &[(1 << 14) - 1, u16::max_value()] {
+            let words = noise(nx * ny)
+                .into_iter()
+                .map(|n| (n % (max_value as u32 + 1)) as u16)
+                .collect::<Vec<_>>();
+            let mut transformed = words.clone();
+
+            wav2_encode(&mut transformed, nx, 1, ny, nx, max_value);
+            assert!(transformed != words);
+            wav2_decode(&mut transformed, nx, 1, ny, nx, max_value);
+
+            assert_eq!(transformed, words);
+        }
+    }
+
+    #[test]
+    fn huffman_codes_are_reversible() {
+        // Long runs, a single outlier at the end of the range and gaps of different lengths
+        let mut words = vec![0; 700];
+        words.extend(noise(300).into_iter().map(|n| (n % 40) as u16 * 9));
+        words.extend(vec![3; 20]);
+        words.push(u16::max_value());
+
+        let compressed = huf_compress(&words);
+
+        assert_eq!(read_u32(&compressed, 4) as usize, USHORT_RANGE);
+        assert!(compressed.len() < words.len() * 2);
+        assert_eq!(huf_uncompress(&compressed), words);
+    }
+
+    #[test]
+    fn canonical_codes_are_prefix_free() {
+        let codes = canonical_codes(&[0, 3, 2, 3, 1, 0]);
+
+        // Codes 000, 01, 001 and 1
+        assert_eq!(codes, vec![0, 3, 2 | 1 << 6, 3 | 1 << 6, 1 | 1 << 6, 0]);
+    }
+
+    #[test]
+    fn smooth_chunk_is_reversible_and_smaller() {
+        let (width, lines, channel_count) = (41, 7, 2);
+        // Gradient over the lines of both channels
+        let raw = (0..(width * lines * channel_count))
+            .map(|idx| (idx % width + idx / width) as f32 * 0.25)
+            .flat_map(|value| value.to_bits().to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
+
+        assert!(piz_compress(&raw, width, lines, channel_count).len() < raw.len());
+        assert_reversible(&raw, width, lines, channel_count);
+    }
+
+    #[test]
+    fn noisy_chunk_is_reversible() {
+        let (width, lines, channel_count) = (150, 32, 3);
+        let raw = noise(width * lines * channel_count)
+            .into_iter()
+            .flat_map(|n| n.to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
+
+        // Enough distinct values to require the 16 bit wavelet transform
+        let words = raw.chunks(2).map(|bytes| read_u16(bytes, 0)).collect::<Vec<_>>();
+        let (_, max_value) = forward_lut_from_bitmap(&bitmap_from_data(&words));
+        assert!(max_value >= 1 << 14);
+
+        assert_reversible(&raw, width, lines, channel_count);
+    }
+
+    #[test]
+    fn zero_chunk_is_reversible() {
+        let raw = vec![0; 4 * 3 * 2 * 4];
+        let compressed = piz_compress(&raw, 4, 3, 2);
+
+        // Empty bitmap range
+        assert_eq!(read_u16(&compressed, 0) as usize, BITMAP_SIZE - 1);
+        assert_eq!(read_u16(&compressed, 2), 0);
+        assert_reversible(&raw, 4, 3, 2);
+    }
+}
@@ -0,0 +1,226 @@
+//!
+//! Provides color ramps for visualizing scalar values like substance densities.
+//!
+
+use blend::blend;
+use image::{ImageBuffer, Rgba};
+
+/// Specifies how colors are calculated between two stops of a `ColorRamp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RampInterpolation {
+    /// Linearly blends between the colors of the stops before and after.
+    Linear,
+    /// Uses the color of the stop before without blending.
+    Constant,
+    /// Blends between the colors of the stops before and after, easing in and out
+    /// at the stops.
+    Smoothstep,
+}
+
+/// Maps values in the range 0..1 to colors, using an arbitrary number of stops.
+#[derive(Debug, Clone)]
+pub struct ColorRamp {
+    /// Positions and colors of the stops, sorted by position
+    stops: Vec<(f32, Rgba<u8>)>,
+    interpolation: RampInterpolation,
+}
+
+impl ColorRamp {
+    /// Creates a color ramp from a non-empty iterator of positions and colors.
+    /// The stops may be given in any order.
+    ///
+    /// Values before the first stop or after the last stop get the color of
+    /// the first or last stop, respectively.
+    ///
+    /// # Panics
+    /// Panics if the given iterator does not yield at least one element or if some
+    /// position is infinite or NaN.
+    pub fn new(
+        stops: impl IntoIterator<Item = (f32, Rgba<u8>)>,
+        interpolation: RampInterpolation,
+    ) -> Self {
+        let mut stops = stops.into_iter().collect::<Vec<_>>();
+
+        if stops.is_empty() {
+            panic!("Tried to create a color ramp without stops, which is undefined");
+        }
+
+        if stops.iter().any(|&(position, _)| !position.is_finite()) {
+            let positions = stops.iter().map(|&(p, _)| p).collect::<Vec<_>>();
+            panic!(
+                "Some positions were NaN/Infinity during color ramp construction: {:?}",
+                positions
+            );
+        }
+
+        stops.sort_by(|&(a, _), &(b, _)| {
+            a.partial_cmp(&b).unwrap() // NaN or infinite would have panicked before, comparison is safe
+        });
+
+        ColorRamp {
+            stops,
+            interpolation,
+        }
+    }
+
+    /// Creates a ramp linearly blending from `min_color` at 0 to `max_color` at 1.
+    pub fn two_color(min_color: Rgba<u8>, max_color: Rgba<u8>) -> Self {
+        Self::new(
+            vec![(0.0, min_color), (1.0, max_color)],
+            RampInterpolation::Linear,
+        )
+    }
+
+    /// Perceptually uniform ramp from dark blue over green to yellow.
+    pub fn viridis() -> Self {
+        Self::evenly_spaced(&[
+            0x440154, 0x472c7a, 0x3b518b, 0x2c718e, 0x21908d, 0x27ad81, 0x5cc863, 0xaadc32,
+            0xfde725,
+        ])
+    }
+
+    /// Perceptually uniform ramp from black over purple to light yellow.
+    pub fn magma() -> Self {
+        Self::evenly_spaced(&[
+            0x000004, 0x1c1044, 0x4f127b, 0x812581, 0xb5367a, 0xe55064, 0xfb8761, 0xfec287,
+            0xfcfdbf,
+        ])
+    }
+
+    /// Perceptually uniform ramp from black over red and orange to light yellow.
+    pub fn inferno() -> Self {
+        Self::evenly_spaced(&[
+            0x000004, 0x1f0c48, 0x550f6d, 0x88226a, 0xba3655, 0xe35933, 0xf98e09, 0xf9cb35,
+            0xfcffa4,
+        ])
+    }
+
+    /// Creates a linear ramp with opaque stops evenly spaced between 0 and 1 from
+    /// colors in `0xRRGGBB` notation.
+    fn evenly_spaced(colors: &[u32]) -> Self {
+        let last_idx = (colors.len() - 1) as f32;
+        let stops = colors.iter().enumerate().map(|(idx, &rgb)| {
+            let color = Rgba {
+                data: [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 255],
+            };
+            (idx as f32 / last_idx, color)
+        });
+
+        Self::new(stops, RampInterpolation::Linear)
+    }
+
+    /// Changes the interpolation between stops.
+    pub fn with_interpolation(self, interpolation: RampInterpolation) -> Self {
+        ColorRamp {
+            interpolation,
+            ..self
+        }
+    }
+
+    /// Gets the color of the ramp at the given position.
+    pub fn color_at(&self, position: f32) -> Rgba<u8> {
+        let after_idx = self
+            .stops
+            .iter()
+            .position(|&(stop_position, _)| stop_position > position);
+
+        let (before, after) = match after_idx {
+            // Before first stop
+            Some(0) => return self.stops[0].1,
+            Some(after_idx) => (self.stops[after_idx - 1], self.stops[after_idx]),
+            // After last stop or NaN
+            None => return self.stops[self.stops.len() - 1].1,
+        };
+
+        let (edge0, color0) = before;
+        let (edge1, color1) = after;
+        let alpha = (position - edge0) / (edge1 - edge0);
+
+        match self.interpolation {
+            RampInterpolation::Linear => blend(color0, color1, alpha),
+            RampInterpolation::Constant => color0,
+            RampInterpolation::Smoothstep => {
+                blend(color0, color1, alpha * alpha * (3.0 - 2.0 * alpha))
+            }
+        }
+    }
+
+    /// Renders a horizontal strip showing the colors of the ramp from 0 at the
+    /// left to 1 at the right, for use as a legend.
+    pub fn legend(&self, width: u32, height: u32) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let last_x = (width.max(2) - 1) as f32;
+        ImageBuffer::from_fn(width, height, |x, _| self.color_at(x as f32 / last_x))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn black() -> Rgba<u8> {
+        Rgba {
+            data: [0, 0, 0, 255],
+        }
+    }
+
+    fn white() -> Rgba<u8> {
+        Rgba {
+            data: [255, 255, 255, 255],
+        }
+    }
+
+    #[test]
+    fn stops_are_sorted() {
+        let ramp = ColorRamp::new(
+            vec![(1.0, white()), (0.0, black())],
+            RampInterpolation::Linear,
+        );
+
+        assert_eq!(ramp.color_at(0.0), black());
+        assert_eq!(ramp.color_at(0.5).data[0], 127);
+        assert_eq!(ramp.color_at(1.0), white());
+    }
+
+    #[test]
+    fn clamped_outside_stops() {
+        let ramp = ColorRamp::two_color(black(), white());
+
+        assert_eq!(ramp.color_at(-3.0), black());
+        assert_eq!(ramp.color_at(4.0), white());
+    }
+
+    #[test]
+    fn constant_interpolation() {
+        let ramp = ColorRamp::two_color(black(), white())
+            .with_interpolation(RampInterpolation::Constant);
+
+        assert_eq!(ramp.color_at(0.99), black());
+        assert_eq!(ramp.color_at(1.0), white());
+    }
+
+    #[test]
+    fn smoothstep_is_symmetric() {
+        let ramp = ColorRamp::two_color(black(), white())
+            .with_interpolation(RampInterpolation::Smoothstep);
+
+        assert_eq!(ramp.color_at(0.5).data[0], 127);
+        let linear = ColorRamp::two_color(black(), white());
+        assert!(ramp.color_at(0.1).data[0] < linear.color_at(0.1).data[0]);
+    }
+
+    #[test]
+    fn presets_span_whole_range() {
+        let viridis = ColorRamp::viridis();
+        assert_eq!(viridis.color_at(0.0).data, [0x44, 0x01, 0x54, 255]);
+        assert_eq!(viridis.color_at(1.0).data, [0xfd, 0xe7, 0x25, 255]);
+
+        let legend = ColorRamp::magma().legend(16, 2);
+        assert_eq!(legend.get_pixel(15, 1).data, [0xfc, 0xfd, 0xbf, 255]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_ramp() {
+        ColorRamp::new(vec![], RampInterpolation::Linear);
+    }
+}
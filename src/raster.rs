@@ -1,12 +1,44 @@
-use geom::Triangle;
+use geom::{Triangle, Vec3};
 use image::{ImageBuffer, Pixel};
 use std::ops::{Deref, DerefMut};
 
+/// Specifies which pixels a triangle is rasterized into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RasterMode {
+    /// Renders pixels with the lower left corner inside the triangle, using a top-left
+    /// fill rule so pixels on shared edges are rendered exactly once.
+    Exact,
+    /// Renders every pixel whose square touches the triangle, so that even slivers and
+    /// triangles smaller than a pixel render at least one pixel. Pixels on shared edges
+    /// are rendered for both triangles.
+    Conservative,
+}
+
+impl Default for RasterMode {
+    fn default() -> Self {
+        RasterMode::Exact
+    }
+}
+
 pub trait Rasterize {
     fn rasterize<F>(&self, raster_width: usize, raster_height: usize, render_pixel_at: F)
     where
         F: FnMut(usize, usize);
 
+    /// Renders like `rasterize`, using the given mode for triangles. Other things
+    /// are rendered the same in both modes.
+    fn rasterize_with_mode<F>(
+        &self,
+        _mode: RasterMode,
+        raster_width: usize,
+        raster_height: usize,
+        render_pixel_at: F,
+    ) where
+        F: FnMut(usize, usize),
+    {
+        self.rasterize(raster_width, raster_height, render_pixel_at)
+    }
+
     /// Renders a thing already transfomed into image space into the given slice.
     /// The y axis is drawn flipped, such that y = raster_height - 1 is the first line
     /// and y = 0 is the last line in the image.
@@ -41,205 +73,300 @@ pub trait Rasterize {
     }
 }
 
+/// Rasterization of triangles that passes barycentric weights to the callback.
+pub trait RasterizeBarycentric {
+    /// Renders like `Rasterize::rasterize_with_mode`, additionally passing the barycentric
+    /// weights of the lower left pixel corner with respect to the vertices, in the order of
+    /// `Triangle::positions`.
+    ///
+    /// Weights can be outside of 0..1 for pixels rendered in conservative mode.
+    fn rasterize_barycentric<F>(
+        &self,
+        mode: RasterMode,
+        raster_width: usize,
+        raster_height: usize,
+        render_pixel_at: F,
+    ) where
+        F: FnMut(usize, usize, Vec3);
+}
+
+impl<T: Triangle> RasterizeBarycentric for T {
+    fn rasterize_barycentric<F>(
+        &self,
+        mode: RasterMode,
+        raster_width: usize,
+        raster_height: usize,
+        render_pixel_at: F,
+    ) where
+        F: FnMut(usize, usize, Vec3),
+    {
+        match mode {
+            RasterMode::Exact => rasterize_exact(
+                self.positions(),
+                raster_width,
+                raster_height,
+                render_pixel_at,
+            ),
+            RasterMode::Conservative => rasterize_conservative(
+                self.positions(),
+                raster_width,
+                raster_height,
+                render_pixel_at,
+            ),
+        }
+    }
+}
+
+/// Renders the given triangles, passing the pixel coordinates, the barycentric weights
+/// of the lower left pixel corner and the index of the triangle to the callback, e.g. to
+/// interpolate arbitrary vertex attributes. See `RasterizeBarycentric`.
+///
+/// The y axis is pointing upwards, such that y = 0 is the last line in the image.
+pub fn rasterize_triangles<I, T, F>(
+    triangles: I,
+    mode: RasterMode,
+    raster_width: usize,
+    raster_height: usize,
+    mut render_pixel_at: F,
+) where
+    I: IntoIterator<Item = T>,
+    T: Triangle,
+    F: FnMut(usize, usize, Vec3, usize),
+{
+    for (triangle_idx, triangle) in triangles.into_iter().enumerate() {
+        triangle.rasterize_barycentric(mode, raster_width, raster_height, |x, y, weights| {
+            render_pixel_at(x, y, weights, triangle_idx)
+        });
+    }
+}
+
 impl<T: Triangle> Rasterize for T {
+    fn rasterize_with_mode<F>(
+        &self,
+        mode: RasterMode,
+        raster_width: usize,
+        raster_height: usize,
+        mut render_pixel_at: F,
+    ) where
+        F: FnMut(usize, usize),
+    {
+        self.rasterize_barycentric(mode, raster_width, raster_height, |x, y, _| {
+            render_pixel_at(x, y)
+        });
+    }
+
     /// Fills the triangle with a top-left fill convention, similar to OpenGL.
     /// See: http://forum.devmaster.net/t/advanced-rasterization/6145
-    #[allow(non_snake_case)]
     fn rasterize<F>(&self, raster_width: usize, raster_height: usize, mut render_pixel_at: F)
     where
         F: FnMut(usize, usize),
     {
-        /*if self.area() < 0.00000001 {
-            return; // ignore zero area triangles
-        }*/
-
-        // Flip order to compensate for flipped y axis
-        let (v1, v3, v2) = self.positions();
-
-        /*let (v1 , v2, v3) = (
-            v1 + Vec3::new(-0.5, -0.5, 0.0),
-            v2 + Vec3::new(-0.5, -0.5, 0.0),
-            v3 + Vec3::new(-0.5, -0.5, 0.0)
-        );*/
-
-        // First draw the minkowski sum of the triangle edges and a 3x3 square by drawing
-        // thick lines at the edges.
-        // FIXME this is a really weird and inefficient workaround, maybe implement
-        // other algorithm with different fill rule / convention
-        /*{
-            let mut draw3x3 = |x : usize, y : usize| {
-                let min_x = match x {
-                    0 => 0,
-                    1 => 0,
-                    x => x - 2
-                };
-                // max is exclusive
-                let max_x = (x + 2).min(raster_width);
-
-                //let min_y = if x <= 1 { y } else { y - 1 };
-                let min_y = match y {
-                    0 => 0,
-                    1 => 0,
-                    y => y - 2
-                };
-                let max_y = (y + 2).min(raster_height);
-
-                for x in min_x..max_x {
-                    for y in min_y..max_y {
-                        render_pixel_at(x, y);
-                    }
-                }
-            };
-
-            rasterize_line(
-                (v1.x.round() as i32, v1.y.round() as i32),
-                (v2.x.round() as i32, v2.y.round() as i32),
-                raster_width,
-                raster_height,
-                &mut draw3x3
-            );
-            rasterize_line(
-                (v2.x.round() as i32, v2.y.round() as i32),
-                (v3.x.round() as i32, v3.y.round() as i32),
-                raster_width,
-                raster_height,
-                &mut draw3x3
-            );
-            rasterize_line(
-                (v3.x.round() as i32, v3.y.round() as i32),
-                (v1.x.round() as i32, v1.y.round() as i32),
-                raster_width,
-                raster_height,
-                &mut draw3x3
-            );
-        }*/
-
-        // Draw the triangle larger than it actually is, this ensures the triangle
-        // will not contain or touch uninitialized pixels, i.e. it adds bleeding
-        // to the triangle, which is useful for texture images
-        // However, pixels now may be written to multiple times
-        /*let padding = 4.0;
-        let centroid = self.centroid();
-
-        let mut v1 = v1 - centroid;
-        v1.x = v1.x + padding * v1.x.signum() + centroid.x;
-        v1.y = v1.y + padding * v1.y.signum() + centroid.y;
-
-        let mut v2 = v2 - centroid;
-        v2.x = v2.x + padding * v2.x.signum() + centroid.x;
-        v2.y = v2.y + padding * v2.y.signum() + centroid.y;
-
-        let mut v3 = v3 - centroid;
-        v3.x = v3.x + padding * v3.x.signum() + centroid.x;
-        v3.y = v3.y + padding * v3.y.signum() + centroid.y;
-*/
-
-        // 28.4 fixed-point coordinates
-        let Y1 = (16.0 * v1.y).round() as i64;
-        let Y2 = (16.0 * v2.y).round() as i64;
-        let Y3 = (16.0 * v3.y).round() as i64;
-
-        let X1 = (16.0 * v1.x).round() as i64;
-        let X2 = (16.0 * v2.x).round() as i64;
-        let X3 = (16.0 * v3.x).round() as i64;
-
-        // Deltas
-        let DX12 = X1 - X2;
-        let DX23 = X2 - X3;
-        let DX31 = X3 - X1;
-
-        let DY12 = Y1 - Y2;
-        let DY23 = Y2 - Y3;
-        let DY31 = Y3 - Y1;
-
-        // Fixed-point deltas
-        let FDX12 = DX12 << 4;
-        let FDX23 = DX23 << 4;
-        let FDX31 = DX31 << 4;
-
-        let FDY12 = DY12 << 4;
-        let FDY23 = DY23 << 4;
-        let FDY31 = DY31 << 4;
-
-        // Bounding rectangle
-        let mut minx = ([X1, X2, X3].iter().min().unwrap() + 0xF) >> 4;
-        let mut maxx = ([X1, X2, X3].iter().max().unwrap() + 0xF) >> 4;
-        let mut miny = ([Y1, Y2, Y3].iter().min().unwrap() + 0xF) >> 4;
-        let mut maxy = ([Y1, Y2, Y3].iter().max().unwrap() + 0xF) >> 4;
-
-        // Clamp to raster size "cull"
-        {
-            let last_x = raster_width as i64;
-            let last_y = raster_height as i64;
-
-            if minx < 0 {
-                minx = 0;
-            }
-            if minx > last_x {
-                minx = last_x;
-            }
-            if maxx < 0 {
-                maxx = 0;
-            }
-            if maxx > last_x {
-                maxx = last_x;
-            }
+        rasterize_exact(
+            self.positions(),
+            raster_width,
+            raster_height,
+            |x, y, _| render_pixel_at(x, y),
+        );
+    }
+}
 
-            if miny < 0 {
-                miny = 0;
-            }
-            if miny > last_y {
-                miny = last_y;
-            }
-            if maxy < 0 {
-                maxy = 0;
-            }
-            if maxy > last_y {
-                maxy = last_y;
-            }
-        }
+/// Fills the triangle with the given corners with a top-left fill convention, passing
+/// the barycentric weights of each rendered pixel, see `Rasterize::rasterize`.
+#[allow(non_snake_case)]
+fn rasterize_exact<F>(
+    positions: (Vec3, Vec3, Vec3),
+    raster_width: usize,
+    raster_height: usize,
+    mut render_pixel_at: F,
+) where
+    F: FnMut(usize, usize, Vec3),
+{
+    // Flip order to compensate for flipped y axis
+    let (v1, v3, v2) = positions;
+
+    // 28.4 fixed-point coordinates
+    let Y1 = (16.0 * v1.y).round() as i64;
+    let Y2 = (16.0 * v2.y).round() as i64;
+    let Y3 = (16.0 * v3.y).round() as i64;
+
+    let X1 = (16.0 * v1.x).round() as i64;
+    let X2 = (16.0 * v2.x).round() as i64;
+    let X3 = (16.0 * v3.x).round() as i64;
+
+    // Deltas
+    let DX12 = X1 - X2;
+    let DX23 = X2 - X3;
+    let DX31 = X3 - X1;
+
+    let DY12 = Y1 - Y2;
+    let DY23 = Y2 - Y3;
+    let DY31 = Y3 - Y1;
+
+    // Fixed-point deltas
+    let FDX12 = DX12 << 4;
+    let FDX23 = DX23 << 4;
+    let FDX31 = DX31 << 4;
+
+    let FDY12 = DY12 << 4;
+    let FDY23 = DY23 << 4;
+    let FDY31 = DY31 << 4;
+
+    // Bounding rectangle
+    let mut minx = ([X1, X2, X3].iter().min().unwrap() + 0xF) >> 4;
+    let mut maxx = ([X1, X2, X3].iter().max().unwrap() + 0xF) >> 4;
+    let mut miny = ([Y1, Y2, Y3].iter().min().unwrap() + 0xF) >> 4;
+    let mut maxy = ([Y1, Y2, Y3].iter().max().unwrap() + 0xF) >> 4;
+
+    // Clamp to raster size "cull"
+    {
+        let last_x = raster_width as i64;
+        let last_y = raster_height as i64;
 
-        // Half-edge constants
-        let mut C1 = DY12 * X1 - DX12 * Y1;
-        let mut C2 = DY23 * X2 - DX23 * Y2;
-        let mut C3 = DY31 * X3 - DX31 * Y3;
+        if minx < 0 {
+            minx = 0;
+        }
+        if minx > last_x {
+            minx = last_x;
+        }
+        if maxx < 0 {
+            maxx = 0;
+        }
+        if maxx > last_x {
+            maxx = last_x;
+        }
 
-        // Correct for fill convention
-        if DY12 < 0 || (DY12 == 0 && DX12 > 0) {
-            C1 += 1;
+        if miny < 0 {
+            miny = 0;
+        }
+        if miny > last_y {
+            miny = last_y;
         }
-        if DY23 < 0 || (DY23 == 0 && DX23 > 0) {
-            C2 += 1;
+        if maxy < 0 {
+            maxy = 0;
         }
-        if DY31 < 0 || (DY31 == 0 && DX31 > 0) {
-            C3 += 1;
+        if maxy > last_y {
+            maxy = last_y;
         }
+    }
 
-        let mut CY1 = C1 + DX12 * (miny << 4) - DY12 * (minx << 4);
-        let mut CY2 = C2 + DX23 * (miny << 4) - DY23 * (minx << 4);
-        let mut CY3 = C3 + DX31 * (miny << 4) - DY31 * (minx << 4);
-
-        for y in miny..maxy {
-            let mut CX1 = CY1;
-            let mut CX2 = CY2;
-            let mut CX3 = CY3;
-
-            for x in minx..maxx {
-                //if CX1 >= -20 && CX2 >= -20 && CX3 >= -20 {
-                if CX1 > 0 && CX2 > 0 && CX3 > 0 {
-                    let x = x as usize;
-                    let y = y as usize;
-                    render_pixel_at(x, y);
-                }
-
-                CX1 -= FDY12;
-                CX2 -= FDY23;
-                CX3 -= FDY31;
+    // Half-edge constants
+    let mut C1 = DY12 * X1 - DX12 * Y1;
+    let mut C2 = DY23 * X2 - DX23 * Y2;
+    let mut C3 = DY31 * X3 - DX31 * Y3;
+
+    // Correct for fill convention
+    let B1 = (DY12 < 0 || (DY12 == 0 && DX12 > 0)) as i64;
+    let B2 = (DY23 < 0 || (DY23 == 0 && DX23 > 0)) as i64;
+    let B3 = (DY31 < 0 || (DY31 == 0 && DX31 > 0)) as i64;
+    C1 += B1;
+    C2 += B2;
+    C3 += B3;
+
+    let mut CY1 = C1 + DX12 * (miny << 4) - DY12 * (minx << 4);
+    let mut CY2 = C2 + DX23 * (miny << 4) - DY23 * (minx << 4);
+    let mut CY3 = C3 + DX31 * (miny << 4) - DY31 * (minx << 4);
+
+    for y in miny..maxy {
+        let mut CX1 = CY1;
+        let mut CX2 = CY2;
+        let mut CX3 = CY3;
+
+        for x in minx..maxx {
+            if CX1 > 0 && CX2 > 0 && CX3 > 0 {
+                let x = x as usize;
+                let y = y as usize;
+
+                // Without the fill convention, the edge function of each edge is
+                // proportional to the weight of the opposite vertex, report in the
+                // original vertex order
+                let (E1, E2, E3) = ((CX1 - B1) as f32, (CX2 - B2) as f32, (CX3 - B3) as f32);
+                let sum = E1 + E2 + E3;
+                let weights = Vec3::new(E2 / sum, E1 / sum, E3 / sum);
+                render_pixel_at(x, y, weights);
             }
 
-            CY1 += FDX12;
-            CY2 += FDX23;
-            CY3 += FDX31;
+            CX1 -= FDY12;
+            CX2 -= FDY23;
+            CX3 -= FDY31;
+        }
+
+        CY1 += FDX12;
+        CY2 += FDX23;
+        CY3 += FDX31;
+    }
+}
+
+/// Renders every pixel whose unit square, with the pixel coordinates as lower left corner,
+/// overlaps the triangle with the given corners, passing the barycentric weights of the
+/// lower left corner.
+///
+/// A pixel overlaps the triangle if it overlaps the bounds of the triangle and, for each
+/// edge, the corner of the square farthest inside the edge is inside. Degenerate
+/// triangles render the pixels touched by their extent, weighting all vertices equally.
+fn rasterize_conservative<F>(
+    (a, b, c): (Vec3, Vec3, Vec3),
+    raster_width: usize,
+    raster_height: usize,
+    mut render_pixel_at: F,
+) where
+    F: FnMut(usize, usize, Vec3),
+{
+    let edge_function = |p: Vec3, q: Vec3, x: f32, y: f32| {
+        (q.x - p.x) * (y - p.y) - (q.y - p.y) * (x - p.x)
+    };
+    let double_area = edge_function(a, b, c.x, c.y);
+    let weights_at = |x: f32, y: f32| {
+        if double_area == 0.0 {
+            Vec3::new(1.0, 1.0, 1.0) / 3.0
+        } else {
+            Vec3::new(
+                edge_function(b, c, x, y) / double_area,
+                edge_function(c, a, x, y) / double_area,
+                edge_function(a, b, x, y) / double_area,
+            )
+        }
+    };
+
+    // Make counter-clockwise so the inside is on the left of each edge
+    let (b, c) = if (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y) < 0.0 {
+        (c, b)
+    } else {
+        (b, c)
+    };
+
+    let min_x = a.x.min(b.x).min(c.x).floor().max(0.0);
+    let min_y = a.y.min(b.y).min(c.y).floor().max(0.0);
+    // Pixels beyond the maximum only touch the bounds, but at least one pixel is rendered
+    let max_x = (a.x.max(b.x).max(c.x).ceil() - 1.0)
+        .max(min_x)
+        .min(raster_width as f32 - 1.0);
+    let max_y = (a.y.max(b.y).max(c.y).ceil() - 1.0)
+        .max(min_y)
+        .min(raster_height as f32 - 1.0);
+
+    if !(min_x <= max_x && min_y <= max_y) {
+        return;
+    }
+
+    // Edge start and inward normal, skipping edges without length
+    let edges = [(a, b), (b, c), (c, a)]
+        .iter()
+        .filter(|&&(start, end)| start.x != end.x || start.y != end.y)
+        .map(|&(start, end)| (start, Vec3::new(start.y - end.y, end.x - start.x, 0.0)))
+        .collect::<Vec<_>>();
+
+    for y in (min_y as usize)..(max_y as usize + 1) {
+        for x in (min_x as usize)..(max_x as usize + 1) {
+            let inside = edges.iter().all(|&(start, normal)| {
+                // Corner of the pixel square farthest along the inward normal
+                let corner_x = x as f32 + if normal.x > 0.0 { 1.0 } else { 0.0 };
+                let corner_y = y as f32 + if normal.y > 0.0 { 1.0 } else { 0.0 };
+                (corner_x - start.x) * normal.x + (corner_y - start.y) * normal.y > 0.0
+            });
+
+            if inside {
+                render_pixel_at(x, y, weights_at(x as f32, y as f32));
+            }
         }
     }
 }
@@ -249,12 +376,12 @@ mod test {
     extern crate aitios_asset as asset;
 
     use super::*;
-    use geom::{Interpolation, Vec3};
+    use geom::{FromVertices, Interpolation, TupleTriangle, Vec2};
     use image::{self, Rgb};
     use scene::Mesh;
     use std::f32::EPSILON;
     use std::fs::File;
-    use uv_triangle::triangle_into_uv_image_space;
+    use uv_triangle::{triangle_into_uv_image_space, UvVtx};
 
     /// Takes the mesh triangles and draws the interpolated model positions in UV space
     /// just as in the use case where we want to synthesize a texture.
@@ -295,4 +422,75 @@ mod test {
             .write_to(fout, image::PNG)
             .unwrap();
     }
+
+    fn triangle(a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> TupleTriangle<UvVtx> {
+        let vtx = |(x, y): (f32, f32)| UvVtx {
+            uv_position: Vec2::new(x, y),
+            world_normal: Vec3::new(0.0, 0.0, 1.0),
+            world_position: Vec3::new(x, y, 0.0),
+            corner: 0,
+        };
+        TupleTriangle::new(vtx(a), vtx(b), vtx(c))
+    }
+
+    fn rendered(t: &TupleTriangle<UvVtx>, mode: RasterMode) -> Vec<(usize, usize)> {
+        let mut pixels = Vec::new();
+        t.rasterize_with_mode(mode, 8, 8, |x, y| pixels.push((x, y)));
+        pixels.sort();
+        pixels
+    }
+
+    #[test]
+    fn conservative_renders_slivers() {
+        let sliver = triangle((1.2, 1.2), (3.8, 1.3), (1.2, 1.4));
+
+        assert!(rendered(&sliver, RasterMode::Exact).is_empty());
+        assert_eq!(
+            rendered(&sliver, RasterMode::Conservative),
+            vec![(1, 1), (2, 1), (3, 1)]
+        );
+    }
+
+    #[test]
+    fn conservative_contains_exact() {
+        let t = triangle((0.5, 0.5), (6.5, 1.5), (2.5, 6.0));
+        let exact = rendered(&t, RasterMode::Exact);
+        let conservative = rendered(&t, RasterMode::Conservative);
+
+        assert!(!exact.is_empty());
+        assert!(exact.iter().all(|p| conservative.contains(p)));
+        assert!(conservative.len() > exact.len());
+        // Pixel touching only the corner of the bounds, but not the triangle
+        assert!(!conservative.contains(&(6, 5)));
+    }
+
+    #[test]
+    fn barycentric_weights_interpolate_pixel_corner() {
+        let triangles = vec![
+            triangle((0.5, 0.5), (6.5, 1.5), (2.5, 6.0)),
+            triangle((7.5, 7.5), (6.5, 1.5), (2.5, 6.0)),
+        ];
+
+        for &mode in &[RasterMode::Exact, RasterMode::Conservative] {
+            let mut pixels = Vec::new();
+            rasterize_triangles(
+                triangles.iter().cloned(),
+                mode,
+                8,
+                8,
+                |x, y, weights, triangle_idx| {
+                    let (a, b, c) = triangles[triangle_idx].positions();
+                    let corner = a * weights.x + b * weights.y + c * weights.z;
+                    assert_abs_diff_eq!(weights.x + weights.y + weights.z, 1.0, epsilon = 1e-5);
+                    assert_abs_diff_eq!(corner.x, x as f32, epsilon = 1e-4);
+                    assert_abs_diff_eq!(corner.y, y as f32, epsilon = 1e-4);
+                    pixels.push((x, y, triangle_idx));
+                },
+            );
+
+            assert!(pixels.iter().any(|&(_, _, triangle_idx)| triangle_idx == 1));
+            let first = pixels.iter().filter(|&&(_, _, triangle_idx)| triangle_idx == 0);
+            assert_eq!(first.count(), rendered(&triangles[0], mode).len());
+        }
+    }
 }
@@ -0,0 +1,341 @@
+//!
+//! Provides kernels for reconstructing texel values from the surfels close to a texel.
+//!
+
+use self::SubstanceFilter::*;
+use lookup_table::TexelSurfels;
+use std::f32::{INFINITY, NEG_INFINITY};
+
+/// Reconstructs a texel value from the values of nearby surfels.
+///
+/// Implement this to use a custom kernel with `Density` or `PackedDensity`.
+pub trait Reconstruction: Send + Sync {
+    /// Combines the values of the given close surfels into a single texel value.
+    ///
+    /// `close_surfels` holds the squared world-space distance between texel and surfel
+    /// along with the index of the surfel, in no particular order. `value_of` obtains the
+    /// value to reconstruct from a surfel index, e.g. the amount of a specific substance.
+    ///
+    /// With supersampling, surfels gathered at only some samples of the texel have less
+    /// weight, see `TexelSurfels::weight`, which kernels should multiply in.
+    ///
+    /// Returns `None` if no value can be reconstructed, e.g. if no surfels are close to
+    /// the texel, marking the texel as undefined.
+    fn reconstruct(
+        &self,
+        close_surfels: TexelSurfels,
+        value_of: &Fn(usize) -> f32,
+    ) -> Option<f32>;
+}
+
+pub enum SubstanceFilter {
+    /// When combining n surfels into a texel, take the average of substance, only weighted
+    /// with the weights of the surfels in the texel.
+    Flat,
+    /// When combining n surfels into a texel do a weighted average, give the nearest
+    /// texel the highest influence, gradually decreasing until the last surfel with influence 0
+    Smooth,
+}
+
+impl SubstanceFilter {
+    /// Combines the values of the given close surfels into a single texel value.
+    ///
+    /// `value_of` obtains the value to filter from a surfel index, e.g. the amount of
+    /// a specific substance. Returns `None` if no surfels are close to the texel.
+    pub fn filter<F>(&self, close_surfels: TexelSurfels, value_of: F) -> Option<f32>
+    where
+        F: Fn(usize) -> f32,
+    {
+        match close_surfels.len() {
+            0 => None,
+            // Single surfel, no filtering
+            1 => Some(value_of(close_surfels.get(0).1)),
+            _ => Some(match *self {
+                Flat => density_at_idxs(close_surfels, value_of),
+                Smooth => dist_sqr_ratio_weighted(close_surfels, value_of),
+            }),
+        }
+    }
+}
+
+impl Reconstruction for SubstanceFilter {
+    fn reconstruct(
+        &self,
+        close_surfels: TexelSurfels,
+        value_of: &Fn(usize) -> f32,
+    ) -> Option<f32> {
+        self.filter(close_surfels, value_of)
+    }
+}
+
+/// Uses only the value of the closest surfel, regardless of its weight in the texel.
+pub struct Nearest;
+
+impl Reconstruction for Nearest {
+    fn reconstruct(
+        &self,
+        close_surfels: TexelSurfels,
+        value_of: &Fn(usize) -> f32,
+    ) -> Option<f32> {
+        close_surfels
+            .iter()
+            .min_by(|&(a, _), &(b, _)| a.partial_cmp(&b).unwrap())
+            .map(|(_, surfel_idx)| value_of(surfel_idx))
+    }
+}
+
+/// Weights surfels by their inverse distance raised to the given power.
+///
+/// A power of 2 yields classical Shepard interpolation, higher powers make the
+/// result look more like `Nearest`.
+pub struct InverseDistance {
+    pub power: f32,
+}
+
+impl Reconstruction for InverseDistance {
+    fn reconstruct(
+        &self,
+        close_surfels: TexelSurfels,
+        value_of: &Fn(usize) -> f32,
+    ) -> Option<f32> {
+        // A coinciding surfel would get infinite weight, use its value exactly
+        let coinciding = close_surfels
+            .iter()
+            .find(|&(dist_sqr, _)| dist_sqr == 0.0);
+        if let Some((_, surfel_idx)) = coinciding {
+            return Some(value_of(surfel_idx));
+        }
+
+        // Working with squared distances avoids the square root
+        let exponent = -0.5 * self.power;
+        let weights = close_surfels
+            .iter()
+            .map(|(dist_sqr, _)| dist_sqr.powf(exponent));
+        normalized_weighted_avg(close_surfels, value_of, weights)
+    }
+}
+
+/// Weights surfels with a gaussian of their distance with the given standard
+/// deviation in world units.
+///
+/// Texels are defined as long as any surfel is close. If all surfels are far away
+/// compared to the standard deviation, the result approaches the value of the nearest
+/// surfel instead of becoming undefined.
+pub struct Gaussian {
+    pub sigma: f32,
+}
+
+impl Reconstruction for Gaussian {
+    fn reconstruct(
+        &self,
+        close_surfels: TexelSurfels,
+        value_of: &Fn(usize) -> f32,
+    ) -> Option<f32> {
+        let one_over_two_sigma_sqr = (2.0 * self.sigma * self.sigma).recip();
+        // Scaling all weights by the same factor does not change the weighted average.
+        // Measuring relative to the nearest surfel gives it weight 1, so the weights
+        // cannot all underflow to zero for distant surfels.
+        let min_dist_sqr = close_surfels
+            .iter()
+            .map(|(dist_sqr, _)| dist_sqr)
+            .fold(INFINITY, f32::min);
+        let weights = close_surfels
+            .iter()
+            .map(|(dist_sqr, _)| (-(dist_sqr - min_dist_sqr) * one_over_two_sigma_sqr).exp());
+        normalized_weighted_avg(close_surfels, value_of, weights)
+    }
+}
+
+/// Weights surfels with the compactly supported Wendland C2 function, giving
+/// no weight to surfels farther away than the given radius in world units.
+///
+/// Texels without any surfel inside the radius are undefined.
+pub struct Wendland {
+    pub radius: f32,
+}
+
+impl Reconstruction for Wendland {
+    fn reconstruct(
+        &self,
+        close_surfels: TexelSurfels,
+        value_of: &Fn(usize) -> f32,
+    ) -> Option<f32> {
+        let one_over_radius = self.radius.recip();
+        let weights = close_surfels.iter().map(|(dist_sqr, _)| {
+            let r = dist_sqr.sqrt() * one_over_radius;
+            if r < 1.0 {
+                (1.0 - r).powi(4) * (4.0 * r + 1.0)
+            } else {
+                0.0
+            }
+        });
+        normalized_weighted_avg(close_surfels, value_of, weights)
+    }
+}
+
+/// Weighted average that is undefined if the weights sum up to zero.
+fn normalized_weighted_avg<F>(
+    close_surfels: TexelSurfels,
+    value_of: F,
+    weights: impl Clone + Iterator<Item = f32>,
+) -> Option<f32>
+where
+    F: Fn(usize) -> f32,
+{
+    let weights_sum = weights
+        .clone()
+        .enumerate()
+        .map(|(idx, weight)| weight * close_surfels.weight(idx))
+        .sum::<f32>();
+    if weights_sum > 0.0 {
+        Some(weighted_avg(close_surfels, value_of, weights))
+    } else {
+        None
+    }
+}
+
+/// Average weighted with the given weights multiplied by the weights of the surfels
+/// in the texel.
+fn weighted_avg<F>(
+    close_surfels: TexelSurfels,
+    value_of: F,
+    weights: impl Clone + Iterator<Item = f32>,
+) -> f32
+where
+    F: Fn(usize) -> f32,
+{
+    let weights = weights
+        .enumerate()
+        .map(|(idx, weight)| weight * close_surfels.weight(idx));
+    let one_over_weights_sum = weights.clone().sum::<f32>().recip();
+    let scaled_weights = weights.map(|w| one_over_weights_sum * w);
+    close_surfels
+        .iter()
+        .map(|(_, surfel_idx)| value_of(surfel_idx))
+        .zip(scaled_weights)
+        .map(|(substance, weight)| substance * weight)
+        .sum::<f32>()
+}
+
+fn dist_sqr_ratio_weighted<F>(close_surfels: TexelSurfels, value_of: F) -> f32
+where
+    F: Fn(usize) -> f32,
+{
+    // The maximally distant surfel still has some influence,
+    const MIN_WEIGHT: f32 = 1.0;
+    // If a surfel completely coincides with the texel position, it has MIN_WEIGHT+RANGE influence
+    const RANGE: f32 = 5.0;
+    let dists = close_surfels.iter().map(|(dist, _)| dist);
+    let max_dist = dists.clone().fold(NEG_INFINITY, f32::max);
+    let max_dist_inv = max_dist.recip();
+    let weights = dists.map(|d| (max_dist - d) * max_dist_inv * RANGE + MIN_WEIGHT);
+    weighted_avg(close_surfels, value_of, weights)
+}
+
+fn density_at_idxs<F>(close_surfels: TexelSurfels, value_of: F) -> f32
+where
+    F: Fn(usize) -> f32,
+{
+    let weights = (0..close_surfels.len()).map(|_| 1.0);
+    weighted_avg(close_surfels, value_of, weights)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const VALUES: [f32; 3] = [0.0, 1.0, 2.0];
+
+    fn value_of(surfel_idx: usize) -> f32 {
+        VALUES[surfel_idx]
+    }
+
+    #[test]
+    fn no_surfels_undefined() {
+        let empty = TexelSurfels::empty();
+        assert_eq!(Flat.reconstruct(empty, &value_of), None);
+        assert_eq!(Nearest.reconstruct(empty, &value_of), None);
+        assert_eq!(
+            InverseDistance { power: 2.0 }.reconstruct(empty, &value_of),
+            None
+        );
+        assert_eq!(Gaussian { sigma: 1.0 }.reconstruct(empty, &value_of), None);
+    }
+
+    #[test]
+    fn nearest_ignores_order() {
+        let close_surfels = TexelSurfels::new(&[0, 2, 1], &[4.0, 1.0, 9.0]);
+        assert_eq!(Nearest.reconstruct(close_surfels, &value_of), Some(2.0));
+    }
+
+    #[test]
+    fn inverse_distance_coinciding_surfel() {
+        let close_surfels = TexelSurfels::new(&[2, 1], &[1.0, 0.0]);
+        assert_eq!(
+            InverseDistance { power: 2.0 }.reconstruct(close_surfels, &value_of),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn inverse_distance_weights() {
+        // Distances 1 and 2, weights 1 and 1/4 with power 2
+        let close_surfels = TexelSurfels::new(&[0, 2], &[1.0, 4.0]);
+        let reconstructed = InverseDistance { power: 2.0 }
+            .reconstruct(close_surfels, &value_of)
+            .unwrap();
+        assert_ulps_eq!(reconstructed, 0.5 / 1.25);
+    }
+
+    #[test]
+    fn gaussian_equidistant_is_average() {
+        let close_surfels = TexelSurfels::new(&[0, 2], &[2.0, 2.0]);
+        let reconstructed = Gaussian { sigma: 0.5 }
+            .reconstruct(close_surfels, &value_of)
+            .unwrap();
+        assert_ulps_eq!(reconstructed, 1.0);
+    }
+
+    #[test]
+    fn gaussian_distant_surfels_defined() {
+        // Absolute weights of e^-5000 and less underflow to zero
+        let gaussian = Gaussian { sigma: 1.0 };
+        let equidistant = TexelSurfels::new(&[0, 2], &[1e4, 1e4]);
+        let reconstructed = gaussian.reconstruct(equidistant, &value_of).unwrap();
+        assert_ulps_eq!(reconstructed, 1.0);
+
+        let gaussian = Gaussian { sigma: 0.1 };
+        let close_surfels = TexelSurfels::new(&[2, 1], &[1e4 + 1.0, 1e4]);
+        let reconstructed = gaussian.reconstruct(close_surfels, &value_of).unwrap();
+        assert_abs_diff_eq!(reconstructed, 1.0, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn wendland_compact_support() {
+        let wendland = Wendland { radius: 1.5 };
+        let out_of_range = TexelSurfels::new(&[1], &[4.0]);
+        assert_eq!(wendland.reconstruct(out_of_range, &value_of), None);
+
+        let reconstructed = wendland
+            .reconstruct(TexelSurfels::new(&[1, 2], &[4.0, 1.0]), &value_of)
+            .unwrap();
+        assert_ulps_eq!(reconstructed, 2.0);
+    }
+
+    #[test]
+    fn surfel_weights_in_texel() {
+        // Surfel 2 was only gathered at a quarter of the samples of the texel
+        let weights = [0xFFFF, 0x4000];
+        let close_surfels = TexelSurfels::new(&[1, 2], &[1.0, 1.0]).with_weights(&weights);
+        let quarter = f32::from(0x4000_u16) / 65535.0;
+        let expected = (1.0 + 2.0 * quarter) / (1.0 + quarter);
+
+        let flat = Flat.reconstruct(close_surfels, &value_of).unwrap();
+        assert_abs_diff_eq!(flat, expected, epsilon = 1e-6);
+        let gaussian = Gaussian { sigma: 1.0 }
+            .reconstruct(close_surfels, &value_of)
+            .unwrap();
+        assert_abs_diff_eq!(gaussian, expected, epsilon = 1e-6);
+        assert_eq!(Nearest.reconstruct(close_surfels, &value_of), Some(1.0));
+    }
+}
@@ -0,0 +1,189 @@
+//!
+//! Provides a mapping from surfels to the texels they influence.
+//!
+
+use image::{ImageBuffer, Luma, Pixel};
+use lookup_table::SurfelLookupTable;
+use reconstruction::{Reconstruction, SubstanceFilter};
+use std::ops::{Deref, DerefMut, Range};
+
+/// Maps each surfel to the texels whose entries in a `SurfelLookupTable` refer to it,
+/// inverting the table, along with the weight of the surfel in each texel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReverseIndex {
+    width: usize,
+    height: usize,
+    /// Index of the first texel of each surfel, followed by the total texel count
+    offsets: Vec<u32>,
+    /// Texel offsets in scanline order, one surfel after another
+    texel_idxs: Vec<u32>,
+    /// Weight of the surfel in the texel at the same position in `texel_idxs`
+    weights: Vec<f32>,
+}
+
+impl ReverseIndex {
+    /// Inverts the given table for a surface with the given number of surfels, weighting
+    /// surfels equally in each texel.
+    pub fn new(table: &SurfelLookupTable, surfel_count: usize) -> Self {
+        Self::with_reconstruction(table, surfel_count, &SubstanceFilter::Flat)
+    }
+
+    /// Inverts the given table, obtaining the weight of a surfel in a texel from the
+    /// given reconstruction.
+    ///
+    /// The weight is the texel value reconstructed when the surfel has value 1 and
+    /// all other surfels have value 0, which is exact for kernels that are weighted averages.
+    pub fn with_reconstruction(
+        table: &SurfelLookupTable,
+        surfel_count: usize,
+        reconstruction: &Reconstruction,
+    ) -> Self {
+        let mut counts = vec![0_u32; surfel_count];
+        for texel in table.texels() {
+            for (_, surfel_idx) in texel.iter() {
+                counts[surfel_idx] += 1;
+            }
+        }
+
+        let mut offsets = Vec::with_capacity(surfel_count + 1);
+        let mut offset = 0;
+        offsets.push(offset);
+        for count in counts {
+            offset += count;
+            offsets.push(offset);
+        }
+
+        // Fill in texels, using the offsets as write positions that are advanced
+        let mut next = offsets[..surfel_count].to_vec();
+        let mut texel_idxs = vec![0; offset as usize];
+        let mut weights = vec![0.0; offset as usize];
+        for (texel_idx, texel) in table.texels().enumerate() {
+            for (_, surfel_idx) in texel.iter() {
+                let weight = reconstruction
+                    .reconstruct(texel, &|other_idx| {
+                        if other_idx == surfel_idx {
+                            1.0
+                        } else {
+                            0.0
+                        }
+                    })
+                    .unwrap_or(0.0);
+
+                let entry = next[surfel_idx] as usize;
+                texel_idxs[entry] = texel_idx as u32;
+                weights[entry] = weight;
+                next[surfel_idx] += 1;
+            }
+        }
+
+        ReverseIndex {
+            width: table.width(),
+            height: table.height(),
+            offsets,
+            texel_idxs,
+            weights,
+        }
+    }
+
+    /// Number of surfels in the index.
+    pub fn surfel_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Gets the offsets in scanline order of the texels influenced by the given surfel.
+    pub fn texels(&self, surfel_idx: usize) -> &[u32] {
+        &self.texel_idxs[self.range(surfel_idx)]
+    }
+
+    /// Gets the weights of the given surfel in the texels returned by `texels`.
+    pub fn weights(&self, surfel_idx: usize) -> &[f32] {
+        &self.weights[self.range(surfel_idx)]
+    }
+
+    /// Iterates over the texel coordinates influenced by the given surfel, with y = 0
+    /// being the top line, along with the weight of the surfel in the texel.
+    pub fn footprint<'a>(
+        &'a self,
+        surfel_idx: usize,
+    ) -> impl Iterator<Item = (u32, u32, f32)> + 'a {
+        let width = self.width as u32;
+        self.texels(surfel_idx)
+            .iter()
+            .zip(self.weights(surfel_idx).iter())
+            .map(move |(&texel_idx, &weight)| (texel_idx % width, texel_idx / width, weight))
+    }
+
+    /// Renders the footprint of a single surfel for debugging, with the weight of the
+    /// surfel mapped to brightness.
+    pub fn footprint_image(&self, surfel_idx: usize) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        let mut image = ImageBuffer::new(self.width as u32, self.height as u32);
+        self.paint(&mut image, Some(surfel_idx), |_, weight| Luma {
+            data: [(weight.max(0.0).min(1.0) * 255.0).round() as u8],
+        });
+        image
+    }
+
+    /// Paints the texels influenced by the given surfels into an image with the
+    /// dimensions of the lookup table, leaving other texels as they are.
+    ///
+    /// `pixel_of` obtains the pixel from the surfel index and its weight in the texel.
+    /// If a texel is influenced by multiple of the surfels, the last one wins.
+    pub fn paint<P, C, I, F>(&self, image: &mut ImageBuffer<P, C>, surfels: I, pixel_of: F)
+    where
+        P: Pixel + 'static,
+        C: Deref<Target = [P::Subpixel]> + DerefMut,
+        I: IntoIterator<Item = usize>,
+        F: Fn(usize, f32) -> P,
+    {
+        for surfel_idx in surfels {
+            for (x, y, weight) in self.footprint(surfel_idx) {
+                image.put_pixel(x, y, pixel_of(surfel_idx, weight));
+            }
+        }
+    }
+
+    fn range(&self, surfel_idx: usize) -> Range<usize> {
+        (self.offsets[surfel_idx] as usize)..(self.offsets[surfel_idx + 1] as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lookup_table::TableSettings;
+    use reconstruction::Nearest;
+    use surfel_table::Gather;
+
+    fn table() -> SurfelLookupTable {
+        let texels = vec![
+            vec![(1.0, 0), (4.0, 1)],
+            vec![(1.0, 1)],
+            vec![],
+            vec![(9.0, 0), (1.0, 2)],
+        ];
+        let settings = TableSettings::new(2, 2, 0, Gather::Nearest(2));
+        SurfelLookupTable::from_texels(settings, 0, texels)
+    }
+
+    #[test]
+    fn inverts_table() {
+        let reverse = ReverseIndex::new(&table(), 3);
+
+        assert_eq!(reverse.surfel_count(), 3);
+        assert_eq!(reverse.texels(0), &[0, 3]);
+        assert_eq!(reverse.texels(1), &[0, 1]);
+        assert_eq!(reverse.texels(2), &[3]);
+        assert_eq!(reverse.weights(1), &[0.5, 1.0]);
+    }
+
+    #[test]
+    fn weights_from_reconstruction() {
+        let reverse = ReverseIndex::with_reconstruction(&table(), 3, &Nearest);
+
+        assert_eq!(reverse.weights(0), &[1.0, 0.0]);
+        assert_eq!(
+            reverse.footprint(2).collect::<Vec<_>>(),
+            vec![(1, 1, 1.0)]
+        );
+    }
+}
@@ -0,0 +1,641 @@
+//!
+//! Provides detection of UV seams, i.e. mesh edges that are cut apart in texture space,
+//! and texture filters that treat the surface as continuous across them.
+//!
+
+use dilation::FillSubpixel;
+use geom::{Vec2, Vec3};
+use geom_tex::GBuffer;
+use image::{ImageBuffer, Pixel};
+use islands::{BoundaryEdge, UvIslands};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// A mesh edge that occurs twice on the outlines of UV islands with different texture
+/// coordinates, so that both sides of the edge are far apart in the texture.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Seam {
+    /// Indexes of the mesh triangles on both sides of the seam.
+    pub triangles: (usize, usize),
+    /// Indexes of the UV islands on both sides of the seam, which may be the same island.
+    pub islands: (usize, usize),
+    /// World-space positions of the start and end of the seam.
+    pub positions: (Vec3, Vec3),
+    /// Texture coordinates of the start and end of the seam on the first side.
+    pub texcoords_a: (Vec2, Vec2),
+    /// Texture coordinates of the start and end of the seam on the second side.
+    pub texcoords_b: (Vec2, Vec2),
+}
+
+impl Seam {
+    /// Interpolates the texture coordinates on both sides at the given fraction of the
+    /// way from start to end.
+    pub fn texcoords_at(&self, t: f32) -> (Vec2, Vec2) {
+        (
+            self.texcoords_a.0 + (self.texcoords_a.1 - self.texcoords_a.0) * t,
+            self.texcoords_b.0 + (self.texcoords_b.1 - self.texcoords_b.0) * t,
+        )
+    }
+
+    /// Number of samples needed to visit each texel along both sides of the seam
+    /// in a texture with the given dimensions.
+    pub fn sample_count(&self, width: usize, height: usize) -> usize {
+        let scale = |v: Vec2| Vec2::new(v.x * width as f32, v.y * height as f32);
+        let length = |(start, end): (Vec2, Vec2)| {
+            let d = scale(end) - scale(start);
+            (d.x * d.x + d.y * d.y).sqrt()
+        };
+        let longest = length(self.texcoords_a).max(length(self.texcoords_b));
+        (2.0 * longest).ceil() as usize + 1
+    }
+}
+
+/// Finds the seams between the boundary edges of the given islands.
+///
+/// Boundary edges with the same world-space positions are paired up. Edges on the
+/// border of the mesh and non-manifold edges shared by more than two boundary edges
+/// are not seams.
+pub fn find_seams(islands: &UvIslands) -> Vec<Seam> {
+    let mut edges = HashMap::new();
+    for island in islands.islands() {
+        for edge in &island.boundary_edges {
+            let start = position_key(edge.positions.0);
+            let end = position_key(edge.positions.1);
+            let key = if start < end { (start, end) } else { (end, start) };
+            edges.entry(key).or_insert_with(Vec::new).push(*edge);
+        }
+    }
+
+    let mut seams = edges
+        .values()
+        .filter(|edges| edges.len() == 2)
+        .map(|edges| seam(islands, &edges[0], &edges[1]))
+        .collect::<Vec<_>>();
+    seams.sort_by_key(|s| s.triangles);
+    seams
+}
+
+fn seam(islands: &UvIslands, a: &BoundaryEdge, b: &BoundaryEdge) -> Seam {
+    let (a, b) = if a.triangle_idx <= b.triangle_idx {
+        (a, b)
+    } else {
+        (b, a)
+    };
+
+    // Orient the second side so it starts at the same vertex as the first
+    let same_direction = position_key(a.positions.0) == position_key(b.positions.0);
+    let texcoords_b = if same_direction {
+        b.texcoords
+    } else {
+        (b.texcoords.1, b.texcoords.0)
+    };
+
+    Seam {
+        triangles: (a.triangle_idx, b.triangle_idx),
+        islands: (
+            islands.island_of(a.triangle_idx),
+            islands.island_of(b.triangle_idx),
+        ),
+        positions: a.positions,
+        texcoords_a: a.texcoords,
+        texcoords_b,
+    }
+}
+
+fn position_key(position: Vec3) -> [u32; 3] {
+    [
+        position.x.to_bits(),
+        position.y.to_bits(),
+        position.z.to_bits(),
+    ]
+}
+
+/// Finds the texel at the given texture coordinates in a geometry buffer that is
+/// covered by a triangle of the given island, or a covered texel of the island right
+/// next to it, since texels exactly on the edge may not be covered by the fill rule.
+pub fn seam_texel(gbuffer: &GBuffer, texcoords: Vec2, island_idx: usize) -> Option<usize> {
+    let (width, height) = (gbuffer.width() as i64, gbuffer.height() as i64);
+    let x = (texcoords.x * width as f32).floor() as i64;
+    let y = ((1.0 - texcoords.y) * height as f32).floor() as i64;
+
+    for &(dx, dy) in &[
+        (0, 0),
+        (1, 0),
+        (-1, 0),
+        (0, 1),
+        (0, -1),
+        (1, 1),
+        (-1, 1),
+        (1, -1),
+        (-1, -1),
+    ] {
+        let (x, y) = (x + dx, y + dy);
+        if x < 0 || y < 0 || x >= width || y >= height {
+            continue;
+        }
+
+        let matches = match gbuffer.texel_at(x as usize, y as usize) {
+            Some(g) => g.covered && g.island_idx == island_idx,
+            None => false,
+        };
+        if matches {
+            return Some((y * width + x) as usize);
+        }
+    }
+
+    None
+}
+
+/// Finds the pairs of corresponding texels on both sides of the given seam, as indexes
+/// in scanline order, without duplicates.
+fn seam_texel_pairs(gbuffer: &GBuffer, seam: &Seam) -> Vec<(usize, usize)> {
+    let samples = seam.sample_count(gbuffer.width(), gbuffer.height());
+    let mut pairs = Vec::new();
+
+    for sample in 0..samples {
+        let t = sample as f32 / (samples - 1).max(1) as f32;
+        let (texcoords_a, texcoords_b) = seam.texcoords_at(t);
+        let a = seam_texel(gbuffer, texcoords_a, seam.islands.0);
+        let b = seam_texel(gbuffer, texcoords_b, seam.islands.1);
+        if let (Some(a), Some(b)) = (a, b) {
+            if a != b && pairs.last() != Some(&(a, b)) {
+                pairs.push((a, b));
+            }
+        }
+    }
+
+    pairs.sort();
+    pairs.dedup();
+    pairs
+}
+
+/// Outcome of stitching a single seam with `stitch_seams`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeamStitch {
+    pub seam: Seam,
+    /// Number of texel pairs compared along the seam.
+    pub texel_pairs: usize,
+    /// Largest difference of a channel between corresponding texels before stitching,
+    /// in units of the subpixels, e.g. 0..255 for 8 bit images.
+    pub initial_mismatch: f32,
+    /// Largest difference of a channel between corresponding texels after stitching.
+    pub residual_mismatch: f32,
+}
+
+/// Makes the texels on both sides of the seams of the given islands consistent by
+/// repeatedly setting corresponding texels to their average, e.g. for images obtained
+/// from `Density` or `GuidedBlend`.
+///
+/// Texels on more than one seam, e.g. at corners, are set to the mean of the averages
+/// of all their pairs, which is why more iterations reduce the mismatch further.
+/// With zero iterations, the image is left unchanged and only the mismatch is reported.
+///
+/// The geometry buffer should be baked from the entity the islands were found in, with
+/// the same dimensions as the image.
+pub fn stitch_seams<P>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    gbuffer: &GBuffer,
+    islands: &UvIslands,
+    iterations: usize,
+) -> Vec<SeamStitch>
+where
+    P: Pixel + 'static,
+    P::Subpixel: FillSubpixel,
+{
+    assert_eq!(
+        (image.width() as usize, image.height() as usize),
+        (gbuffer.width(), gbuffer.height()),
+        "Image dimensions must match the geometry buffer"
+    );
+
+    let channels = P::channel_count() as usize;
+    let seams = find_seams(islands);
+    let seam_pairs = seams
+        .iter()
+        .map(|seam| seam_texel_pairs(gbuffer, seam))
+        .collect::<Vec<_>>();
+    let initial_mismatches = seam_pairs
+        .iter()
+        .map(|pairs| max_mismatch(image, channels, pairs))
+        .collect::<Vec<_>>();
+
+    let mut values = image
+        .iter()
+        .map(|&subpixel| subpixel.into())
+        .collect::<Vec<f32>>();
+
+    for _ in 0..iterations {
+        // Sum of the pair averages and number of pairs for each stitched texel
+        let mut targets = HashMap::new();
+        for &(a, b) in seam_pairs.iter().flat_map(|pairs| pairs.iter()) {
+            for &texel_idx in &[a, b] {
+                let target = targets
+                    .entry(texel_idx)
+                    .or_insert_with(|| (vec![0.0; channels], 0.0));
+                for c in 0..channels {
+                    target.0[c] += 0.5 * (values[a * channels + c] + values[b * channels + c]);
+                }
+                target.1 += 1.0;
+            }
+        }
+
+        for (texel_idx, (sums, count)) in targets {
+            for c in 0..channels {
+                values[texel_idx * channels + c] = sums[c] / count;
+            }
+        }
+    }
+
+    if iterations > 0 {
+        for (subpixel, &value) in image.iter_mut().zip(values.iter()) {
+            *subpixel = FillSubpixel::from_f32(value);
+        }
+    }
+
+    seams
+        .into_iter()
+        .zip(seam_pairs.iter())
+        .zip(initial_mismatches)
+        .map(|((seam, pairs), initial_mismatch)| SeamStitch {
+            seam,
+            texel_pairs: pairs.len(),
+            initial_mismatch,
+            residual_mismatch: max_mismatch(image, channels, pairs),
+        })
+        .collect()
+}
+
+/// Finds the largest difference of a channel between the given texel pairs.
+fn max_mismatch<P>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    channels: usize,
+    pairs: &[(usize, usize)],
+) -> f32
+where
+    P: Pixel + 'static,
+    P::Subpixel: FillSubpixel,
+{
+    let subpixels: &[P::Subpixel] = &**image;
+    let value = |idx: usize| -> f32 { subpixels[idx].into() };
+
+    pairs
+        .iter()
+        .flat_map(|&(a, b)| (0..channels).map(move |c| (a * channels + c, b * channels + c)))
+        .map(|(a, b)| (value(a) - value(b)).abs())
+        .fold(0.0, f32::max)
+}
+
+/// Neighborhood of each texel covered by a triangle, consisting of the adjacent covered
+/// texels in the image and the corresponding texels on the other side of seams.
+///
+/// Filters using the neighborhood behave as if the texture were continuous on the
+/// surface. Texels not covered by a triangle, including the island bleed, are left
+/// unchanged by filters and can be filled afterwards, e.g. with `jump_flood_fill`.
+#[derive(Debug, Clone)]
+pub struct SeamAdjacency {
+    width: usize,
+    height: usize,
+    covered: Vec<bool>,
+    /// Start of the seam links of each texel in `links`, with an additional entry at the end
+    offsets: Vec<u32>,
+    links: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Blur,
+    Max,
+    Min,
+}
+
+impl SeamAdjacency {
+    /// Links texels across the seams of the given islands, using the given geometry
+    /// buffer, which should be baked from the entity the islands were found in.
+    pub fn new(gbuffer: &GBuffer, islands: &UvIslands) -> Self {
+        let (width, height) = (gbuffer.width(), gbuffer.height());
+        let covered = gbuffer
+            .texels()
+            .iter()
+            .map(|g| g.as_ref().map(|g| g.covered).unwrap_or(false))
+            .collect();
+
+        let mut links = Vec::new();
+        for seam in find_seams(islands) {
+            for (a, b) in seam_texel_pairs(gbuffer, &seam) {
+                links.push((a as u32, b as u32));
+                links.push((b as u32, a as u32));
+            }
+        }
+
+        Self::from_links(width, height, covered, links)
+    }
+
+    fn from_links(
+        width: usize,
+        height: usize,
+        covered: Vec<bool>,
+        mut links: Vec<(u32, u32)>,
+    ) -> Self {
+        links.sort();
+        links.dedup();
+
+        let mut offsets = Vec::with_capacity(width * height + 1);
+        let mut link_idx = 0;
+        for texel_idx in 0..(width * height) {
+            offsets.push(link_idx as u32);
+            while link_idx < links.len() && links[link_idx].0 as usize == texel_idx {
+                link_idx += 1;
+            }
+        }
+        offsets.push(link_idx as u32);
+
+        SeamAdjacency {
+            width,
+            height,
+            covered,
+            offsets,
+            links: links.into_iter().map(|(_, to)| to).collect(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Gets the texels on the other side of a seam for the texel at the given coordinates,
+    /// as indexes in scanline order.
+    pub fn across_seam(&self, x: usize, y: usize) -> &[u32] {
+        let idx = y * self.width + x;
+        &self.links[self.offsets[idx] as usize..self.offsets[idx + 1] as usize]
+    }
+
+    /// Blurs the covered texels with a gaussian kernel of roughly the given standard
+    /// deviation in texels, continuing across seams.
+    ///
+    /// The smallest blur is a single pass of a 3x3 binomial kernel with a standard
+    /// deviation of about 0.7 texels, which is used for any positive `sigma` below that.
+    pub fn gaussian_blur<P>(&self, image: &mut ImageBuffer<P, Vec<P::Subpixel>>, sigma: f32)
+    where
+        P: Pixel + 'static,
+        P::Subpixel: FillSubpixel,
+    {
+        // Each binomial pass adds a variance of one half
+        let passes = if sigma > 0.0 {
+            ((2.0 * sigma * sigma).round() as usize).max(1)
+        } else {
+            0
+        };
+        self.apply(image, passes, Op::Blur);
+    }
+
+    /// Sets each covered texel to the per-channel maximum of the covered texels within
+    /// the given number of steps, continuing across seams.
+    pub fn dilate<P>(&self, image: &mut ImageBuffer<P, Vec<P::Subpixel>>, radius: usize)
+    where
+        P: Pixel + 'static,
+        P::Subpixel: FillSubpixel,
+    {
+        self.apply(image, radius, Op::Max);
+    }
+
+    /// Sets each covered texel to the per-channel minimum of the covered texels within
+    /// the given number of steps, continuing across seams.
+    pub fn erode<P>(&self, image: &mut ImageBuffer<P, Vec<P::Subpixel>>, radius: usize)
+    where
+        P: Pixel + 'static,
+        P::Subpixel: FillSubpixel,
+    {
+        self.apply(image, radius, Op::Min);
+    }
+
+    fn apply<P>(&self, image: &mut ImageBuffer<P, Vec<P::Subpixel>>, passes: usize, op: Op)
+    where
+        P: Pixel + 'static,
+        P::Subpixel: FillSubpixel,
+    {
+        assert_eq!(
+            (image.width() as usize, image.height() as usize),
+            (self.width, self.height),
+            "Image dimensions must match the seam adjacency"
+        );
+
+        if passes == 0 {
+            return;
+        }
+
+        let channels = P::channel_count() as usize;
+        let mut values = image
+            .iter()
+            .map(|&subpixel| subpixel.into())
+            .collect::<Vec<f32>>();
+
+        for _ in 0..passes {
+            values = self.pass(&values, channels, op);
+        }
+
+        for (subpixel, &value) in image.iter_mut().zip(values.iter()) {
+            *subpixel = FillSubpixel::from_f32(value);
+        }
+    }
+
+    fn pass(&self, values: &[f32], channels: usize, op: Op) -> Vec<f32> {
+        let mut result = values.to_vec();
+
+        result
+            .par_chunks_mut(channels)
+            .enumerate()
+            .filter(|&(idx, _)| self.covered[idx])
+            .for_each(|(idx, out)| {
+                if op == Op::Blur {
+                    for value in out.iter_mut() {
+                        *value = 0.0;
+                    }
+                }
+
+                let mut weight_sum = 0.0;
+                self.visit_neighborhood(idx, |neighbor_idx, weight| {
+                    let start = neighbor_idx * channels;
+                    let neighbor = &values[start..(start + channels)];
+                    for (value, &neighbor) in out.iter_mut().zip(neighbor) {
+                        *value = match op {
+                            Op::Blur => *value + weight * neighbor,
+                            Op::Max => value.max(neighbor),
+                            Op::Min => value.min(neighbor),
+                        };
+                    }
+                    weight_sum += weight;
+                });
+
+                if op == Op::Blur {
+                    for value in out.iter_mut() {
+                        *value /= weight_sum;
+                    }
+                }
+            });
+
+        result
+    }
+
+    /// Calls `visit` with the index and binomial weight of the given texel itself,
+    /// its covered neighbors in the image and its neighbors across seams, which are
+    /// weighted like direct neighbors.
+    fn visit_neighborhood<F>(&self, idx: usize, mut visit: F)
+    where
+        F: FnMut(usize, f32),
+    {
+        let (x, y) = ((idx % self.width) as i64, (idx / self.width) as i64);
+
+        for dy in -1..2 {
+            for dx in -1..2 {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= self.width as i64 || ny >= self.height as i64 {
+                    continue;
+                }
+
+                let neighbor_idx = ny as usize * self.width + nx as usize;
+                if self.covered[neighbor_idx] {
+                    let weight = match (dx == 0, dy == 0) {
+                        (true, true) => 4.0,
+                        (true, false) | (false, true) => 2.0,
+                        (false, false) => 1.0,
+                    };
+                    visit(neighbor_idx, weight);
+                }
+            }
+        }
+
+        let links = &self.links[self.offsets[idx] as usize..self.offsets[idx + 1] as usize];
+        for &neighbor_idx in links {
+            visit(neighbor_idx as usize, 2.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fixtures;
+    use image::Luma;
+    use scene::Entity;
+
+    /// Two separate 2x1 strips in a 5x1 image with the outer ends linked by a seam.
+    fn strips() -> SeamAdjacency {
+        let covered = vec![true, true, false, true, true];
+        SeamAdjacency::from_links(5, 1, covered, vec![(0, 4), (4, 0)])
+    }
+
+    #[test]
+    fn links_both_ways() {
+        let adjacency = strips();
+        assert_eq!(adjacency.across_seam(0, 0), &[4]);
+        assert_eq!(adjacency.across_seam(4, 0), &[0]);
+        assert!(adjacency.across_seam(1, 0).is_empty());
+    }
+
+    #[test]
+    fn dilation_crosses_seams() {
+        let adjacency = strips();
+        let mut image = ImageBuffer::from_raw(5, 1, vec![0_u8, 0, 7, 0, 200]).unwrap();
+
+        adjacency.dilate::<Luma<u8>>(&mut image, 1);
+        assert_eq!(image.into_raw(), vec![200, 0, 7, 200, 200]);
+    }
+
+    #[test]
+    fn blur_keeps_uncovered() {
+        let adjacency = strips();
+        let mut image = ImageBuffer::from_raw(5, 1, vec![1.0_f32, 1.0, 9.0, 0.0, 0.0]).unwrap();
+
+        adjacency.gaussian_blur::<Luma<f32>>(&mut image, 0.5);
+        let blurred = image.into_raw();
+        assert_eq!(blurred[2], 9.0);
+        // Texel 0 has itself, texel 1 and texel 4 across the seam as neighbors
+        assert_ulps_eq!(blurred[0], (4.0 + 2.0) / 8.0);
+        assert_ulps_eq!(blurred[4], 2.0 / 8.0);
+    }
+
+    #[test]
+    fn small_sigma_still_blurs() {
+        let adjacency = strips();
+        let mut image = ImageBuffer::from_raw(5, 1, vec![1.0_f32, 1.0, 9.0, 0.0, 0.0]).unwrap();
+
+        adjacency.gaussian_blur::<Luma<f32>>(&mut image, 0.1);
+        assert_ulps_eq!(image.into_raw()[0], (4.0 + 2.0) / 8.0);
+
+        let mut image = ImageBuffer::from_raw(5, 1, vec![1.0_f32, 1.0, 9.0, 0.0, 0.0]).unwrap();
+        adjacency.gaussian_blur::<Luma<f32>>(&mut image, 0.0);
+        assert_eq!(image.into_raw(), vec![1.0, 1.0, 9.0, 0.0, 0.0]);
+    }
+
+    /// A unit square made of two triangles whose UV triangles are in the left and right
+    /// half of the texture, cut apart along the diagonal of the square.
+    fn cut_square() -> Entity {
+        fixtures::entity(&[
+            [
+                ([0.0, 0.0, 0.0], [0.1, 0.1]),
+                ([1.0, 0.0, 0.0], [0.4, 0.1]),
+                ([1.0, 1.0, 0.0], [0.4, 0.4]),
+            ],
+            [
+                ([0.0, 0.0, 0.0], [0.6, 0.1]),
+                ([1.0, 1.0, 0.0], [0.9, 0.4]),
+                ([0.0, 1.0, 0.0], [0.6, 0.4]),
+            ],
+        ])
+    }
+
+    #[test]
+    fn seam_along_cut_edge() {
+        let islands = UvIslands::new(&cut_square());
+        let seams = find_seams(&islands);
+
+        // Only the diagonal is shared, the other edges are on the border of the mesh
+        assert_eq!(seams.len(), 1);
+        let seam = seams[0];
+        assert_eq!(seam.triangles, (0, 1));
+        assert_eq!(seam.islands, (0, 1));
+        assert_eq!(
+            seam.positions,
+            (Vec3::new(1.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 0.0))
+        );
+        assert_eq!(seam.texcoords_a, (Vec2::new(0.4, 0.4), Vec2::new(0.1, 0.1)));
+        // Oriented to start at the same vertex as the first side
+        assert_eq!(seam.texcoords_b, (Vec2::new(0.9, 0.4), Vec2::new(0.6, 0.1)));
+    }
+
+    #[test]
+    fn stitching_only_changes_seam_texels() {
+        let entity = cut_square();
+        let islands = UvIslands::new(&entity);
+        let gbuffer = GBuffer::bake(&entity, 20, 20, 0);
+        // Zero on the left island, one on the right island, in between elsewhere
+        let original = ImageBuffer::from_fn(20, 20, |x, y| Luma {
+            data: [match gbuffer.texel_at(x as usize, y as usize) {
+                Some(g) if g.covered => g.island_idx as f32,
+                _ => 0.5_f32,
+            }],
+        });
+        let seam_texels = find_seams(&islands)
+            .iter()
+            .flat_map(|seam| seam_texel_pairs(&gbuffer, seam))
+            .flat_map(|(a, b)| vec![a, b])
+            .collect::<Vec<_>>();
+
+        let mut image = original.clone();
+        let stitches = stitch_seams(&mut image, &gbuffer, &islands, 2);
+
+        assert_eq!(stitches.len(), 1);
+        assert!(stitches[0].texel_pairs > 0);
+        assert_ulps_eq!(stitches[0].initial_mismatch, 1.0);
+        assert!(stitches[0].residual_mismatch < stitches[0].initial_mismatch);
+        for (idx, (stitched, before)) in image.pixels().zip(original.pixels()).enumerate() {
+            if !seam_texels.contains(&idx) {
+                assert_eq!(stitched, before);
+            }
+        }
+    }
+}
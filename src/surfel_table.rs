@@ -1,9 +1,192 @@
-use geom::{Normal, Position};
-use geom_tex::{geom_tex, GeomTexel};
+use geom::{Normal, Position, Vec3, Vertex};
+use geom_tex::{BakeOptions, GBuffer, GBufferBaker};
+use lookup_table::{source_hash, SurfelLookupTable, TableSettings};
 use rayon::prelude::*;
 use scene::Entity;
-use std::f32::EPSILON;
-use surf::Surface;
+use sim::SurfelData;
+use std::f32::{EPSILON, INFINITY};
+use std::ops::Range;
+use std::usize;
+use surf::{Surface, Surfel};
+
+// Given the normals of a texel and a surfel, cos(theta) must be larger than this
+// to be taken into account.
+// This avoids the back side of a thin surface to influence the front side and vice-versa.
+// for cos(theta) = f32::EPSILON, rotations up to almost theta = 90° are allowed
+const ANGLE_COS_THRESHOLD: f32 = EPSILON;
+
+/// Number of lines to gather surfels for in parallel before adding them to the table
+const BAND_HEIGHT: usize = 64;
+
+/// Maximum number of nearest surfels queried for a single texel position.
+///
+/// When only some surfels are accepted, e.g. with an entity filter, more nearest surfels
+/// than requested are queried to find enough accepted ones. Without a limit, texels far
+/// from any accepted surfel would query the whole surface. With the limit, such texels
+/// get fewer surfels than requested or none at all, and no more than this number of
+/// surfels are gathered for any position.
+pub const MAX_QUERY_COUNT: usize = 4096;
+
+/// Specifies which surfels are gathered for a texel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gather {
+    /// Gathers the given number of nearest surfels, no matter how far away they are.
+    Nearest(usize),
+    /// Gathers up to the given number of nearest surfels, ignoring surfels farther away
+    /// than the given world-space radius.
+    NearestWithin { count: usize, radius: f32 },
+    /// Gathers all surfels within the given world-space radius, but no more than
+    /// `MAX_QUERY_COUNT` of them.
+    Within(f32),
+}
+
+impl Default for Gather {
+    fn default() -> Self {
+        Gather::Nearest(4)
+    }
+}
+
+impl Gather {
+    /// Finds the surfels to use for a texel with the given world-space position and normal,
+    /// returning squared distances and surfel indexes sorted by distance.
+    ///
+    /// Only surfels for which `accept` returns `true` are gathered. If no surfels are in
+    /// range, the returned vector is empty. See `MAX_QUERY_COUNT` for the limit on the
+    /// surfels considered.
+    pub fn gather<S, F>(
+        &self,
+        surf: &Surface<S>,
+        position: Vec3,
+        normal: Vec3,
+        accept: F,
+    ) -> Vec<(f32, usize)>
+    where
+        S: Position + Normal,
+        F: Fn(&S) -> bool,
+    {
+        self.gather_from(
+            surf.samples.len(),
+            |n| surf.nearest_n_indexes_oriented(position, normal, ANGLE_COS_THRESHOLD, n),
+            |surfel_idx| accept(&surf.samples[surfel_idx]),
+        )
+    }
+
+    /// Like `gather`, but obtains the `n` nearest of `surfel_count` surfels, sorted by
+    /// distance, from `nearest_n`.
+    fn gather_from<Q, F>(&self, surfel_count: usize, nearest_n: Q, accept: F) -> Vec<(f32, usize)>
+    where
+        Q: Fn(usize) -> Vec<(f32, usize)>,
+        F: Fn(usize) -> bool,
+    {
+        match *self {
+            Gather::Nearest(count) => {
+                nearest_accepted(surfel_count, nearest_n, count, INFINITY, accept)
+            }
+            Gather::NearestWithin { count, radius } => {
+                nearest_accepted(surfel_count, nearest_n, count, radius * radius, accept)
+            }
+            Gather::Within(radius) => {
+                nearest_accepted(surfel_count, nearest_n, usize::MAX, radius * radius, accept)
+            }
+        }
+    }
+
+    /// Maximum number of surfels gathered for a texel.
+    fn max_count(&self) -> usize {
+        match *self {
+            Gather::Nearest(count) | Gather::NearestWithin { count, .. } => count,
+            Gather::Within(_) => MAX_QUERY_COUNT,
+        }
+    }
+}
+
+/// Specifies the entities whose surfels may influence a texel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntityFilter {
+    /// Gathers surfels regardless of entity.
+    All,
+    /// Only gathers surfels with the given entity index, which should be the index of
+    /// the entity the texture is created for.
+    Own(usize),
+    /// Only gathers surfels with an entity index contained in the list.
+    Entities(Vec<usize>),
+}
+
+impl Default for EntityFilter {
+    fn default() -> Self {
+        EntityFilter::All
+    }
+}
+
+impl EntityFilter {
+    /// Checks if surfels with the given entity index pass the filter.
+    pub fn accepts(&self, entity_idx: usize) -> bool {
+        match *self {
+            EntityFilter::All => true,
+            EntityFilter::Own(own_idx) => own_idx == entity_idx,
+            EntityFilter::Entities(ref entities) => entities.contains(&entity_idx),
+        }
+    }
+}
+
+/// Surfels that know the index of the entity they were sampled from, which is required
+/// to restrict gathered surfels with an `EntityFilter`.
+pub trait EntityIdx {
+    fn entity_idx(&self) -> usize;
+}
+
+impl EntityIdx for Surfel<Vertex, SurfelData> {
+    fn entity_idx(&self) -> usize {
+        self.data().entity_idx
+    }
+}
+
+/// Finds up to `count` surfels accepted by `accept` within the given squared radius.
+///
+/// Since `nearest_n` can only report the nearest surfels regardless of `accept`,
+/// the number of queried nearest surfels is doubled until enough surfels are accepted,
+/// one of them is out of range, all surfels have been queried, or `MAX_QUERY_COUNT`
+/// surfels have been queried.
+fn nearest_accepted<Q, F>(
+    surfel_count: usize,
+    nearest_n: Q,
+    count: usize,
+    radius_sqr: f32,
+    accept: F,
+) -> Vec<(f32, usize)>
+where
+    Q: Fn(usize) -> Vec<(f32, usize)>,
+    F: Fn(usize) -> bool,
+{
+    if count == 0 {
+        return Vec::new();
+    }
+
+    // For unbounded counts, start with a few surfels
+    let initial_count = if count == usize::MAX { 8 } else { count };
+    let mut query_count = initial_count.min(MAX_QUERY_COUNT);
+
+    loop {
+        let nearest = nearest_n(query_count);
+
+        let exhausted = nearest.len() < query_count || query_count >= surfel_count;
+        let out_of_range = nearest.iter().any(|&(dist_sqr, _)| dist_sqr > radius_sqr);
+        let at_limit = query_count >= MAX_QUERY_COUNT;
+
+        let mut accepted = nearest
+            .into_iter()
+            .filter(|&(dist_sqr, surfel_idx)| dist_sqr <= radius_sqr && accept(surfel_idx))
+            .collect::<Vec<_>>();
+
+        if exhausted || out_of_range || at_limit || accepted.len() >= count {
+            accepted.sort_by(|&(a, _), &(b, _)| a.partial_cmp(&b).unwrap());
+            accepted.truncate(count);
+            return accepted;
+        }
+
+        query_count = query_count.saturating_mul(2).min(MAX_QUERY_COUNT);
+    }
+}
 
 pub fn build_surfel_lookup_table<S>(
     entity: &Entity,
@@ -12,38 +195,395 @@ pub fn build_surfel_lookup_table<S>(
     width: usize,
     height: usize,
     island_bleed: usize,
-) -> Vec<Vec<(f32, usize)>>
+) -> SurfelLookupTable
 where
     S: Position + Normal,
     Surface<S>: Sync,
 {
-    let geom_texels = geom_tex(entity, width, height, island_bleed);
-
-    // Given the normals of a texel and a surfel, cos(theta) must be larger than this
-    // to be taken into account.
-    // This avoids the back side of a thin surface to influence the front side and vice-versa.
-    // for cos(theta) = f32::EPSILON, rotations up to almost theta = 90° are allowed
-    const ANGLE_COS_THRESHOLD: f32 = EPSILON;
-
-    geom_texels
-        .par_iter()
-        .map(|g| {
-            g.as_ref()
-                .map(
-                    |&GeomTexel {
-                         position,
-                         normal: texel_normal,
-                     }| {
-                         // FIXME bleeding from other entities may not always be wanted
-                        surf.nearest_n_indexes_oriented(
-                            position,
-                            texel_normal,
-                            ANGLE_COS_THRESHOLD,
-                            surfel_count,
-                        )
-                    },
-                )
-                .unwrap_or_else(Vec::new)
+    let options = BakeOptions::default();
+    build_table_in_bands(
+        entity,
+        &GBufferBaker::new(entity, width, height, island_bleed, &options),
+        surf,
+        Gather::Nearest(surfel_count),
+        EntityFilter::All,
+        |_| true,
+    )
+}
+
+/// Builds a table holding the surfels for each texel as specified by `gather`,
+/// only considering surfels of entities accepted by `entity_filter`.
+///
+/// Texels not covered by the entity or without surfels in range have no surfels.
+pub fn build_surfel_lookup_table_with_gather<S>(
+    entity: &Entity,
+    surf: &Surface<S>,
+    gather: Gather,
+    entity_filter: &EntityFilter,
+    width: usize,
+    height: usize,
+    island_bleed: usize,
+) -> SurfelLookupTable
+where
+    S: Position + Normal + EntityIdx,
+    Surface<S>: Sync,
+{
+    let options = BakeOptions::default();
+    build_surfel_lookup_table_with_baker(
+        entity,
+        &GBufferBaker::new(entity, width, height, island_bleed, &options),
+        surf,
+        gather,
+        entity_filter,
+    )
+}
+
+/// Builds a table holding the surfels for each texel of the given geometry buffer,
+/// which should be baked from the given entity.
+///
+/// For texels with more than one selected sample, due to supersampling or the overlap
+/// policy of the buffer, surfels are gathered at each of the selected positions and
+/// merged, keeping the smallest distance for surfels found more than once. Each surfel
+/// is weighted with the fraction of the samples it was gathered at, and no more surfels
+/// than a single gather would find are kept, preferring surfels with higher weight.
+///
+/// The table records the options the buffer was baked with, along with the gather mode
+/// and entity filter.
+pub fn build_surfel_lookup_table_with_gbuffer<S>(
+    entity: &Entity,
+    gbuffer: &GBuffer,
+    surf: &Surface<S>,
+    gather: Gather,
+    entity_filter: &EntityFilter,
+) -> SurfelLookupTable
+where
+    S: Position + Normal + EntityIdx,
+    Surface<S>: Sync,
+{
+    let width = gbuffer.width();
+    let settings = TableSettings {
+        entity_filter: entity_filter.clone(),
+        ..TableSettings::new(width, gbuffer.height(), gbuffer.island_bleed(), gather)
+            .with_bake_options(gbuffer.bake_options())
+    };
+    let accept = |surfel: &S| entity_filter.accepts(surfel.entity_idx());
+
+    build_table(entity, surf, settings, |lines| {
+        let texels = (lines.start * width)..(lines.end * width);
+        gather_texels(gbuffer, texels, surf, gather, &accept)
+    })
+}
+
+/// Builds a table like `build_surfel_lookup_table_with_gbuffer`, but only bakes a band
+/// of lines of the geometry buffer at a time, so the whole buffer is never held in memory.
+pub fn build_surfel_lookup_table_with_baker<S>(
+    entity: &Entity,
+    baker: &GBufferBaker,
+    surf: &Surface<S>,
+    gather: Gather,
+    entity_filter: &EntityFilter,
+) -> SurfelLookupTable
+where
+    S: Position + Normal + EntityIdx,
+    Surface<S>: Sync,
+{
+    build_table_in_bands(
+        entity,
+        baker,
+        surf,
+        gather,
+        entity_filter.clone(),
+        |surfel: &S| entity_filter.accepts(surfel.entity_idx()),
+    )
+}
+
+/// Builds a table baking one band at a time with the given baker, only considering
+/// surfels for which `accept` returns `true`, which should match the recorded entity filter.
+fn build_table_in_bands<S, F>(
+    entity: &Entity,
+    baker: &GBufferBaker,
+    surf: &Surface<S>,
+    gather: Gather,
+    entity_filter: EntityFilter,
+    accept: F,
+) -> SurfelLookupTable
+where
+    S: Position + Normal,
+    Surface<S>: Sync,
+    F: Fn(&S) -> bool + Sync,
+{
+    let settings = TableSettings {
+        entity_filter,
+        ..TableSettings::new(baker.width(), baker.height(), baker.island_bleed(), gather)
+            .with_bake_options(baker.options())
+    };
+
+    build_table(entity, surf, settings, |lines| {
+        let band = baker.bake_lines(lines);
+        let texels = 0..(band.width() * band.height());
+        gather_texels(&band, texels, surf, gather, &accept)
+    })
+}
+
+/// Builds a table with the given settings, obtaining the gathered surfels of all texels
+/// in a range of lines from `gather_lines`.
+fn build_table<S, B>(
+    entity: &Entity,
+    surf: &Surface<S>,
+    settings: TableSettings,
+    mut gather_lines: B,
+) -> SurfelLookupTable
+where
+    S: Position + Normal,
+    B: FnMut(Range<usize>) -> Vec<GatheredTexel>,
+{
+    let height = settings.height;
+    let mut table = SurfelLookupTable::new(settings, source_hash(entity, surf));
+
+    // Gather in parallel, but only for a band of lines at a time, so the surfels
+    // of all texels never have to be held in memory before they are compacted
+    for band_start in (0..height).step_by(BAND_HEIGHT) {
+        let band_end = (band_start + BAND_HEIGHT).min(height);
+        for texel in gather_lines(band_start..band_end) {
+            table.push_weighted_texel(&texel.surfels, &texel.weights);
+        }
+    }
+
+    table
+}
+
+/// Gathers the surfels of the given range of texel indexes of the geometry buffer in
+/// parallel, merging the surfels of all samples selected for a texel.
+fn gather_texels<S, F>(
+    gbuffer: &GBuffer,
+    texels: Range<usize>,
+    surf: &Surface<S>,
+    gather: Gather,
+    accept: &F,
+) -> Vec<GatheredTexel>
+where
+    S: Position + Normal,
+    Surface<S>: Sync,
+    F: Fn(&S) -> bool + Sync,
+{
+    texels
+        .into_par_iter()
+        .map(|idx| {
+            let samples = gbuffer
+                .selected(idx)
+                .iter()
+                .map(|g| (g.weight, gather.gather(surf, g.position, g.normal, accept)))
+                .collect();
+            merge_gathered(samples, gather.max_count())
         })
         .collect()
 }
+
+/// Surfels gathered for a texel, sorted by distance, along with their weight in the texel.
+struct GatheredTexel {
+    surfels: Vec<(f32, usize)>,
+    weights: Vec<f32>,
+}
+
+/// Merges the surfels gathered at the samples of a texel, given along with the weight
+/// of each sample. Surfels found at more than one sample keep the smaller distance.
+///
+/// Each surfel is weighted with the summed weight of the samples it was found at, relative
+/// to the weight of all samples. If more than `max_count` surfels were found, the surfels
+/// with the highest weight are kept, preferring closer ones for equal weights.
+fn merge_gathered(samples: Vec<(f32, Vec<(f32, usize)>)>, max_count: usize) -> GatheredTexel {
+    let total_weight = samples.iter().map(|&(weight, _)| weight).sum::<f32>();
+
+    // Squared distance, surfel index and summed sample weight of each surfel
+    let mut merged: Vec<(f32, usize, f32)> = Vec::new();
+    for (sample_weight, nearby) in samples {
+        for (dist_sqr, surfel_idx) in nearby {
+            match merged.iter_mut().find(|entry| entry.1 == surfel_idx) {
+                Some(existing) => {
+                    existing.0 = existing.0.min(dist_sqr);
+                    existing.2 += sample_weight;
+                }
+                None => merged.push((dist_sqr, surfel_idx, sample_weight)),
+            }
+        }
+    }
+
+    if merged.len() > max_count {
+        merged.sort_by(|a, b| {
+            b.2.partial_cmp(&a.2)
+                .unwrap()
+                .then(a.0.partial_cmp(&b.0).unwrap())
+        });
+        merged.truncate(max_count);
+    }
+    merged.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    GatheredTexel {
+        surfels: merged
+            .iter()
+            .map(|&(dist_sqr, surfel_idx, _)| (dist_sqr, surfel_idx))
+            .collect(),
+        weights: merged
+            .iter()
+            .map(|&(_, _, weight)| weight / total_weight)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reconstruction::{Reconstruction, SubstanceFilter};
+    use std::cell::Cell;
+
+    #[test]
+    fn entity_filter_accepts() {
+        assert!(EntityFilter::All.accepts(3));
+        assert!(EntityFilter::Own(3).accepts(3));
+        assert!(!EntityFilter::Own(3).accepts(2));
+        assert!(EntityFilter::Entities(vec![0, 2]).accepts(2));
+        assert!(!EntityFilter::Entities(vec![0, 2]).accepts(1));
+    }
+
+    /// Surfels on the x axis at x = 0, 1, 2..., the first ten belonging to entity 0 and
+    /// the others to entity 1.
+    fn entity_of(surfel_idx: usize) -> usize {
+        if surfel_idx < 10 {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// Brute-force nearest surfels to the origin among surfels on the x axis.
+    fn nearest_on_line(surfel_count: usize, n: usize) -> Vec<(f32, usize)> {
+        (0..surfel_count.min(n))
+            .map(|idx| ((idx * idx) as f32, idx))
+            .collect()
+    }
+
+    fn gather_on_line(
+        gather: Gather,
+        surfel_count: usize,
+        accept: impl Fn(usize) -> bool,
+    ) -> Vec<(f32, usize)> {
+        gather.gather_from(surfel_count, |n| nearest_on_line(surfel_count, n), accept)
+    }
+
+    #[test]
+    fn gather_nearest() {
+        let gathered = gather_on_line(Gather::Nearest(3), 20, |_| true);
+        assert_eq!(gathered, vec![(0.0, 0), (1.0, 1), (4.0, 2)]);
+
+        let gathered = gather_on_line(Gather::Nearest(3), 2, |_| true);
+        assert_eq!(gathered, vec![(0.0, 0), (1.0, 1)]);
+    }
+
+    #[test]
+    fn gather_nearest_within() {
+        let gather = Gather::NearestWithin {
+            count: 3,
+            radius: 1.5,
+        };
+        assert_eq!(
+            gather_on_line(gather, 20, |_| true),
+            vec![(0.0, 0), (1.0, 1)]
+        );
+
+        let gather = Gather::NearestWithin {
+            count: 2,
+            radius: 5.0,
+        };
+        assert_eq!(
+            gather_on_line(gather, 20, |_| true),
+            vec![(0.0, 0), (1.0, 1)]
+        );
+    }
+
+    #[test]
+    fn gather_within() {
+        let gathered = gather_on_line(Gather::Within(2.0), 20, |_| true);
+        assert_eq!(gathered, vec![(0.0, 0), (1.0, 1), (4.0, 2)]);
+
+        // More than the initial query count of eight surfels
+        let gathered = gather_on_line(Gather::Within(11.0), 20, |_| true);
+        assert_eq!(gathered.len(), 12);
+    }
+
+    #[test]
+    fn gather_filtered_entity_beyond_nearest() {
+        let own_entity = EntityFilter::Own(1);
+        let gathered = gather_on_line(Gather::Nearest(2), 20, |idx| {
+            own_entity.accepts(entity_of(idx))
+        });
+        assert_eq!(gathered, vec![(100.0, 10), (121.0, 11)]);
+
+        let gather = Gather::NearestWithin {
+            count: 2,
+            radius: 5.0,
+        };
+        let gathered = gather_on_line(gather, 20, |idx| own_entity.accepts(entity_of(idx)));
+        assert!(gathered.is_empty());
+    }
+
+    #[test]
+    fn gather_queries_limited() {
+        let surfel_count = 4 * MAX_QUERY_COUNT;
+        let max_queried = Cell::new(0);
+        let nearest_n = |n| {
+            max_queried.set(max_queried.get().max(n));
+            nearest_on_line(surfel_count, n)
+        };
+
+        // Only the most distant surfel is accepted, but never found
+        let gathered =
+            Gather::Nearest(1).gather_from(surfel_count, nearest_n, |idx| idx == surfel_count - 1);
+
+        assert!(gathered.is_empty());
+        assert_eq!(max_queried.get(), MAX_QUERY_COUNT);
+    }
+
+    #[test]
+    fn merge_keeps_closest() {
+        let samples = vec![
+            (0.5, vec![(1.0, 3), (4.0, 5)]),
+            (0.5, vec![(0.5, 5), (2.0, 3), (3.0, 7)]),
+        ];
+        let merged = merge_gathered(samples, 4);
+        assert_eq!(merged.surfels, vec![(0.5, 5), (1.0, 3), (3.0, 7)]);
+        assert_eq!(merged.weights, vec![1.0, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn merge_keeps_highest_weights() {
+        // Surfel 7 is the closest, but only found at one of four samples
+        let samples = vec![
+            (0.25, vec![(0.5, 7), (1.0, 3)]),
+            (0.25, vec![(1.0, 3), (2.0, 5)]),
+            (0.25, vec![(1.0, 3), (2.0, 5)]),
+            (0.25, vec![(1.0, 3), (2.0, 4)]),
+        ];
+        let merged = merge_gathered(samples, 2);
+        assert_eq!(merged.surfels, vec![(1.0, 3), (2.0, 5)]);
+        assert_eq!(merged.weights, vec![1.0, 0.5]);
+
+        // Equal weights prefer closer surfels
+        let samples = vec![(0.5, vec![(2.0, 4), (3.0, 6)]), (0.5, vec![(1.0, 5)])];
+        assert_eq!(merge_gathered(samples, 1).surfels, vec![(1.0, 5)]);
+    }
+
+    #[test]
+    fn partly_gathered_surfels_weigh_less() {
+        let samples = vec![(0.25, vec![(1.0, 0)]), (0.25, vec![(1.0, 0), (1.0, 1)])];
+        let merged = merge_gathered(samples, 4);
+        let mut table = SurfelLookupTable::new(TableSettings::new(1, 1, 0, Gather::Nearest(4)), 0);
+        table.push_weighted_texel(&merged.surfels, &merged.weights);
+
+        // Unweighted, the average of 0 and 3 would be 1.5
+        let values = [0.0, 3.0];
+        let flat = SubstanceFilter::Flat
+            .reconstruct(table.texel(0), &|idx| values[idx])
+            .unwrap();
+        assert_abs_diff_eq!(flat, 1.0, epsilon = 1e-4);
+    }
+}
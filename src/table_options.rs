@@ -0,0 +1,294 @@
+//!
+//! Provides the settings shared by all collectors that build surfel lookup tables.
+//!
+
+use density::Surface;
+use geom_tex::{BakeOptions, GBufferBaker, OverlapPolicy, Supersampling};
+use image::{ImageBuffer, Pixel};
+use lookup_table::{SurfelLookupTable, TableSettings};
+use parallel::{install, par_from_fn};
+use raster::RasterMode;
+use rayon::ThreadPool;
+use scene::Entity;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use surfel_table::{build_surfel_lookup_table_with_baker, EntityFilter, Gather};
+
+/// Texture dimensions and settings for rasterizing an entity and gathering surfels,
+/// shared by `Density` and `PackedDensity`.
+#[derive(Debug, Clone)]
+pub struct TableOptions {
+    tex_width: usize,
+    tex_height: usize,
+    island_bleed: usize,
+    /// Specifies the surfels to gather for each texel
+    gather: Gather,
+    /// Specifies the entities whose surfels may influence the texture
+    entity_filter: EntityFilter,
+    /// Specifies overlap handling and supersampling for rasterizing the entity
+    bake_options: BakeOptions,
+    /// Thread pool to use for building tables and collecting texels, global pool if `None`
+    thread_pool: Option<Arc<ThreadPool>>,
+}
+
+impl TableOptions {
+    /// Creates options for a texture with the given dimensions, drawing margins with the
+    /// given thickness around UV islands, gathering the four nearest surfels of any entity.
+    pub fn new(tex_width: usize, tex_height: usize, island_bleed: usize) -> Self {
+        TableOptions {
+            tex_width,
+            tex_height,
+            island_bleed,
+            gather: Gather::default(),
+            entity_filter: EntityFilter::default(),
+            bake_options: BakeOptions::default(),
+            thread_pool: None,
+        }
+    }
+
+    /// Changes the surfels gathered for each texel, which defaults to the four
+    /// nearest surfels.
+    pub fn with_gather(self, gather: Gather) -> Self {
+        Self { gather, ..self }
+    }
+
+    /// Restricts the entities whose surfels may influence the texture, which defaults
+    /// to surfels of all entities.
+    pub fn with_entity_filter(self, entity_filter: EntityFilter) -> Self {
+        Self {
+            entity_filter,
+            ..self
+        }
+    }
+
+    /// Changes how texels covered by more than one triangle are handled, which defaults
+    /// to using the last triangle.
+    pub fn with_overlap_policy(self, overlap_policy: OverlapPolicy) -> Self {
+        Self {
+            bake_options: BakeOptions {
+                overlap_policy,
+                ..self.bake_options.clone()
+            },
+            ..self
+        }
+    }
+
+    /// Samples the geometry at multiple positions per texel, gathering surfels at all of
+    /// them, which avoids holes for geometry thinner than a texel. Defaults to a single
+    /// sample per texel.
+    pub fn with_supersampling(self, supersampling: Supersampling) -> Self {
+        Self {
+            bake_options: BakeOptions {
+                supersampling,
+                ..self.bake_options.clone()
+            },
+            ..self
+        }
+    }
+
+    /// Changes how triangles are rasterized into texels, which defaults to exact
+    /// rasterization without clamping, see `BakeOptions`.
+    pub fn with_raster_mode(self, raster_mode: RasterMode, clamp_interpolation: bool) -> Self {
+        Self {
+            bake_options: BakeOptions {
+                raster_mode,
+                clamp_interpolation,
+                ..self.bake_options.clone()
+            },
+            ..self
+        }
+    }
+
+    /// Uses the given thread pool instead of the global rayon thread pool for building
+    /// tables and collecting texels, e.g. to leave threads to a simulation running alongside.
+    pub fn with_thread_pool(self, thread_pool: Arc<ThreadPool>) -> Self {
+        Self {
+            thread_pool: Some(thread_pool),
+            ..self
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.tex_width
+    }
+
+    pub fn height(&self) -> usize {
+        self.tex_height
+    }
+
+    pub fn island_bleed(&self) -> usize {
+        self.island_bleed
+    }
+
+    pub fn gather(&self) -> Gather {
+        self.gather
+    }
+
+    pub fn entity_filter(&self) -> &EntityFilter {
+        &self.entity_filter
+    }
+
+    pub fn bake_options(&self) -> &BakeOptions {
+        &self.bake_options
+    }
+
+    /// Gets the settings recorded in tables built with these options.
+    pub fn settings(&self) -> TableSettings {
+        TableSettings {
+            entity_filter: self.entity_filter.clone(),
+            ..TableSettings::new(self.tex_width, self.tex_height, self.island_bleed, self.gather)
+                .with_bake_options(&self.bake_options)
+        }
+    }
+
+    /// Builds a table for the given entity with these options, rasterizing the entity
+    /// one band of lines at a time.
+    pub fn build_table(&self, entity: &Entity, surf: &Surface) -> SurfelLookupTable {
+        self.install(|| {
+            let baker = GBufferBaker::new(
+                entity,
+                self.tex_width,
+                self.tex_height,
+                self.island_bleed,
+                &self.bake_options,
+            );
+
+            build_surfel_lookup_table_with_baker(
+                entity,
+                &baker,
+                surf,
+                self.gather,
+                &self.entity_filter,
+            )
+        })
+    }
+
+    /// Loads a table previously saved with `SurfelLookupTable::save`.
+    ///
+    /// # Errors
+    /// Fails if the table cannot be read or if it was built from a different mesh or
+    /// surfels. Fails with `ErrorKind::InvalidData` if any of these settings of the
+    /// table differ from these options:
+    /// * texture width and height,
+    /// * island bleed,
+    /// * gather mode,
+    /// * entity filter,
+    /// * kind of overlap policy, where all `Priority` policies count as the same,
+    /// * supersampling,
+    /// * raster mode,
+    /// * clamping of interpolation.
+    pub fn load_table<P: AsRef<Path>>(
+        &self,
+        path: P,
+        entity: &Entity,
+        surf: &Surface,
+    ) -> io::Result<SurfelLookupTable> {
+        self.check_settings(SurfelLookupTable::load(path, entity, surf)?)
+    }
+
+    /// Passes through the given table if it was built with these options, see `load_table`.
+    fn check_settings(&self, table: SurfelLookupTable) -> io::Result<SurfelLookupTable> {
+        let settings = self.settings();
+        if *table.settings() != settings {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Surfel lookup table was built with {:?}, but expected {:?}",
+                    table.settings(),
+                    settings
+                ),
+            ));
+        }
+
+        Ok(table)
+    }
+
+    /// Runs the given operation in the thread pool of these options.
+    pub fn install<R, F>(&self, op: F) -> R
+    where
+        R: Send,
+        F: FnOnce() -> R + Send,
+    {
+        install(&self.thread_pool, op)
+    }
+
+    /// Evaluates the given function for each texel in parallel.
+    pub fn image_from_fn<P, F>(&self, pixel_at: F) -> ImageBuffer<P, Vec<P::Subpixel>>
+    where
+        P: Pixel + 'static,
+        P::Subpixel: Send + 'static,
+        F: Fn(u32, u32) -> P + Sync,
+    {
+        let (width, height) = (self.tex_width as u32, self.tex_height as u32);
+        self.install(|| par_from_fn(width, height, &pixel_at))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use geom_tex::OverlapKind;
+
+    fn table(settings: TableSettings) -> SurfelLookupTable {
+        let texels = vec![vec![(1.0, 0)], vec![]];
+        SurfelLookupTable::from_texels(settings, 0, texels)
+    }
+
+    #[test]
+    fn settings_of_default_options() {
+        let settings = TableSettings::new(2, 1, 3, Gather::default());
+        assert_eq!(TableOptions::new(2, 1, 3).settings(), settings);
+        assert!(TableOptions::new(2, 1, 3)
+            .check_settings(table(settings))
+            .is_ok());
+    }
+
+    #[test]
+    fn tables_with_other_settings_rejected() {
+        let options = TableOptions::new(2, 1, 3)
+            .with_gather(Gather::Within(0.5))
+            .with_entity_filter(EntityFilter::Own(2))
+            .with_overlap_policy(OverlapPolicy::Average)
+            .with_supersampling(Supersampling::Grid(2))
+            .with_raster_mode(RasterMode::Conservative, true);
+        let settings = options.settings();
+        assert!(options.check_settings(table(settings.clone())).is_ok());
+
+        let other_settings = vec![
+            TableSettings {
+                island_bleed: 2,
+                ..settings.clone()
+            },
+            TableSettings {
+                gather: Gather::Within(0.25),
+                ..settings.clone()
+            },
+            TableSettings {
+                entity_filter: EntityFilter::All,
+                ..settings.clone()
+            },
+            TableSettings {
+                overlap: OverlapKind::KeepLast,
+                ..settings.clone()
+            },
+            TableSettings {
+                supersampling: Supersampling::RotatedGrid(2),
+                ..settings.clone()
+            },
+            TableSettings {
+                raster_mode: RasterMode::Exact,
+                ..settings.clone()
+            },
+            TableSettings {
+                clamp_interpolation: false,
+                ..settings.clone()
+            },
+        ];
+
+        for other in other_settings {
+            let err = options.check_settings(table(other)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
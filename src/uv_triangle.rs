@@ -23,6 +23,7 @@ where
         (texcoord0, texcoord1, texcoord2),
         (worldpos0, worldpos1, worldpos2),
         (worldnormal0, worldnormal1, worldnormal2),
+        (corner0, corner1, corner2),
     ) = {
         let (v0, v1, v2) = tri.vertices();
 
@@ -37,10 +38,10 @@ where
         let normals = (v0.normal(), v1.normal(), v2.normal());
 
         if is_ccw(&texcoords) {
-            (texcoords, positions, normals)
+            (texcoords, positions, normals, (0, 1, 2))
         } else {
             // Flip order if would be pointing downwards in uv space
-            (flip(texcoords), flip(positions), flip(normals))
+            (flip(texcoords), flip(positions), flip(normals), flip((0, 1, 2)))
         }
     };
 
@@ -49,16 +50,19 @@ where
             uv_position: texcoord0,
             world_position: worldpos0,
             world_normal: worldnormal0,
+            corner: corner0,
         },
         UvVtx {
             uv_position: texcoord1,
             world_position: worldpos1,
             world_normal: worldnormal1,
+            corner: corner1,
         },
         UvVtx {
             uv_position: texcoord2,
             world_position: worldpos2,
             world_normal: worldnormal2,
+            corner: corner2,
         },
     )
 }
@@ -87,6 +91,8 @@ pub struct UvVtx {
     pub uv_position: Vec2,
     pub world_normal: Vec3,
     pub world_position: Vec3,
+    /// Index of the vertex in the original triangle, before the order was possibly changed
+    pub corner: usize,
 }
 
 impl Position for UvVtx {
@@ -0,0 +1,414 @@
+//!
+//! Provides validation of UV layouts before baking textures for them.
+//!
+
+use geom::{Texcoords, Triangle, Vec2};
+use image::{ImageBuffer, Rgba};
+use islands::triangle_islands;
+use line2d::Line2D;
+use raster::Rasterize;
+use scene::{Entity, Mesh};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use uv_triangle::triangle_into_uv_image_space;
+
+/// UV triangles with an area smaller than this fraction of the texture are considered
+/// degenerate.
+const MIN_UV_AREA: f32 = 1e-10;
+
+/// Triangles penetrating each other less than this distance in UV space are considered
+/// touching rather than overlapping, e.g. for neighboring triangles sharing an edge.
+const OVERLAP_TOLERANCE: f32 = 1e-6;
+
+/// Upper bound for the number of cells per axis used to find overlap candidates.
+const MAX_GRID_CELLS: usize = 1024;
+
+const OVERLAY_COVERED: Rgba<u8> = Rgba {
+    data: [128, 128, 128, 255],
+};
+const OVERLAY_FLIPPED: Rgba<u8> = Rgba {
+    data: [0, 96, 255, 255],
+};
+const OVERLAY_OVERLAPPING: Rgba<u8> = Rgba {
+    data: [255, 0, 0, 255],
+};
+const OVERLAY_DEGENERATE: Rgba<u8> = Rgba {
+    data: [255, 0, 255, 255],
+};
+
+/// Problems found in the UV layout of an entity, with triangles identified by their index
+/// in the mesh and islands by the index obtained from the UV island detection.
+///
+/// Triangles with missing texture coordinates are excluded from all other checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UvValidation {
+    pub entity_name: String,
+    pub triangle_count: usize,
+    pub island_count: usize,
+    /// Pairs of triangles whose UV regions overlap, lower triangle index first.
+    pub overlapping_triangles: Vec<(usize, usize)>,
+    /// Triangles with texture coordinates outside of `[0,1]`.
+    pub out_of_range_triangles: Vec<usize>,
+    /// Triangles without area in texture space, which will not be rasterized.
+    pub zero_area_triangles: Vec<usize>,
+    /// Islands that are mirrored in texture space, i.e. where clockwise winding in
+    /// UV space is prevalent.
+    pub flipped_islands: Vec<usize>,
+    /// Triangles with non-finite texture coordinates or with all texture coordinates
+    /// at the origin, as produced by loaders for meshes without texture coordinates.
+    pub missing_texcoord_triangles: Vec<usize>,
+}
+
+impl UvValidation {
+    /// Checks the UV layout of the given entity.
+    pub fn new(entity: &Entity) -> Self {
+        let islands = triangle_islands(entity);
+        let uvs = entity
+            .mesh
+            .triangles()
+            .map(|t| {
+                let (v0, v1, v2) = t.vertices();
+                [v0.texcoords(), v1.texcoords(), v2.texcoords()]
+            })
+            .collect::<Vec<_>>();
+
+        let mut validation = UvValidation {
+            entity_name: entity.name.clone(),
+            triangle_count: uvs.len(),
+            island_count: islands.iter().max().map(|&max| max + 1).unwrap_or(0),
+            overlapping_triangles: Vec::new(),
+            out_of_range_triangles: Vec::new(),
+            zero_area_triangles: Vec::new(),
+            flipped_islands: Vec::new(),
+            missing_texcoord_triangles: Vec::new(),
+        };
+
+        let mut island_areas = vec![0.0; validation.island_count];
+        // Triangles with proper area that take part in the overlap test
+        let mut solid_triangles = Vec::new();
+
+        for (triangle_idx, uv) in uvs.iter().enumerate() {
+            if is_missing(uv) {
+                validation.missing_texcoord_triangles.push(triangle_idx);
+                continue;
+            }
+
+            if uv.iter().any(|c| c.x < 0.0 || c.x > 1.0 || c.y < 0.0 || c.y > 1.0) {
+                validation.out_of_range_triangles.push(triangle_idx);
+            }
+
+            let area = signed_area(uv);
+            island_areas[islands[triangle_idx]] += area;
+
+            if area.abs() < MIN_UV_AREA {
+                validation.zero_area_triangles.push(triangle_idx);
+            } else {
+                solid_triangles.push(triangle_idx);
+            }
+        }
+
+        validation.flipped_islands = island_areas
+            .iter()
+            .enumerate()
+            .filter(|&(_, &area)| area < 0.0)
+            .map(|(island_idx, _)| island_idx)
+            .collect();
+
+        validation.overlapping_triangles = overlapping_pairs(&uvs, &solid_triangles);
+
+        validation
+    }
+
+    /// Checks if no problems were found.
+    pub fn is_valid(&self) -> bool {
+        self.overlapping_triangles.is_empty()
+            && self.out_of_range_triangles.is_empty()
+            && self.zero_area_triangles.is_empty()
+            && self.flipped_islands.is_empty()
+            && self.missing_texcoord_triangles.is_empty()
+    }
+
+    /// Turns the validation into an error if any problems were found, for use as a
+    /// guard before baking.
+    pub fn into_result(self) -> Result<(), UvValidation> {
+        if self.is_valid() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Renders the UV layout of the given entity, which should be the validated entity,
+    /// with the found problems highlighted.
+    ///
+    /// Regular triangles are gray, triangles in flipped islands blue and overlapping
+    /// regions red. Zero-area triangles are drawn as magenta outlines. Unused texels
+    /// are transparent. Parts of triangles outside of `[0,1]` are not visible.
+    pub fn overlay(
+        &self,
+        entity: &Entity,
+        width: usize,
+        height: usize,
+    ) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let islands = triangle_islands(entity);
+        let missing = self
+            .missing_texcoord_triangles
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>();
+        let zero_area = self
+            .zero_area_triangles
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        let mut coverage = vec![0_u32; width * height];
+        let mut flipped = vec![false; width * height];
+        let mut degenerate = vec![false; width * height];
+
+        let uv_triangles = entity
+            .mesh
+            .triangles()
+            .map(|t| triangle_into_uv_image_space(t, width, height))
+            .enumerate()
+            .filter(|&(triangle_idx, _)| !missing.contains(&triangle_idx));
+
+        for (triangle_idx, t) in uv_triangles {
+            if zero_area.contains(&triangle_idx) {
+                let (a, b, c) = t.positions();
+                for &(start, end) in &[(a, b), (b, c), (c, a)] {
+                    let line = Line2D {
+                        start,
+                        end,
+                        stroke_width: 1,
+                    };
+                    line.rasterize(width, height, |x, y| {
+                        degenerate[(height - 1 - y) * width + x] = true;
+                    });
+                }
+            } else {
+                let is_flipped = self.flipped_islands.contains(&islands[triangle_idx]);
+                t.rasterize(width, height, |x, y| {
+                    let idx = (height - 1 - y) * width + x;
+                    coverage[idx] += 1;
+                    flipped[idx] |= is_flipped;
+                });
+            }
+        }
+
+        ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+            let idx = y as usize * width + x as usize;
+            if degenerate[idx] {
+                OVERLAY_DEGENERATE
+            } else if coverage[idx] > 1 {
+                OVERLAY_OVERLAPPING
+            } else if flipped[idx] {
+                OVERLAY_FLIPPED
+            } else if coverage[idx] == 1 {
+                OVERLAY_COVERED
+            } else {
+                Rgba { data: [0, 0, 0, 0] }
+            }
+        })
+    }
+}
+
+impl fmt::Display for UvValidation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "UV validation for {} ({} triangles, {} islands)",
+            self.entity_name, self.triangle_count, self.island_count
+        )?;
+        writeln!(
+            f,
+            "  overlapping triangle pairs: {}",
+            self.overlapping_triangles.len()
+        )?;
+        writeln!(
+            f,
+            "  triangles outside [0,1]: {}",
+            self.out_of_range_triangles.len()
+        )?;
+        writeln!(f, "  zero-area triangles: {}", self.zero_area_triangles.len())?;
+        writeln!(f, "  flipped islands: {}", self.flipped_islands.len())?;
+        write!(
+            f,
+            "  triangles with missing texture coordinates: {}",
+            self.missing_texcoord_triangles.len()
+        )
+    }
+}
+
+impl Error for UvValidation {
+    fn description(&self) -> &str {
+        "UV layout is invalid"
+    }
+}
+
+fn is_missing(uv: &[Vec2; 3]) -> bool {
+    uv.iter().any(|c| !c.x.is_finite() || !c.y.is_finite())
+        || uv.iter().all(|c| c.x == 0.0 && c.y == 0.0)
+}
+
+/// Area of the triangle in UV space, negative for clockwise winding.
+fn signed_area(uv: &[Vec2; 3]) -> f32 {
+    let (ab, ac) = (uv[1] - uv[0], uv[2] - uv[0]);
+    0.5 * (ab.x * ac.y - ac.x * ab.y)
+}
+
+/// Finds all pairs of the given triangles that overlap in UV space.
+///
+/// Candidates are found by sorting the triangle bounds into a grid before testing
+/// them for separation.
+fn overlapping_pairs(uvs: &[[Vec2; 3]], triangle_idxs: &[usize]) -> Vec<(usize, usize)> {
+    if triangle_idxs.len() < 2 {
+        return Vec::new();
+    }
+
+    let bounds = |uv: &[Vec2; 3]| {
+        let min = Vec2::new(
+            uv[0].x.min(uv[1].x).min(uv[2].x),
+            uv[0].y.min(uv[1].y).min(uv[2].y),
+        );
+        let max = Vec2::new(
+            uv[0].x.max(uv[1].x).max(uv[2].x),
+            uv[0].y.max(uv[1].y).max(uv[2].y),
+        );
+        (min, max)
+    };
+
+    let (mut min, mut max) = bounds(&uvs[triangle_idxs[0]]);
+    for &triangle_idx in triangle_idxs {
+        let (tri_min, tri_max) = bounds(&uvs[triangle_idx]);
+        min = Vec2::new(min.x.min(tri_min.x), min.y.min(tri_min.y));
+        max = Vec2::new(max.x.max(tri_max.x), max.y.max(tri_max.y));
+    }
+
+    let cells = ((triangle_idxs.len() as f32).sqrt().ceil() as usize).min(MAX_GRID_CELLS);
+    let cell_size = Vec2::new(
+        ((max.x - min.x) / cells as f32).max(OVERLAP_TOLERANCE),
+        ((max.y - min.y) / cells as f32).max(OVERLAP_TOLERANCE),
+    );
+    let cell_of = |coord: f32, min: f32, size: f32| {
+        (((coord - min) / size) as usize).min(cells - 1)
+    };
+
+    let mut grid = vec![Vec::new(); cells * cells];
+    for &triangle_idx in triangle_idxs {
+        let (tri_min, tri_max) = bounds(&uvs[triangle_idx]);
+        let (min_x, max_x) = (
+            cell_of(tri_min.x, min.x, cell_size.x),
+            cell_of(tri_max.x, min.x, cell_size.x),
+        );
+        let (min_y, max_y) = (
+            cell_of(tri_min.y, min.y, cell_size.y),
+            cell_of(tri_max.y, min.y, cell_size.y),
+        );
+
+        for y in min_y..(max_y + 1) {
+            for x in min_x..(max_x + 1) {
+                grid[y * cells + x].push(triangle_idx);
+            }
+        }
+    }
+
+    let mut pairs = HashSet::new();
+    for cell in grid {
+        for (i, &a) in cell.iter().enumerate() {
+            for &b in &cell[(i + 1)..] {
+                let pair = if a < b { (a, b) } else { (b, a) };
+                if !pairs.contains(&pair) && triangles_overlap(&uvs[a], &uvs[b]) {
+                    pairs.insert(pair);
+                }
+            }
+        }
+    }
+
+    let mut pairs = pairs.into_iter().collect::<Vec<_>>();
+    pairs.sort();
+    pairs
+}
+
+/// Tests two non-degenerate triangles for overlap using the separating axis theorem,
+/// treating triangles that only touch as not overlapping.
+fn triangles_overlap(a: &[Vec2; 3], b: &[Vec2; 3]) -> bool {
+    let edges = (0..3)
+        .map(|i| a[(i + 1) % 3] - a[i])
+        .chain((0..3).map(|i| b[(i + 1) % 3] - b[i]));
+
+    for edge in edges {
+        let length = (edge.x * edge.x + edge.y * edge.y).sqrt();
+        let axis = Vec2::new(-edge.y / length, edge.x / length);
+
+        let project = |t: &[Vec2; 3]| {
+            let dots = [
+                t[0].x * axis.x + t[0].y * axis.y,
+                t[1].x * axis.x + t[1].y * axis.y,
+                t[2].x * axis.x + t[2].y * axis.y,
+            ];
+            (
+                dots[0].min(dots[1]).min(dots[2]),
+                dots[0].max(dots[1]).max(dots[2]),
+            )
+        };
+
+        let (min_a, max_a) = project(a);
+        let (min_b, max_b) = project(b);
+        if max_a <= min_b + OVERLAP_TOLERANCE || max_b <= min_a + OVERLAP_TOLERANCE {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tri(coords: [(f32, f32); 3]) -> [Vec2; 3] {
+        [
+            Vec2::new(coords[0].0, coords[0].1),
+            Vec2::new(coords[1].0, coords[1].1),
+            Vec2::new(coords[2].0, coords[2].1),
+        ]
+    }
+
+    #[test]
+    fn neighbors_do_not_overlap() {
+        let a = tri([(0.0, 0.0), (0.5, 0.0), (0.0, 0.5)]);
+        let b = tri([(0.5, 0.0), (0.5, 0.5), (0.0, 0.5)]);
+        let shifted = tri([(0.1, 0.1), (0.6, 0.1), (0.1, 0.6)]);
+
+        assert!(!triangles_overlap(&a, &b));
+        assert!(triangles_overlap(&a, &shifted));
+        assert!(triangles_overlap(&b, &shifted));
+    }
+
+    #[test]
+    fn overlapping_pairs_across_cells() {
+        let uvs = vec![
+            tri([(0.0, 0.0), (0.5, 0.0), (0.0, 0.5)]),
+            tri([(0.5, 0.0), (0.5, 0.5), (0.0, 0.5)]),
+            tri([(0.1, 0.1), (0.6, 0.1), (0.1, 0.6)]),
+            tri([(0.9, 0.9), (1.0, 0.9), (0.9, 1.0)]),
+        ];
+
+        assert_eq!(overlapping_pairs(&uvs, &[0, 1, 2, 3]), vec![(0, 2), (1, 2)]);
+        assert_eq!(overlapping_pairs(&uvs, &[0, 1, 3]), vec![]);
+    }
+
+    #[test]
+    fn winding_and_missing() {
+        let ccw = tri([(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]);
+        let cw = tri([(0.0, 0.0), (0.0, 1.0), (1.0, 0.0)]);
+
+        assert_ulps_eq!(signed_area(&ccw), 0.5);
+        assert_ulps_eq!(signed_area(&cw), -0.5);
+        assert!(!is_missing(&ccw));
+        assert!(is_missing(&tri([(0.0, 0.0); 3])));
+        assert!(is_missing(&tri([(0.0, 0.0), (::std::f32::NAN, 0.0), (0.0, 1.0)])));
+    }
+}